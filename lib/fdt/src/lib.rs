@@ -0,0 +1,860 @@
+// a flattened device tree (a dtb), as the boot loader hands it to us.
+// https://www.devicetree.org/specifications/ (chapter 5)
+// nothing here allocates, so the tree can be read before there is a heap.
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use core::slice;
+use core::str;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+
+// header fields, as u32 offsets
+const HEADER_TOTALSIZE: usize = 1;
+const HEADER_OFF_DT_STRUCT: usize = 2;
+const HEADER_OFF_DT_STRINGS: usize = 3;
+const HEADER_OFF_MEM_RSVMAP: usize = 4;
+const HEADER_SIZE_DT_STRINGS: usize = 8;
+const HEADER_SIZE_DT_STRUCT: usize = 9;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+// how deep we follow the tree; real trees are a few levels deep.
+const MAX_DEPTH: usize = 16;
+
+pub struct Fdt<'a> {
+    data: &'a [u8],
+}
+
+pub enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    // name and value
+    Prop(&'a str, &'a [u8]),
+}
+
+// everything in the tree is big endian
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    match offset.checked_add(4) {
+        Some(end) if end <= data.len() => {}
+        _ => return None,
+    }
+    Some(((data[offset] as u32) << 24) | ((data[offset + 1] as u32) << 16) |
+         ((data[offset + 2] as u32) << 8) | (data[offset + 3] as u32))
+}
+
+fn be64(data: &[u8], offset: usize) -> Option<u64> {
+    match (be32(data, offset), offset.checked_add(4).and_then(|o| be32(data, o))) {
+        (Some(hi), Some(lo)) => Some(((hi as u64) << 32) | (lo as u64)),
+        _ => None,
+    }
+}
+
+fn align4(a: usize) -> usize {
+    (a + 3) & !3
+}
+
+// a nul terminated string at the start of data
+fn c_str(data: &[u8]) -> Option<&str> {
+    match data.iter().position(|b| *b == 0) {
+        Some(len) => str::from_utf8(&data[..len]).ok(),
+        None => None,
+    }
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(data: &'a [u8]) -> Option<Fdt<'a>> {
+        if (data.len() < HEADER_SIZE) || (be32(data, 0) != Some(FDT_MAGIC)) {
+            return None;
+        }
+
+        let fdt = Fdt { data };
+        let size = fdt.total_size();
+        // the header is whatever the boot loader left there; the sums may well overflow.
+        let block_fits = |offset, len| match fdt.header(offset).checked_add(fdt.header(len)) {
+            Some(end) => end <= size,
+            None => false,
+        };
+        if (size < HEADER_SIZE) || (size > data.len()) ||
+           !block_fits(HEADER_OFF_DT_STRUCT, HEADER_SIZE_DT_STRUCT) ||
+           !block_fits(HEADER_OFF_DT_STRINGS, HEADER_SIZE_DT_STRINGS) {
+            return None;
+        }
+        Some(Fdt { data: &data[..size] })
+    }
+
+    /// the tree at address v.
+    ///
+    /// # Safety
+    ///
+    /// v must be mapped for at least the size in its header, and stay mapped for as long as
+    /// the tree is used.
+    pub unsafe fn from_address(v: usize) -> Option<Fdt<'static>> {
+        let header = slice::from_raw_parts(v as *const u8, HEADER_SIZE);
+        if !Fdt::is_fdt(header) {
+            return None;
+        }
+        let size = be32(header, HEADER_TOTALSIZE * 4).unwrap() as usize;
+        Fdt::new(slice::from_raw_parts(v as *const u8, size))
+    }
+
+    pub fn is_fdt(header: &[u8]) -> bool {
+        be32(header, 0) == Some(FDT_MAGIC)
+    }
+
+    // the size of the tree, as its header says; header must be at least 8 bytes.
+    pub fn size_from_header(header: &[u8]) -> usize {
+        be32(header, HEADER_TOTALSIZE * 4).unwrap_or(0) as usize
+    }
+
+    fn header(&self, field: usize) -> usize {
+        be32(self.data, field * 4).unwrap() as usize
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.header(HEADER_TOTALSIZE)
+    }
+
+    // the memory reservation block: (address, size) of memory we should leave alone.
+    pub fn reservations(&self) -> Reservations<'a> {
+        Reservations {
+            data: self.data,
+            offset: self.header(HEADER_OFF_MEM_RSVMAP),
+        }
+    }
+
+    pub fn tokens(&self) -> Tokens<'a> {
+        let structs = self.header(HEADER_OFF_DT_STRUCT);
+        let strings = self.header(HEADER_OFF_DT_STRINGS);
+        Tokens {
+            data: &self.data[structs..structs + self.header(HEADER_SIZE_DT_STRUCT)],
+            strings: &self.data[strings..strings + self.header(HEADER_SIZE_DT_STRINGS)],
+            offset: 0,
+        }
+    }
+
+    // all the nodes, in the order they are in the tree; the root first.
+    pub fn nodes(&self) -> Nodes<'a> {
+        Nodes {
+            tokens: self.tokens(),
+            levels: [ROOT_LEVEL; MAX_DEPTH],
+            depth: 0,
+        }
+    }
+}
+
+pub struct Reservations<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Reservations<'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let entry = match (be64(self.data, self.offset),
+                           self.offset.checked_add(8).and_then(|o| be64(self.data, o))) {
+            (Some(address), Some(size)) => (address, size),
+            _ => return None,
+        };
+        // the block ends with an empty entry
+        if entry == (0, 0) {
+            return None;
+        }
+        self.offset += 16;
+        Some(entry)
+    }
+}
+
+// walks the structure block. a malformed tree just ends the walk.
+#[derive(Clone)]
+pub struct Tokens<'a> {
+    data: &'a [u8],
+    strings: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        loop {
+            let token = be32(self.data, self.offset)?;
+            self.offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(&self.data[self.offset..])?;
+                    self.offset += align4(name.len() + 1);
+                    return Some(Token::BeginNode(name));
+                }
+                FDT_END_NODE => return Some(Token::EndNode),
+                FDT_PROP => {
+                    let (len, name_offset) = match (be32(self.data, self.offset),
+                                                    be32(self.data, self.offset + 4)) {
+                        (Some(len), Some(name_offset)) => (len as usize, name_offset as usize),
+                        _ => return None,
+                    };
+                    let start = self.offset + 8;
+                    match start.checked_add(len) {
+                        Some(end) if end <= self.data.len() => {}
+                        _ => return None,
+                    }
+                    if name_offset >= self.strings.len() {
+                        return None;
+                    }
+                    let name = c_str(&self.strings[name_offset..])?;
+                    self.offset = start + align4(len);
+                    return Some(Token::Prop(name, &self.data[start..start + len]));
+                }
+                FDT_NOP => continue,
+                // FDT_END, or garbage
+                _ => return None,
+            }
+        }
+    }
+}
+
+// reads a number made of cells (0, 1 or 2 u32s) from the start of a property value; returns it
+// with the rest of the value. no cells is a 0 that takes no room, like the sizes under
+// #size-cells = <0>.
+pub fn read_cells(value: &[u8], cells: usize) -> Option<(u64, &[u8])> {
+    let n = match cells {
+        0 => Some(0),
+        1 => be32(value, 0).map(|x| x as u64),
+        2 => be64(value, 0),
+        _ => None,
+    };
+    n.map(|n| (n, &value[cells * 4..]))
+}
+
+// the value of a string property, without the nul.
+pub fn prop_str(value: &[u8]) -> Option<&str> {
+    c_str(value)
+}
+
+// the node name without the unit address; "memory@0" is "memory".
+pub fn node_base_name(name: &str) -> &str {
+    match name.find('@') {
+        Some(i) => &name[..i],
+        None => name,
+    }
+}
+
+// what a node tells its children: the cells of their addresses, and how their addresses map to
+// the node's parent.
+#[derive(Clone, Copy)]
+struct Level<'a> {
+    address_cells: usize,
+    size_cells: usize,
+    ranges: Option<&'a [u8]>,
+}
+
+// the defaults, when a node doesn't say
+const ROOT_LEVEL: Level<'static> = Level {
+    address_cells: 2,
+    size_cells: 1,
+    ranges: None,
+};
+
+pub struct Nodes<'a> {
+    tokens: Tokens<'a>,
+    // the nodes above the next one
+    levels: [Level<'a>; MAX_DEPTH],
+    depth: usize,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        loop {
+            match self.tokens.next() {
+                Some(Token::BeginNode(name)) => {
+                    if self.depth == MAX_DEPTH {
+                        return None;
+                    }
+                    let node = Node {
+                        name,
+                        depth: self.depth,
+                        props: self.tokens.clone(),
+                        levels: self.levels,
+                    };
+                    self.levels[self.depth] = Level {
+                        address_cells: node.prop_u32("#address-cells").map(|x| x as usize).unwrap_or(ROOT_LEVEL.address_cells),
+                        size_cells: node.prop_u32("#size-cells").map(|x| x as usize).unwrap_or(ROOT_LEVEL.size_cells),
+                        ranges: node.prop("ranges"),
+                    };
+                    self.depth += 1;
+                    return Some(node);
+                }
+                Some(Token::EndNode) => {
+                    if self.depth == 0 {
+                        return None;
+                    }
+                    self.depth -= 1;
+                }
+                // the properties are read through the node
+                Some(Token::Prop(_, _)) => {}
+                None => return None,
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Node<'a> {
+    pub name: &'a str,
+    // the root is 0
+    pub depth: usize,
+    // right after the node begins, where its properties are
+    props: Tokens<'a>,
+    // levels[..depth] are the nodes above this one
+    levels: [Level<'a>; MAX_DEPTH],
+}
+
+impl<'a> Node<'a> {
+    pub fn props(&self) -> Props<'a> {
+        Props { tokens: self.props.clone() }
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        self.props().find(|p| p.0 == name).map(|p| p.1)
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        self.prop(name).and_then(|v| be32(v, 0))
+    }
+
+    // the compatible strings, from the most specific to the most generic.
+    pub fn compatible(&self) -> StrList<'a> {
+        StrList { value: self.prop("compatible").unwrap_or(&[]) }
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|c| c == compatible)
+    }
+
+    // nodes without a status are enabled.
+    pub fn is_enabled(&self) -> bool {
+        match self.prop("status").and_then(prop_str) {
+            Some(status) => (status == "okay") || (status == "ok"),
+            None => true,
+        }
+    }
+
+    // the (address, size) pairs of the reg property, with the addresses translated to what the
+    // cpu sees (through the ranges of the buses above the node). pairs that don't translate
+    // are left out.
+    pub fn reg<'b>(&'b self) -> Reg<'a, 'b> {
+        Reg {
+            node: self,
+            value: self.prop("reg").unwrap_or(&[]),
+        }
+    }
+
+    // the cells of the interrupts property. how many cells an interrupt takes and what they mean
+    // is up to the interrupt controller.
+    pub fn interrupts(&self) -> Cells<'a> {
+        Cells { value: self.prop("interrupts").unwrap_or(&[]) }
+    }
+
+    fn parent(&self) -> Level<'a> {
+        if self.depth == 0 {
+            ROOT_LEVEL
+        } else {
+            self.levels[self.depth - 1]
+        }
+    }
+
+    // walk up from the parent; each bus maps the addresses of its children to the address
+    // space of its own parent.
+    fn translate(&self, address: u64) -> Option<u64> {
+        let mut address = address;
+        let mut d = if self.depth == 0 { 0 } else { self.depth - 1 };
+        while d > 0 {
+            let (bus, parent) = (self.levels[d], self.levels[d - 1]);
+            match bus.ranges {
+                // strictly, a bus without ranges can't be reached from the cpu; but simple
+                // trees leave it out, so treat it like an empty one.
+                None => {}
+                // the same addresses on both sides
+                Some([]) => {}
+                Some(ranges) => {
+                    address = translate_range(ranges, address, bus, parent)?;
+                }
+            }
+            d -= 1;
+        }
+        Some(address)
+    }
+}
+
+// ranges is made of (child address, parent address, size) entries.
+fn translate_range(ranges: &[u8], address: u64, bus: Level, parent: Level) -> Option<u64> {
+    // entries of no cells at all would never end
+    if bus.address_cells + parent.address_cells + bus.size_cells == 0 {
+        return None;
+    }
+    let mut rest = ranges;
+    while !rest.is_empty() {
+        let (child, r) = read_cells(rest, bus.address_cells)?;
+        let (parent_address, r) = read_cells(r, parent.address_cells)?;
+        let (size, r) = read_cells(r, bus.size_cells)?;
+        if (address >= child) && (address - child < size) {
+            return Some(parent_address + (address - child));
+        }
+        rest = r;
+    }
+    None
+}
+
+pub struct Props<'a> {
+    tokens: Tokens<'a>,
+}
+
+impl<'a> Iterator for Props<'a> {
+    type Item = (&'a str, &'a [u8]);
+
+    // the properties of a node come before its children.
+    fn next(&mut self) -> Option<(&'a str, &'a [u8])> {
+        match self.tokens.next() {
+            Some(Token::Prop(name, value)) => Some((name, value)),
+            _ => None,
+        }
+    }
+}
+
+pub struct StrList<'a> {
+    value: &'a [u8],
+}
+
+impl<'a> Iterator for StrList<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        while !self.value.is_empty() {
+            let len = self.value.iter().position(|b| *b == 0).unwrap_or(self.value.len());
+            let s = str::from_utf8(&self.value[..len]).ok();
+            self.value = &self.value[::core::cmp::min(len + 1, self.value.len())..];
+            if let Some(s) = s {
+                return Some(s);
+            }
+        }
+        None
+    }
+}
+
+pub struct Cells<'a> {
+    value: &'a [u8],
+}
+
+impl<'a> Iterator for Cells<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let cell = be32(self.value, 0);
+        if cell.is_some() {
+            self.value = &self.value[4..];
+        }
+        cell
+    }
+}
+
+pub struct Reg<'a: 'b, 'b> {
+    node: &'b Node<'a>,
+    value: &'a [u8],
+}
+
+impl<'a, 'b> Iterator for Reg<'a, 'b> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let parent = self.node.parent();
+        if parent.address_cells + parent.size_cells == 0 {
+            return None;
+        }
+        while !self.value.is_empty() {
+            let (address, r) = read_cells(self.value, parent.address_cells)?;
+            let (size, r) = read_cells(r, parent.size_cells)?;
+            self.value = r;
+            if let Some(address) = self.node.translate(address) {
+                return Some((address, size));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    // builds a dtb the way dtc lays it out: header, reservations, structure, strings.
+    struct Dtb {
+        reservations: Vec<(u64, u64)>,
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    fn push32(v: &mut Vec<u8>, x: u32) {
+        v.extend_from_slice(&[(x >> 24) as u8, (x >> 16) as u8, (x >> 8) as u8, x as u8]);
+    }
+
+    fn pad4(v: &mut Vec<u8>) {
+        while v.len() & 3 != 0 {
+            v.push(0);
+        }
+    }
+
+    fn cells(cells: &[u32]) -> Vec<u8> {
+        let mut v = vec![];
+        for c in cells {
+            push32(&mut v, *c);
+        }
+        v
+    }
+
+    impl Dtb {
+        fn new() -> Dtb {
+            Dtb {
+                reservations: vec![],
+                structs: vec![],
+                strings: vec![],
+            }
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Dtb {
+            push32(&mut self.structs, FDT_BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            pad4(&mut self.structs);
+            self
+        }
+
+        fn end(&mut self) -> &mut Dtb {
+            push32(&mut self.structs, FDT_END_NODE);
+            self
+        }
+
+        fn nop(&mut self) -> &mut Dtb {
+            push32(&mut self.structs, FDT_NOP);
+            self
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Dtb {
+            let name_offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            push32(&mut self.structs, FDT_PROP);
+            push32(&mut self.structs, value.len() as u32);
+            push32(&mut self.structs, name_offset);
+            self.structs.extend_from_slice(value);
+            pad4(&mut self.structs);
+            self
+        }
+
+        fn prop_cells(&mut self, name: &str, c: &[u32]) -> &mut Dtb {
+            self.prop(name, &cells(c))
+        }
+
+        fn prop_str(&mut self, name: &str, value: &str) -> &mut Dtb {
+            let mut v = value.as_bytes().to_vec();
+            v.push(0);
+            self.prop(name, &v)
+        }
+
+        fn build(&self) -> Vec<u8> {
+            let mut structs = self.structs.clone();
+            push32(&mut structs, 9); // FDT_END
+
+            let off_rsvmap = HEADER_SIZE;
+            let off_struct = off_rsvmap + 16 * (self.reservations.len() + 1);
+            let off_strings = off_struct + structs.len();
+            let total = off_strings + self.strings.len();
+
+            let mut v = vec![];
+            for x in &[FDT_MAGIC, total as u32, off_struct as u32, off_strings as u32,
+                       off_rsvmap as u32, 17, 16, 0, self.strings.len() as u32,
+                       structs.len() as u32] {
+                push32(&mut v, *x);
+            }
+            for &(address, size) in self.reservations.iter().chain(Some(&(0, 0))) {
+                push32(&mut v, (address >> 32) as u32);
+                push32(&mut v, address as u32);
+                push32(&mut v, (size >> 32) as u32);
+                push32(&mut v, size as u32);
+            }
+            v.extend_from_slice(&structs);
+            v.extend_from_slice(&self.strings);
+            v
+        }
+    }
+
+    // a cut down raspberry pi tree
+    fn sample() -> Vec<u8> {
+        let mut dtb = Dtb::new();
+        dtb.reservations.push((0, 0x1000));
+        dtb.reservations.push((0x1_0000_0000, 0x20));
+        dtb.begin("")
+                .prop_cells("#address-cells", &[1])
+                .prop_cells("#size-cells", &[1])
+                .prop("compatible", b"test,board\0test,generic\0")
+                .begin("memory@0")
+                    .prop_str("device_type", "memory")
+                    .prop_cells("reg", &[0, 0x1000, 0x10000, 0x2000])
+                .end()
+                .begin("cpus")
+                    .prop_cells("#address-cells", &[1])
+                    .prop_cells("#size-cells", &[0])
+                    .begin("cpu@0").prop_cells("reg", &[0]).end()
+                    .nop()
+                    .begin("cpu@1").prop_cells("reg", &[1]).end()
+                .end()
+                .begin("soc")
+                    .prop_str("compatible", "simple-bus")
+                    .prop_cells("#address-cells", &[1])
+                    .prop_cells("#size-cells", &[1])
+                    .prop_cells("ranges", &[0x7e00_0000, 0x3f00_0000, 0x100_0000])
+                    .begin("timer@7e003000")
+                        .prop_str("compatible", "brcm,bcm2835-system-timer")
+                        .prop_cells("reg", &[0x7e00_3000, 0x1000])
+                        .prop_cells("interrupts", &[1, 0, 1, 1])
+                    .end()
+                    .begin("outside@7f000000")
+                        .prop_cells("reg", &[0x7f00_0000, 0x10])
+                    .end()
+                    .begin("spi@7e204000")
+                        .prop_str("compatible", "brcm,bcm2835-spi")
+                        .prop_str("status", "disabled")
+                    .end()
+                .end()
+            .end();
+        dtb.build()
+    }
+
+    fn node<'a>(fdt: &Fdt<'a>, name: &str) -> Node<'a> {
+        fdt.nodes().find(|n| n.name == name).unwrap()
+    }
+
+    #[test]
+    fn tokens() {
+        let blob = sample();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.total_size(), blob.len());
+
+        let mut tokens = fdt.tokens();
+        match tokens.next() {
+            Some(Token::BeginNode("")) => {}
+            _ => panic!("expected the root"),
+        }
+        match tokens.next() {
+            Some(Token::Prop("#address-cells", v)) => assert_eq!(v, &[0, 0, 0, 1]),
+            _ => panic!("expected #address-cells"),
+        }
+
+        // the nop is skipped, and every node ends
+        let (mut begins, mut ends) = (1, 0);
+        for t in tokens {
+            match t {
+                Token::BeginNode(_) => begins += 1,
+                Token::EndNode => ends += 1,
+                Token::Prop(_, _) => {}
+            }
+        }
+        assert_eq!(begins, 9);
+        assert_eq!(begins, ends);
+    }
+
+    #[test]
+    fn nodes() {
+        let blob = sample();
+        let fdt = Fdt::new(&blob).unwrap();
+        let nodes: Vec<(&str, usize)> = fdt.nodes().map(|n| (n.name, n.depth)).collect();
+        assert_eq!(nodes,
+                   vec![("", 0), ("memory@0", 1), ("cpus", 1), ("cpu@0", 2), ("cpu@1", 2),
+                        ("soc", 1), ("timer@7e003000", 2), ("outside@7f000000", 2),
+                        ("spi@7e204000", 2)]);
+
+        let root = node(&fdt, "");
+        assert_eq!(root.compatible().collect::<Vec<_>>(), vec!["test,board", "test,generic"]);
+        assert!(root.is_compatible("test,generic"));
+        assert!(!root.is_compatible("test"));
+
+        let timer = node(&fdt, "timer@7e003000");
+        assert!(timer.is_enabled());
+        assert_eq!(timer.interrupts().collect::<Vec<_>>(), vec![1, 0, 1, 1]);
+        assert_eq!(timer.prop("missing"), None);
+        assert!(!node(&fdt, "spi@7e204000").is_enabled());
+
+        assert_eq!(node_base_name("memory@0"), "memory");
+        assert_eq!(node_base_name("soc"), "soc");
+        assert_eq!(node(&fdt, "memory@0").prop("device_type").and_then(prop_str), Some("memory"));
+    }
+
+    #[test]
+    fn reg() {
+        let blob = sample();
+        let fdt = Fdt::new(&blob).unwrap();
+
+        assert_eq!(node(&fdt, "memory@0").reg().collect::<Vec<_>>(),
+                   vec![(0, 0x1000), (0x10000, 0x2000)]);
+        // through the ranges of soc
+        assert_eq!(node(&fdt, "timer@7e003000").reg().collect::<Vec<_>>(),
+                   vec![(0x3f00_3000, 0x1000)]);
+        // not in the ranges of soc
+        assert_eq!(node(&fdt, "outside@7f000000").reg().count(), 0);
+        assert_eq!(node(&fdt, "spi@7e204000").reg().count(), 0);
+    }
+
+    #[test]
+    fn no_size_cells() {
+        let blob = sample();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(node(&fdt, "cpu@0").reg().collect::<Vec<_>>(), vec![(0, 0)]);
+        assert_eq!(node(&fdt, "cpu@1").reg().collect::<Vec<_>>(), vec![(1, 0)]);
+
+        let value = cells(&[7]);
+        assert_eq!(read_cells(&value, 0), Some((0, &value[..])));
+        assert_eq!(read_cells(&value, 1), Some((7, &value[4..])));
+        assert_eq!(read_cells(&value, 2), None);
+        assert_eq!(read_cells(&value, 3), None);
+    }
+
+    #[test]
+    fn no_cells_at_all() {
+        let mut dtb = Dtb::new();
+        dtb.begin("")
+                .prop_cells("#address-cells", &[0])
+                .prop_cells("#size-cells", &[0])
+                .begin("dev").prop_cells("reg", &[1, 2]).end()
+            .end();
+        let blob = dtb.build();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(node(&fdt, "dev").reg().count(), 0);
+    }
+
+    #[test]
+    fn reservations() {
+        let blob = sample();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.reservations().collect::<Vec<_>>(), vec![(0, 0x1000), (0x1_0000_0000, 0x20)]);
+        assert_eq!(Fdt::new(&Dtb::new().build()).unwrap().reservations().count(), 0);
+    }
+
+    #[test]
+    fn header() {
+        let blob = sample();
+        assert!(Fdt::is_fdt(&blob));
+        assert_eq!(Fdt::size_from_header(&blob), blob.len());
+        assert!(!Fdt::is_fdt(&blob[4..]));
+
+        // a tree may be followed by anything
+        let mut longer = blob.clone();
+        longer.extend_from_slice(&[0xff; 16]);
+        assert_eq!(Fdt::new(&longer).unwrap().total_size(), blob.len());
+    }
+
+    #[test]
+    fn bad_headers() {
+        let blob = sample();
+        assert!(Fdt::new(&[]).is_none());
+        assert!(Fdt::new(&blob[..HEADER_SIZE - 1]).is_none());
+        assert!(Fdt::new(&blob[..blob.len() - 1]).is_none());
+
+        let mut bad_magic = blob.clone();
+        bad_magic[0] = 0;
+        assert!(Fdt::new(&bad_magic).is_none());
+
+        let patched = |field: usize, value: u32| {
+            let mut v = blob.clone();
+            v[field * 4..field * 4 + 4].copy_from_slice(&cells(&[value]));
+            v
+        };
+        assert!(Fdt::new(&patched(HEADER_TOTALSIZE, 8)).is_none());
+        assert!(Fdt::new(&patched(HEADER_OFF_DT_STRUCT, 0xffff_fff0)).is_none());
+        assert!(Fdt::new(&patched(HEADER_SIZE_DT_STRUCT, 0xffff_fff0)).is_none());
+        assert!(Fdt::new(&patched(HEADER_OFF_DT_STRINGS, blob.len() as u32)).is_none());
+        assert!(Fdt::new(&patched(HEADER_SIZE_DT_STRINGS, 0xffff_ffff)).is_none());
+    }
+
+    // walk everything there is to walk
+    fn walk(fdt: &Fdt) {
+        for _ in fdt.reservations() {}
+        for _ in fdt.tokens() {}
+        for n in fdt.nodes() {
+            for _ in n.props() {}
+            for _ in n.reg() {}
+            for _ in n.interrupts() {}
+            for _ in n.compatible() {}
+            n.is_enabled();
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let blob = sample();
+        // every prefix, with a header that agrees with it
+        for len in HEADER_SIZE..blob.len() {
+            let mut v = blob[..len].to_vec();
+            v[4..8].copy_from_slice(&cells(&[len as u32]));
+            let off_strings = be32(&v, HEADER_OFF_DT_STRINGS * 4).unwrap() as usize;
+            let size_strings = len.saturating_sub(off_strings);
+            v[HEADER_SIZE_DT_STRINGS * 4..HEADER_SIZE_DT_STRINGS * 4 + 4]
+                .copy_from_slice(&cells(&[size_strings as u32]));
+            if let Some(fdt) = Fdt::new(&v) {
+                walk(&fdt);
+            }
+        }
+    }
+
+    #[test]
+    fn garbage() {
+        let blob = sample();
+        let fdt = Fdt::new(&blob).unwrap();
+        let structs = fdt.header(HEADER_OFF_DT_STRUCT);
+        let structs_end = structs + fdt.header(HEADER_SIZE_DT_STRUCT);
+
+        // every byte of the structure block, set to a few values that upset the walk
+        for i in structs..structs_end {
+            for b in &[0x00, 0x03, 0x7f, 0xff] {
+                let mut v = blob.clone();
+                v[i] = *b;
+                walk(&Fdt::new(&v).unwrap());
+            }
+        }
+
+        // a node that never ends, and a token that isn't one
+        let mut dtb = Dtb::new();
+        dtb.begin("").begin("a").prop_cells("reg", &[1, 2, 3]);
+        walk(&Fdt::new(&dtb.build()).unwrap());
+        push32(&mut dtb.structs, 0x1234);
+        dtb.prop_cells("after", &[1]);
+        let blob = dtb.build();
+        let fdt = Fdt::new(&blob).unwrap();
+        let mut props = fdt.tokens().filter_map(|t| match t {
+            Token::Prop(name, _) => Some(name),
+            _ => None,
+        });
+        assert!(props.all(|name| name != "after"));
+    }
+
+    #[test]
+    fn too_deep() {
+        let mut dtb = Dtb::new();
+        for _ in 0..MAX_DEPTH + 4 {
+            dtb.begin("n");
+        }
+        let blob = dtb.build();
+        assert_eq!(Fdt::new(&blob).unwrap().nodes().count(), MAX_DEPTH);
+    }
+}
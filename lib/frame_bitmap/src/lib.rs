@@ -0,0 +1,326 @@
+// which physical frames are free: one bit per frame, 1 means used. it counts in frames and knows
+// nothing of addresses or page sizes, so it can be tested on the host.
+// the bitmap memory is provided by the caller, as this is created before we have a heap.
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use core::cmp;
+use core::ops;
+
+const BITS_PER_WORD: usize = 32;
+
+pub struct FrameBitmap<'a> {
+    bitmap: &'a mut [u32],
+    num_frames: usize,
+    free_frames: usize,
+    reserved_frames: usize,
+    // where to start looking for a free frame
+    next_hint: usize,
+}
+
+fn align_up(i: usize, align: usize) -> usize {
+    i.div_ceil(align) * align
+}
+
+impl<'a> FrameBitmap<'a> {
+    // num_frames is cut down to what the bitmap has bits for.
+    pub fn new(bitmap: &'a mut [u32], num_frames: usize) -> FrameBitmap<'a> {
+        let num_frames = cmp::min(num_frames, bitmap.len() * BITS_PER_WORD);
+
+        // everything is free at first; frames past the end of memory are marked used so we never
+        // hand them out.
+        for w in bitmap.iter_mut() {
+            *w = 0;
+        }
+
+        let mut frames = FrameBitmap {
+            bitmap,
+            num_frames,
+            free_frames: num_frames,
+            reserved_frames: 0,
+            next_hint: 0,
+        };
+
+        let total_bits = frames.bitmap.len() * BITS_PER_WORD;
+        for i in num_frames..total_bits {
+            frames.set(i);
+        }
+
+        frames
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn reserved_frames(&self) -> usize {
+        self.reserved_frames
+    }
+
+    // frames past the end of memory are always used.
+    pub fn is_used(&self, frame: usize) -> bool {
+        if frame >= self.num_frames {
+            return true;
+        }
+        (self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD))) != 0
+    }
+
+    fn set(&mut self, frame: usize) {
+        self.bitmap[frame / BITS_PER_WORD] |= 1 << (frame % BITS_PER_WORD);
+    }
+
+    fn clear(&mut self, frame: usize) {
+        self.bitmap[frame / BITS_PER_WORD] &= !(1 << (frame % BITS_PER_WORD));
+    }
+
+    // mark the frames as used, for good. frames that are already used are left alone.
+    pub fn reserve(&mut self, frames: ops::Range<usize>) {
+        let end = cmp::min(frames.end, self.num_frames);
+        for frame in frames.start..end {
+            if !self.is_used(frame) {
+                self.set(frame);
+                self.free_frames -= 1;
+                self.reserved_frames += 1;
+            }
+        }
+    }
+
+    // find a run of number free frames that starts on a multiple of align, looking in [from, to)
+    fn find_free_run(&self, number: usize, align: usize, from: usize, to: usize) -> Option<usize> {
+        let mut start = align_up(from, align);
+
+        while start + number <= to {
+            // skip full words quickly; this is the common case when memory is mostly allocated.
+            if start.is_multiple_of(BITS_PER_WORD) && (self.bitmap[start / BITS_PER_WORD] == !0) {
+                start = align_up(start + BITS_PER_WORD, align);
+                continue;
+            }
+
+            match (start..(start + number)).find(|&frame| self.is_used(frame)) {
+                Some(used) => start = align_up(used + 1, align),
+                None => return Some(start),
+            }
+        }
+
+        None
+    }
+
+    // the first of number contiguous frames, starting on a multiple of align.
+    // align must be a power of two.
+    pub fn allocate(&mut self, number: usize, align: usize) -> Option<usize> {
+        if number == 0 || number > self.free_frames {
+            return None;
+        }
+
+        let hint = self.next_hint;
+        let found = match self.find_free_run(number, align, hint, self.num_frames) {
+            Some(f) => Some(f),
+            // wrap around and try the begining (the run may cross the hint)
+            None => self.find_free_run(number, align, 0, cmp::min(hint + number, self.num_frames)),
+        };
+
+        let start = found?;
+
+        for frame in start..(start + number) {
+            self.set(frame);
+        }
+        self.free_frames -= number;
+        self.next_hint = start + number;
+        if self.next_hint >= self.num_frames {
+            self.next_hint = 0;
+        }
+
+        Some(start)
+    }
+
+    pub fn deallocate(&mut self, start: usize, number: usize) {
+        if start + number > self.num_frames {
+            panic!("deallocating frame out of range!")
+        }
+
+        for frame in start..(start + number) {
+            if !self.is_used(frame) {
+                panic!("double free of frame!")
+            }
+            self.clear(frame);
+        }
+        self.free_frames += number;
+
+        // prefer recently freed frames; they are more likely to be hot in the cache.
+        if start < self.next_hint {
+            self.next_hint = start;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contiguous() {
+        let mut bitmap = [0xffff_ffff; 4];
+        let mut frames = FrameBitmap::new(&mut bitmap, 128);
+        assert_eq!(frames.free_frames(), 128);
+        assert_eq!(frames.allocate(3, 1), Some(0));
+        assert_eq!(frames.allocate(1, 1), Some(3));
+        assert_eq!(frames.allocate(40, 1), Some(4));
+        assert!((0..44).all(|f| frames.is_used(f)));
+        assert!(!frames.is_used(44));
+        assert_eq!(frames.free_frames(), 128 - 44);
+
+        assert_eq!(frames.allocate(0, 1), None);
+        assert_eq!(frames.allocate(129, 1), None);
+        assert_eq!(frames.allocate(85, 1), None);
+        assert_eq!(frames.allocate(84, 1), Some(44));
+        assert_eq!(frames.free_frames(), 0);
+        assert_eq!(frames.allocate(1, 1), None);
+    }
+
+    #[test]
+    fn aligned() {
+        let mut bitmap = [0; 4];
+        let mut frames = FrameBitmap::new(&mut bitmap, 128);
+        assert_eq!(frames.allocate(1, 1), Some(0));
+        assert_eq!(frames.allocate(4, 4), Some(4));
+        assert_eq!(frames.allocate(1, 16), Some(16));
+        // a whole word, used, is skipped in one go
+        assert_eq!(frames.allocate(32, 32), Some(32));
+        assert_eq!(frames.allocate(2, 64), Some(64));
+        // the frames left behind by the alignment are still there
+        assert_eq!(frames.allocate(3, 1), Some(66));
+        frames.deallocate(1, 0);
+        assert_eq!(frames.allocate(3, 1), Some(1));
+        assert_eq!(frames.allocate(1, 128), None);
+    }
+
+    #[test]
+    fn reserved() {
+        let mut bitmap = [0; 2];
+        let mut frames = FrameBitmap::new(&mut bitmap, 64);
+        frames.reserve(0..1);
+        frames.reserve(10..20);
+        // overlapping, and past the end of memory
+        frames.reserve(15..25);
+        frames.reserve(60..100);
+        assert_eq!(frames.reserved_frames(), 1 + 15 + 4);
+        assert_eq!(frames.free_frames(), 64 - 20);
+
+        assert_eq!(frames.allocate(9, 1), Some(1));
+        assert_eq!(frames.allocate(1, 1), Some(25));
+        assert_eq!(frames.allocate(34, 1), Some(26));
+        assert_eq!(frames.allocate(1, 1), None);
+
+        // reserving used frames doesn't count them twice
+        frames.reserve(0..64);
+        assert_eq!(frames.reserved_frames(), 20);
+    }
+
+    #[test]
+    fn past_the_end() {
+        // more bits than frames
+        let mut bitmap = [0; 2];
+        let mut frames = FrameBitmap::new(&mut bitmap, 40);
+        assert_eq!(frames.num_frames(), 40);
+        assert!(frames.is_used(40));
+        assert!(frames.is_used(1000));
+        assert_eq!(frames.allocate(41, 1), None);
+        assert_eq!(frames.allocate(8, 1), Some(0));
+        assert_eq!(frames.allocate(32, 32), None);
+        assert_eq!(frames.allocate(32, 1), Some(8));
+        assert_eq!(frames.free_frames(), 0);
+        assert_eq!(frames.allocate(1, 1), None);
+
+        // more frames than bits
+        let mut bitmap = [0; 1];
+        let frames = FrameBitmap::new(&mut bitmap, 1000);
+        assert_eq!(frames.num_frames(), 32);
+        assert_eq!(frames.free_frames(), 32);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn deallocate_past_the_end() {
+        let mut bitmap = [0; 2];
+        let mut frames = FrameBitmap::new(&mut bitmap, 40);
+        frames.deallocate(39, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free() {
+        let mut bitmap = [0; 1];
+        let mut frames = FrameBitmap::new(&mut bitmap, 32);
+        let f = frames.allocate(2, 1).unwrap();
+        frames.deallocate(f, 2);
+        frames.deallocate(f + 1, 1);
+    }
+
+    #[test]
+    fn reuse() {
+        let mut bitmap = [0; 1];
+        let mut frames = FrameBitmap::new(&mut bitmap, 32);
+        assert_eq!(frames.allocate(8, 1), Some(0));
+        assert_eq!(frames.allocate(8, 1), Some(8));
+        assert_eq!(frames.allocate(8, 1), Some(16));
+
+        // freed frames are used again first
+        frames.deallocate(8, 8);
+        assert_eq!(frames.free_frames(), 16);
+        assert_eq!(frames.allocate(4, 1), Some(8));
+        assert_eq!(frames.allocate(4, 1), Some(12));
+        assert_eq!(frames.allocate(4, 1), Some(24));
+
+        frames.deallocate(0, 16);
+        assert_eq!(frames.allocate(16, 1), Some(0));
+        assert_eq!(frames.free_frames(), 4);
+    }
+
+    #[test]
+    fn hint_wraps() {
+        let mut bitmap = [0; 2];
+        let mut frames = FrameBitmap::new(&mut bitmap, 64);
+        assert_eq!(frames.allocate(60, 1), Some(0));
+        frames.deallocate(0, 4);
+        assert_eq!(frames.allocate(1, 1), Some(0));
+        assert_eq!(frames.allocate(1, 1), Some(1));
+        // only fits at the end, which takes the hint past the end and back to 0
+        assert_eq!(frames.allocate(4, 1), Some(60));
+        assert_eq!(frames.allocate(2, 1), Some(2));
+        assert_eq!(frames.free_frames(), 0);
+    }
+
+    #[test]
+    fn free_run_before_the_hint() {
+        let mut bitmap = [0; 1];
+        let mut frames = FrameBitmap::new(&mut bitmap, 16);
+        frames.reserve(8..16);
+        assert_eq!(frames.allocate(1, 8), Some(0));
+        assert_eq!(frames.allocate(4, 4), Some(4));
+        // nothing after the hint; found by wrapping around
+        assert_eq!(frames.allocate(3, 1), Some(1));
+        assert_eq!(frames.free_frames(), 0);
+    }
+
+    #[test]
+    fn free_run_across_the_hint() {
+        let mut bitmap = [0; 1];
+        let mut frames = FrameBitmap::new(&mut bitmap, 16);
+        frames.reserve(12..16);
+        assert_eq!(frames.allocate(2, 2), Some(0));
+        assert_eq!(frames.allocate(2, 4), Some(4));
+        assert_eq!(frames.allocate(6, 1), Some(6));
+        // the hint goes back to 4, in the middle of the free run 2..6
+        frames.deallocate(4, 2);
+        assert_eq!(frames.allocate(4, 1), Some(2));
+        assert_eq!(frames.free_frames(), 0);
+    }
+}
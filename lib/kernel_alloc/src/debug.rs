@@ -0,0 +1,164 @@
+use core::cmp;
+use core::fmt;
+use core::mem;
+use core::ptr;
+use core::str;
+
+// heap debugging. every allocation is laid out as:
+// [padding | header | front redzone | user data | back redzone]
+// redzones are filled with a canary that is checked on free, freed memory is poisoned, and the
+// header remembers who allocated it and what size/align it has.
+
+const REDZONE_SIZE: usize = 16;
+const CANARY: u8 = 0xcd;
+// fresh allocations are filled with this, to catch use of uninitialized memory
+const ALLOC_POISON: u8 = 0xa5;
+// freed memory is filled with this, to catch use after free
+const FREE_POISON: u8 = 0x6b;
+
+const MAGIC_ALLOCATED: usize = 0xa110_ca7e;
+const MAGIC_FREED: usize = 0xdead_f7ee;
+
+// the free lists of the slab caches and the heap write their links in the first two words of
+// a free block, so keep magic past them; that way we still see it on a double free.
+#[repr(C)]
+struct Header {
+    size: usize,
+    align: usize,
+    caller: usize,
+    magic: usize,
+}
+
+static mut CONSOLE: Option<fn(&str)> = None;
+
+pub fn set_console(console: fn(&str)) {
+    unsafe { CONSOLE = Some(console) };
+}
+
+// offset of the user data from the start of the real allocation
+fn front_size(align: usize) -> usize {
+    let min = mem::size_of::<Header>() + REDZONE_SIZE;
+    let align = cmp::max(align, mem::align_of::<Header>());
+    ((min + align - 1) / align) * align
+}
+
+// the size and align of the real allocation
+pub fn wrap(size: usize, align: usize) -> (usize, usize) {
+    (front_size(align) + size + REDZONE_SIZE, cmp::max(align, mem::align_of::<Header>()))
+}
+
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    ptr.offset(-((mem::size_of::<Header>() + REDZONE_SIZE) as isize)) as *mut Header
+}
+
+// set up the real allocation at base, and return the pointer to give to the user.
+pub unsafe fn on_allocate(base: *mut u8, size: usize, align: usize, caller: usize) -> *mut u8 {
+    let ptr = base.offset(front_size(align) as isize);
+    ptr::write(header(ptr),
+               Header {
+                   size: size,
+                   align: align,
+                   caller: caller,
+                   magic: MAGIC_ALLOCATED,
+               });
+    ptr::write_bytes(ptr.offset(-(REDZONE_SIZE as isize)), CANARY, REDZONE_SIZE);
+    ptr::write_bytes(ptr, ALLOC_POISON, size);
+    ptr::write_bytes(ptr.offset(size as isize), CANARY, REDZONE_SIZE);
+    ptr
+}
+
+// check the allocation being freed and return the start of the real allocation.
+pub unsafe fn on_deallocate(ptr: *mut u8, size: usize, align: usize, caller: usize) -> *mut u8 {
+    let h = &mut *header(ptr);
+
+    match h.magic {
+        MAGIC_ALLOCATED => {}
+        MAGIC_FREED => report("double free", ptr, h, size, align, caller),
+        _ => report("free of memory not from the heap (or header overrun)", ptr, h, size, align, caller),
+    }
+
+    if (h.size != size) || (h.align != align) {
+        report("free with mismatched size/align", ptr, h, size, align, caller);
+    }
+
+    if !is_canary(ptr.offset(-(REDZONE_SIZE as isize))) {
+        report("buffer underrun (front redzone overwritten)", ptr, h, size, align, caller);
+    }
+    if !is_canary(ptr.offset(size as isize)) {
+        report("buffer overrun (back redzone overwritten)", ptr, h, size, align, caller);
+    }
+
+    h.magic = MAGIC_FREED;
+    h.caller = caller;
+    ptr::write_bytes(ptr, FREE_POISON, size);
+
+    ptr.offset(-(front_size(align) as isize))
+}
+
+unsafe fn is_canary(redzone: *const u8) -> bool {
+    for i in 0..REDZONE_SIZE {
+        if *redzone.offset(i as isize) != CANARY {
+            return false;
+        }
+    }
+    true
+}
+
+// formats into a fixed buffer, as we can't allocate here.
+struct BufWriter {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl fmt::Write for BufWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn report(what: &str, ptr: *mut u8, h: &Header, size: usize, align: usize, caller: usize) -> ! {
+    use core::fmt::Write;
+    let mut w = BufWriter {
+        buf: [0; 256],
+        len: 0,
+    };
+    let _ = write!(&mut w,
+                   "heap: {} at {:p}; freed by {:#x} with size {} align {}; header says {:#x} \
+                    (allocated or last freed by) with size {} align {}",
+                   what,
+                   ptr,
+                   caller,
+                   size,
+                   align,
+                   h.caller,
+                   h.size,
+                   h.align);
+    unsafe {
+        if let Some(console) = CONSOLE {
+            // we may have cut a multibyte char in half, but we only write ascii
+            console(str::from_utf8_unchecked(&w.buf[..w.len]));
+        }
+    }
+    panic!("heap corruption")
+}
+
+// the return address of the allocator entry point that calls this, i.e. its caller.
+// must be inlined into the entry point, before it calls anything else.
+#[inline(always)]
+#[cfg(target_arch = "arm")]
+pub fn caller() -> usize {
+    let lr: usize;
+    unsafe {
+        asm!("mov $0, lr" : "=r"(lr));
+    }
+    lr
+}
+
+#[inline(always)]
+#[cfg(not(target_arch = "arm"))]
+pub fn caller() -> usize {
+    0
+}
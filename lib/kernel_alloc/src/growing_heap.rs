@@ -0,0 +1,182 @@
+use core::mem;
+use core::ptr;
+
+use linked_list_allocator::{Heap, align_up};
+
+// a heap that starts small and grows by mapping more memory right after its top.
+// every growth step is a chunk with its own linked list heap, so when the last chunk becomes
+// completely free we can give it back.
+
+// the chunk header lives at the start of the chunk itself, as we have no heap to put it in.
+struct Chunk {
+    heap: Heap,
+    // including the header
+    size: usize,
+    // bytes currently allocated from this chunk
+    used: usize,
+}
+
+const MAX_CHUNKS: usize = 256;
+// grow by at least this much at a time, so we don't go to the mapper for every allocation.
+// this is also the granularity of chunk sizes, so it must be a multiple of the page size.
+const MIN_GROW_SIZE: usize = 1 << 20;
+
+pub struct GrowingHeap {
+    chunks: [*mut Chunk; MAX_CHUNKS],
+    num_chunks: usize,
+    bottom: usize,
+    // end of the last chunk
+    top: usize,
+    // the heap never grows past this address
+    ceiling: usize,
+    // map memory for the heap at [start, start + size); returns false if we are out of memory.
+    map: fn(usize, usize) -> bool,
+    // unmap [start, start + size) and free the memory behind it.
+    unmap: fn(usize, usize),
+}
+
+// the chunks are only touched under the heap lock
+unsafe impl Send for GrowingHeap {}
+
+impl GrowingHeap {
+    // [start, start + initial_size) must already be mapped.
+    pub unsafe fn new(start: usize,
+                      initial_size: usize,
+                      max_size: usize,
+                      map: fn(usize, usize) -> bool,
+                      unmap: fn(usize, usize))
+                      -> GrowingHeap {
+        let mut heap = GrowingHeap {
+            chunks: [ptr::null_mut(); MAX_CHUNKS],
+            num_chunks: 0,
+            bottom: start,
+            top: start,
+            ceiling: start + max_size,
+            map: map,
+            unmap: unmap,
+        };
+        heap.add_chunk(initial_size);
+        heap
+    }
+
+    fn header_size() -> usize {
+        align_up(mem::size_of::<Chunk>(), 2 * mem::size_of::<usize>())
+    }
+
+    // the memory at top must already be mapped
+    unsafe fn add_chunk(&mut self, size: usize) {
+        let start = self.top;
+        let header = Self::header_size();
+        let chunk = start as *mut Chunk;
+        ptr::write(chunk,
+                   Chunk {
+                       heap: Heap::new(start + header, size - header),
+                       size: size,
+                       used: 0,
+                   });
+        self.chunks[self.num_chunks] = chunk;
+        self.num_chunks += 1;
+        self.top = start + size;
+    }
+
+    fn grow(&mut self, size: usize, align: usize) -> bool {
+        if self.num_chunks == MAX_CHUNKS {
+            return false;
+        }
+        let chunk_size = align_up(Self::header_size() + size + align, MIN_GROW_SIZE);
+        if chunk_size > self.ceiling - self.top {
+            return false;
+        }
+        if !(self.map)(self.top, chunk_size) {
+            return false;
+        }
+        unsafe { self.add_chunk(chunk_size) };
+        true
+    }
+
+    // the mapped size of the heap
+    pub fn size(&self) -> usize {
+        self.top - self.bottom
+    }
+
+    // bytes allocated, as requested by the callers
+    pub fn used(&self) -> usize {
+        (0..self.num_chunks).map(|i| unsafe { (*self.chunks[i]).used }).sum()
+    }
+
+    // bytes of the mapped chunks that are not allocated; not all of it is usable, as it may be
+    // fragmented.
+    pub fn free(&self) -> usize {
+        (0..self.num_chunks)
+            .map(|i| unsafe {
+                let chunk = &*self.chunks[i];
+                chunk.heap.size() - chunk.used
+            })
+            .sum()
+    }
+
+    // the biggest free block in any chunk: the largest allocation that would succeed without
+    // growing the heap.
+    pub fn largest_free(&self) -> usize {
+        (0..self.num_chunks)
+            .map(|i| unsafe { (*self.chunks[i]).heap.largest_hole() })
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        for i in 0..self.num_chunks {
+            let chunk = unsafe { &mut *self.chunks[i] };
+            if let Some(p) = chunk.heap.allocate_first_fit(size, align) {
+                chunk.used += size;
+                return Some(p);
+            }
+        }
+
+        if !self.grow(size, align) {
+            return None;
+        }
+
+        let chunk = unsafe { &mut *self.chunks[self.num_chunks - 1] };
+        let p = chunk.heap.allocate_first_fit(size, align);
+        if p.is_some() {
+            chunk.used += size;
+        }
+        p
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, size: usize, align: usize) {
+        let addr = ptr as usize;
+        for i in 0..self.num_chunks {
+            let chunk = &mut *self.chunks[i];
+            let start = self.chunks[i] as usize;
+            if (start <= addr) && (addr < start + chunk.size) {
+                chunk.heap.deallocate(ptr, size, align);
+                chunk.used -= size;
+                self.release_free_chunks();
+                return;
+            }
+        }
+        panic!("deallocating memory not from the heap!")
+    }
+
+    // give back trailing chunks that are completely free, but one: a caller that frees and
+    // allocates around a chunk boundary would otherwise map and unmap it every time. the first
+    // chunk is never released (and is the spare when it is free).
+    fn release_free_chunks(&mut self) {
+        while self.num_chunks > 1 {
+            let chunk = self.chunks[self.num_chunks - 1];
+            let before = self.chunks[self.num_chunks - 2];
+            let (start, size) = unsafe {
+                if ((*chunk).used != 0) || ((*before).used != 0) {
+                    return;
+                }
+                (chunk as usize, (*chunk).size)
+            };
+            self.num_chunks -= 1;
+            self.chunks[self.num_chunks] = ptr::null_mut();
+            self.top = start;
+            (self.unmap)(start, size);
+        }
+    }
+}
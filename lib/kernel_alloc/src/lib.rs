@@ -1,4 +1,5 @@
 #![feature(allocator)]
+#![cfg_attr(feature = "heap-debug", feature(asm))]
 
 #![allocator]
 #![no_std]
@@ -11,114 +12,301 @@ extern crate linked_list_allocator;
 // thanks phil!
 // http://os.phil-opp.com/kernel-heap.html
 
-// TODO replace with somthing that more dynamic that an allocate AND deallocate pages.
+mod growing_heap;
+mod slab;
+#[cfg(feature = "heap-debug")]
+mod debug;
 
+#[cfg(feature = "heap-debug")]
+pub use debug::set_console as set_debug_console;
+
+use growing_heap::GrowingHeap;
+use slab::SlabCache;
 
 use core::cell::UnsafeCell;
-use core::ops::{Drop, Deref, DerefMut};
+use core::ops::Drop;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
 
-struct InterruptGuard<T: ?Sized> {
-    get_int: fn() -> bool,
+// interrupts are disabled for as long as it lives, so the cpu we run on doesn't change and
+// interrupt handlers can't allocate under us.
+struct NoInterrupts {
+    to_state: bool,
     set_int: fn(bool),
-    data: UnsafeCell<T>,
 }
 
+impl NoInterrupts {
+    fn new(get_int: fn() -> bool, set_int: fn(bool)) -> NoInterrupts {
+        let old_state = get_int();
+        set_int(false);
+        NoInterrupts {
+            to_state: old_state,
+            set_int: set_int,
+        }
+    }
+}
 
+impl Drop for NoInterrupts {
+    fn drop(&mut self) {
+        (self.set_int)(self.to_state);
+    }
+}
 
-struct InterruptGuardHelper<'a, T: ?Sized + 'a> {
-    to_state: bool,
+// all cpus share the heap, but each has its own slab caches in front of it.
+const MAX_CPUS: usize = 4;
+
+struct KernelHeap {
+    get_int: fn() -> bool,
     set_int: fn(bool),
-    data: &'a mut T,
+    get_cpu_id: fn() -> usize,
+    // each cpu only touches its own, with interrupts disabled
+    caches: [UnsafeCell<SlabCache>; MAX_CPUS],
+    heap: spin::Mutex<GrowingHeap>,
 }
 
-unsafe impl<T: ?Sized + Send> Sync for InterruptGuard<T> {}
-unsafe impl<T: ?Sized + Send> Send for InterruptGuard<T> {}
+unsafe impl Sync for KernelHeap {}
 
-impl<T> InterruptGuard<T> {
-    pub fn new<'a>(user_data: T, get_int: fn() -> bool, set_int: fn(bool)) -> InterruptGuard<T> {
-        InterruptGuard {
-            get_int: get_int,
-            set_int: set_int,
-            data: UnsafeCell::new(user_data),
+impl KernelHeap {
+    fn no_interrupts(&self) -> NoInterrupts {
+        NoInterrupts::new(self.get_int, self.set_int)
+    }
+
+    // the caches of the cpu we run on; the guard keeps us there. take it once per operation.
+    fn cache<'a>(&'a self, _guard: &'a NoInterrupts) -> &'a mut SlabCache {
+        let cpu = (self.get_cpu_id)();
+        if cpu >= MAX_CPUS {
+            panic!("too many cpus for the heap!")
         }
+        unsafe { &mut *self.caches[cpu].get() }
     }
 
-    pub fn no_interrupts(&self) -> InterruptGuardHelper<T> {
-        let old_state = (self.get_int)();
-        (self.set_int)(false);
+    fn allocate(&self, size: usize, align: usize) -> Option<*mut u8> {
+        let guard = self.no_interrupts();
+        let class = match slab::size_class(size, align) {
+            Some(class) => class,
+            None => {
+                LARGE_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+                return self.heap.lock().allocate(size, align);
+            }
+        };
 
-        InterruptGuardHelper {
-            to_state: old_state,
-            set_int: self.set_int,
-            data: unsafe { &mut *self.data.get() },
+        let cache = self.cache(&guard);
+        if let Some(p) = cache.pop(class) {
+            return Some(p);
         }
+
+        // refill the cache from the heap
+        let block = match self.heap.lock().allocate(slab::SLAB_BLOCK_SIZE, slab::class_size(class)) {
+            Some(block) => block,
+            None => return None,
+        };
+        SLAB_BYTES.fetch_add(slab::SLAB_BLOCK_SIZE, Ordering::Relaxed);
+        unsafe { cache.add_block(class, block, slab::SLAB_BLOCK_SIZE) };
+        cache.pop(class)
     }
-}
 
+    unsafe fn deallocate(&self, ptr: *mut u8, size: usize, align: usize) {
+        let guard = self.no_interrupts();
+        let class = match slab::size_class(size, align) {
+            Some(class) => class,
+            None => return self.heap.lock().deallocate(ptr, size, align),
+        };
 
-impl<'a, T: ?Sized> Deref for InterruptGuardHelper<'a, T> {
-    type Target = T;
-    fn deref<'b>(&'b self) -> &'b T {
-        &*self.data
+        // the object goes to the cache of the cpu freeing it, not necessarily where it came from.
+        // when that cache has more than its share, half of it goes back to the heap, so memory
+        // freed on one cpu doesn't pile up there.
+        let cache = self.cache(&guard);
+        cache.push(class, ptr);
+        if cache.free_objects(class) > slab::cache_limit(class) {
+            let size = slab::class_size(class);
+            let mut heap = self.heap.lock();
+            while cache.free_objects(class) > slab::cache_limit(class) / 2 {
+                let obj = cache.pop(class).unwrap();
+                heap.deallocate(obj, size, size);
+                SLAB_BYTES.fetch_sub(size, Ordering::Relaxed);
+            }
+        }
     }
 }
 
-impl<'a, T: ?Sized> DerefMut for InterruptGuardHelper<'a, T> {
-    fn deref_mut<'b>(&'b mut self) -> &'b mut T {
-        &mut *self.data
+// [start, start + size) must be mapped. when it runs out, the heap grows up to max_size using
+// map, and gives trailing memory that is free again back with unmap.
+pub fn init_heap(start: usize,
+                 size: usize,
+                 max_size: usize,
+                 get_int: fn() -> bool,
+                 set_int: fn(bool),
+                 get_cpu_id: fn() -> usize,
+                 map: fn(usize, usize) -> bool,
+                 unmap: fn(usize, usize)) {
+    unsafe {
+        HEAP = Some(KernelHeap {
+            get_int: get_int,
+            set_int: set_int,
+            get_cpu_id: get_cpu_id,
+            caches: [UnsafeCell::new(SlabCache::new()),
+                     UnsafeCell::new(SlabCache::new()),
+                     UnsafeCell::new(SlabCache::new()),
+                     UnsafeCell::new(SlabCache::new())],
+            heap: spin::Mutex::new(GrowingHeap::new(start, size, max_size, map, unmap)),
+        });
     }
 }
 
-impl<'a, T: ?Sized> Drop for InterruptGuardHelper<'a, T> {
-    fn drop(&mut self) {
-        (self.set_int)(self.to_state);
-    }
+static ALLOCATIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+static DEALLOCATIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+static LARGE_ALLOCATIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+static BYTES_IN_USE: AtomicUsize = ATOMIC_USIZE_INIT;
+static SLAB_BYTES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[derive(Copy, Clone, Debug)]
+pub struct AllocStats {
+    pub allocations: usize,
+    pub deallocations: usize,
+    // allocations too big for the slab caches, that went to the heap directly
+    pub large_allocations: usize,
+    // as requested by the callers, not including slab and heap overhead
+    pub bytes_in_use: usize,
+    // heap memory handed to the slab caches
+    pub slab_bytes: usize,
+    // free objects in the slab caches of all cpus, per size class
+    pub slab_free_objects: [usize; slab::NUM_CLASSES],
+    // mapped heap memory
+    pub heap_size: usize,
+    // of heap_size; used includes the memory of the slab caches
+    pub heap_used: usize,
+    pub heap_free: usize,
+    pub largest_free_block: usize,
 }
 
-pub fn init_heap(start: usize, size: usize, get_int: fn() -> bool, set_int: fn(bool)) {
-    unsafe {
-        HEAP = Some(InterruptGuard::new(spin::Mutex::new(linked_list_allocator::Heap::new(start,
-                                                                                          size)),
-                                        get_int,
-                                        set_int));
+pub fn stats() -> AllocStats {
+    let mut stats = AllocStats {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+        large_allocations: LARGE_ALLOCATIONS.load(Ordering::Relaxed),
+        bytes_in_use: BYTES_IN_USE.load(Ordering::Relaxed),
+        slab_bytes: SLAB_BYTES.load(Ordering::Relaxed),
+        slab_free_objects: [0; slab::NUM_CLASSES],
+        heap_size: 0,
+        heap_used: 0,
+        heap_free: 0,
+        largest_free_block: 0,
+    };
+    for class in 0..slab::NUM_CLASSES {
+        stats.slab_free_objects[class] = slab::total_free_objects(class);
     }
+    {
+        let kernel_heap = heap();
+        let _guard = kernel_heap.no_interrupts();
+        let heap = kernel_heap.heap.lock();
+        stats.heap_size = heap.size();
+        stats.heap_used = heap.used();
+        stats.heap_free = heap.free();
+        stats.largest_free_block = heap.largest_free();
+    }
+    stats
+}
+
+#[no_mangle]
+#[cfg(not(feature = "heap-debug"))]
+pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
+    let allocated_mem = heap().allocate(size, align).expect("out of memory");
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES_IN_USE.fetch_add(size, Ordering::Relaxed);
+    allocated_mem
 }
 
 #[no_mangle]
+#[inline(never)]
+#[cfg(feature = "heap-debug")]
 pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
+    let caller = debug::caller();
+    let (real_size, real_align) = debug::wrap(size, align);
     unsafe {
-        let g = HEAP.as_mut().unwrap().no_interrupts();
-        let mut lockg =  g.lock();
-        let res = lockg.allocate_first_fit(size, align);
-        
-        let allocated_mem = res.expect("out of memory");
-        return allocated_mem;
+        let base = heap().allocate(real_size, real_align).expect("out of memory");
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES_IN_USE.fetch_add(size, Ordering::Relaxed);
+        debug::on_allocate(base, size, align, caller)
     }
 }
 
-static mut HEAP: Option<InterruptGuard<spin::Mutex<linked_list_allocator::Heap>>> = None;
+static mut HEAP: Option<KernelHeap> = None;
+
+fn heap() -> &'static KernelHeap {
+    unsafe { HEAP.as_ref().expect("heap is not initialized!") }
+}
 
 #[no_mangle]
+#[cfg(not(feature = "heap-debug"))]
 pub extern "C" fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
+    unsafe { heap().deallocate(ptr, size, align) };
+    DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES_IN_USE.fetch_sub(size, Ordering::Relaxed);
+}
+
+#[no_mangle]
+#[inline(never)]
+#[cfg(feature = "heap-debug")]
+pub extern "C" fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
+    let caller = debug::caller();
+    let (real_size, real_align) = debug::wrap(size, align);
     unsafe {
-        let g = HEAP.as_mut().unwrap().no_interrupts();
-        g.lock().deallocate(ptr, size, align);
+        // check before taking the heap, as a bad free panics
+        let base = debug::on_deallocate(ptr, size, align, caller);
+        heap().deallocate(base, real_size, real_align);
     }
+    DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES_IN_USE.fetch_sub(size, Ordering::Relaxed);
 }
 
 #[no_mangle]
+#[cfg(feature = "heap-debug")]
 pub extern "C" fn __rust_usable_size(size: usize, _align: usize) -> usize {
+    // anything past size is redzone
     size
 }
 
 #[no_mangle]
+#[cfg(not(feature = "heap-debug"))]
+pub extern "C" fn __rust_usable_size(size: usize, align: usize) -> usize {
+    match slab::size_class(size, align) {
+        Some(class) => slab::class_size(class),
+        None => size,
+    }
+}
+
+#[no_mangle]
+#[cfg(feature = "heap-debug")]
 pub extern "C" fn __rust_reallocate_inplace(_ptr: *mut u8,
-                                            size: usize,
+                                            _size: usize,
                                             _new_size: usize,
                                             _align: usize)
                                             -> usize {
-    size
+    // the back redzone has to move, so always reallocate
+    0
+}
+
+// returns the usable size if the allocation can be resized without moving, and something smaller
+// than new_size if it can't.
+#[no_mangle]
+#[cfg(not(feature = "heap-debug"))]
+pub extern "C" fn __rust_reallocate_inplace(_ptr: *mut u8,
+                                            size: usize,
+                                            new_size: usize,
+                                            align: usize)
+                                            -> usize {
+    let class = slab::size_class(size, align);
+    // the object is freed by its new size later on, so it must stay in the same size class.
+    // heap allocations are freed by exact size, so they can't change at all.
+    if class.is_some() && (class == slab::size_class(new_size, align)) {
+        if new_size > size {
+            BYTES_IN_USE.fetch_add(new_size - size, Ordering::Relaxed);
+        } else {
+            BYTES_IN_USE.fetch_sub(size - new_size, Ordering::Relaxed);
+        }
+        return __rust_usable_size(new_size, align);
+    }
+    0
 }
 
 #[no_mangle]
@@ -129,6 +317,10 @@ pub extern "C" fn __rust_reallocate(ptr: *mut u8,
                                     -> *mut u8 {
     use core::{ptr, cmp};
 
+    if __rust_reallocate_inplace(ptr, size, new_size, align) >= new_size {
+        return ptr;
+    }
+
     // from: https://github.com/rust-lang/rust/blob/
     //     c66d2380a810c9a2b3dbb4f93a830b101ee49cc2/
     //     src/liballoc_system/lib.rs#L98-L101
@@ -0,0 +1,97 @@
+use core::cmp;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+// small objects are served from per size class free lists. the classes are powers of two from
+// 16 bytes to MAX_SLAB_SIZE; objects are aligned to their class size, so any align up to the
+// size is satisfied.
+
+pub const NUM_CLASSES: usize = 8;
+const MIN_CLASS_SHIFT: usize = 4;
+pub const MAX_SLAB_SIZE: usize = 1 << (MIN_CLASS_SHIFT + NUM_CLASSES - 1);
+// slab caches get memory from the heap in blocks of this size. objects go back to the heap one
+// by one, when a cache has more than cache_limit of them; the heap merges them back together.
+pub const SLAB_BLOCK_SIZE: usize = 4096;
+// how many blocks worth of free objects of a class a cpu keeps
+const CACHED_BLOCKS: usize = 2;
+
+// free objects per class, of all the cpus. the caches themselves are only read by their cpu.
+static FREE_OBJECTS: [AtomicUsize; NUM_CLASSES] = [ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                                   ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                                   ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+                                                   ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT];
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+pub fn size_class(size: usize, align: usize) -> Option<usize> {
+    let size = cmp::max(size, align);
+    if size > MAX_SLAB_SIZE {
+        return None;
+    }
+    let mut class = 0;
+    while class_size(class) < size {
+        class += 1;
+    }
+    Some(class)
+}
+
+pub fn class_size(class: usize) -> usize {
+    1 << (MIN_CLASS_SHIFT + class)
+}
+
+// the most free objects of class a cpu keeps
+pub fn cache_limit(class: usize) -> usize {
+    CACHED_BLOCKS * SLAB_BLOCK_SIZE / class_size(class)
+}
+
+pub fn total_free_objects(class: usize) -> usize {
+    FREE_OBJECTS[class].load(Ordering::Relaxed)
+}
+
+// the free lists of one cpu. only used with interrupts disabled, by the cpu that owns it.
+pub struct SlabCache {
+    free: [*mut FreeObject; NUM_CLASSES],
+    free_count: [usize; NUM_CLASSES],
+}
+
+impl SlabCache {
+    pub fn new() -> SlabCache {
+        SlabCache {
+            free: [ptr::null_mut(); NUM_CLASSES],
+            free_count: [0; NUM_CLASSES],
+        }
+    }
+
+    pub fn pop(&mut self, class: usize) -> Option<*mut u8> {
+        let obj = self.free[class];
+        if obj.is_null() {
+            return None;
+        }
+        self.free[class] = unsafe { (*obj).next };
+        self.free_count[class] -= 1;
+        FREE_OBJECTS[class].fetch_sub(1, Ordering::Relaxed);
+        Some(obj as *mut u8)
+    }
+
+    pub unsafe fn push(&mut self, class: usize, p: *mut u8) {
+        let obj = p as *mut FreeObject;
+        (*obj).next = self.free[class];
+        self.free[class] = obj;
+        self.free_count[class] += 1;
+        FREE_OBJECTS[class].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // split a block, aligned to the class size, into free objects.
+    pub unsafe fn add_block(&mut self, class: usize, block: *mut u8, block_size: usize) {
+        let size = class_size(class);
+        for i in 0..(block_size / size) {
+            self.push(class, block.offset((i * size) as isize));
+        }
+    }
+
+    pub fn free_objects(&self, class: usize) -> usize {
+        self.free_count[class]
+    }
+}
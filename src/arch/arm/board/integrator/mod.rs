@@ -5,8 +5,10 @@ pub mod stub;
 
 use core::ops;
 use super::super::mem;
+use super::super::bootinfo;
 use super::super::vector;
 use super::super::pic;
+use super::super::pl011;
 
 use collections::boxed::Box;
 use alloc::rc::Rc;
@@ -19,7 +21,10 @@ use platform;
 
 use device::serial::SerialMMIO;
 
-pub const ticks_in_second : usize = 20;
+pub const DEFAULT_TICKS_IN_SECOND : usize = 20;
+
+// devices see the ram at the same addresses as the cpu.
+pub const DMA_BUS_OFFSET: usize = 0;
 
 fn up(a: usize) -> ::mem::PhysicalAddress {
     ::mem::PhysicalAddress((a + mem::PAGE_MASK) & (!mem::PAGE_MASK))
@@ -28,11 +33,10 @@ fn down(a: usize) -> ::mem::PhysicalAddress {
     ::mem::PhysicalAddress((a) & (!mem::PAGE_MASK))
 }
 
-// see:
+// the peripherals are in 0x1000_0000 - 0x1F00_0000, see:
 // http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.dui0159b/Bbaficij.html
-const MMIO_PSTART: ::mem::PhysicalAddress = ::mem::PhysicalAddress(0x1000_0000);
-const MMIO_PEND: ::mem::PhysicalAddress = ::mem::PhysicalAddress(0x1F00_0000);
-const MMIO_VSTART: ::mem::VirtualAddress = ::mem::VirtualAddress(0x1000_0000);
+// each driver maps its own registers; they all fit in a page.
+const DEVICE_SIZE: ::mem::MemorySize = ::mem::MemorySize::PageSizes(1);
 
 #[no_mangle]
 pub extern "C" fn integrator_main(sp_end_virt: usize,
@@ -41,15 +45,17 @@ pub extern "C" fn integrator_main(sp_end_virt: usize,
                                   kernel_start_virt: usize,
                                   kernel_end_virt: usize,
                                   l1table_id: usize,
-                                  l2table_space_id: usize)
+                                  l2table_space_id: usize,
+                                  boot_info: usize)
                                   -> ! {
 
     let ml = mem::MemLayout {
         kernel_start_phy: ::mem::PhysicalAddress(kernel_start_phy),
         kernel_start_virt: ::mem::VirtualAddress(kernel_start_virt),
         kernel_end_virt: ::mem::VirtualAddress(kernel_end_virt),
-        stack_phy: ::mem::PhysicalAddress(sp_end_phy - 2*mem::PAGE_SIZE), /* sp points to begining of stack.. */
-        stack_virt: ::mem::VirtualAddress(sp_end_virt - 2*mem::PAGE_SIZE),
+        stack_phy: ::mem::PhysicalAddress(sp_end_phy - mem::boot_stack_size()), /* sp points to begining of stack.. */
+        stack_virt: ::mem::VirtualAddress(sp_end_virt - mem::boot_stack_size()),
+        stack_size: mem::boot_stack_size(),
     };
 
     let kernel_size = kernel_end_virt - kernel_start_virt;
@@ -62,9 +68,14 @@ pub extern "C" fn integrator_main(sp_end_virt: usize,
                        pagetable_start..pagetable_end];
 
 
+    // when the boot loader doesn't tell, assume 128mb.
+    let boot_info = unsafe {
+        bootinfo::parse(::mem::VirtualAddress(l1table_id), boot_info, 1 << 27)
+    };
+
     ::arch::arm::arm_main(ml, &skip_ranges,
         ::mem::VirtualAddress(l1table_id),
-        ::mem::VirtualAddress(l2table_space_id), 1 << 27);
+        ::mem::VirtualAddress(l2table_space_id), &boot_info);
 
     loop {}
 }
@@ -92,11 +103,6 @@ pub fn send_ipi(id : usize, ipi : ::cpu::IPI) {
 impl PlatformServices{
 
 pub fn new() -> Self {
-    platform::get_memory_services().mem_manager.map_device(
-                    MMIO_PSTART,
-                    MMIO_VSTART,
-                    MMIO_PEND - MMIO_PSTART)
-        .unwrap();
         PlatformServices{
 
         }
@@ -104,20 +110,24 @@ pub fn new() -> Self {
 
 pub fn init_board(&mut self) -> PlatformServices {
 
-    unsafe { serial_base = platform::get_memory_services().mem_manager.p2v(serial::SERIAL_BASE_PADDR).unwrap() }
+    unsafe { serial_base = platform::get_memory_services().mem_manager.ioremap(serial::SERIAL_BASE_PADDR, DEVICE_SIZE).expect("Can't map serial") }
+    // the boot loader set the uart up; only the rate is ours to change (console=ttyAMA0,<baud>)
+    if let Some(baud) = ::bootparams::get().console_baud(serial::CONSOLE_NAME) {
+        unsafe { pl011::PL011::at(serial_base) }.set_baud_rate(serial::UART_CLOCK, baud as u64);
+    }
 
-    write_to_console("Welcome home!");
+    platform::log(::bootparams::LOG_INFO, "Welcome home!");
 
     let mapper = &::platform::get_memory_services().mem_manager;
 
-    let interrupt_source = intr::PIC::new(mapper.p2v(intr::PIC_BASE_PADDR).unwrap());
+    let interrupt_source = intr::PIC::new(mapper.ioremap(intr::PIC_BASE_PADDR, DEVICE_SIZE).expect("Can't map pic"));
     &platform::get_platform_services().arch_services.interrupt_service.add_source(interrupt_source);
 
     // start a timer
-    let mut tmr = timer::Timer::new(1, mapper.p2v(timer::TIMERS_BASE).unwrap(), Box::new(move||{::platform::get_platform_services().clock()}));
+    let mut tmr = timer::Timer::new(1, mapper.ioremap(timer::TIMERS_BASE, DEVICE_SIZE).expect("Can't map timers"), Box::new(move||{::platform::get_platform_services().clock()}));
 
     // timer 1 is 1mhz
-    let counter = 1_000_000 / (ticks_in_second as u32);
+    let counter = 1_000_000 / (::platform::ticks_in_second() as u32);
     tmr.start_timer(counter, true);
     
     let dm = unsafe{&mut platform::get_mut_platform_services().arch_services.driver_manager};
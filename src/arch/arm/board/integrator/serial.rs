@@ -3,6 +3,10 @@ use ::device::serial;
 use core::intrinsics::{volatile_load, volatile_store};
 
 pub const SERIAL_BASE_PADDR: ::mem::PhysicalAddress = ::mem::PhysicalAddress(0x1600_0000);
+// the name of the uart on the command line
+pub const CONSOLE_NAME: &'static str = "ttyAMA0";
+// the uart reference clock of the integrator/cp
+pub const UART_CLOCK: u64 = 14_745_600;
 
 pub struct Writer {
     base: *mut u8,
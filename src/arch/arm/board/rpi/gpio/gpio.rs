@@ -1,6 +1,5 @@
 use volatile;
 
-const GPIO_ADDR : ::mem::VirtualAddress = super::super::GPIO_BASE.uoffset(0);
 
 
 pub enum FunctionSelect{
@@ -104,7 +103,7 @@ macro_rules! set_bit {
 impl GPIO {
     
     pub unsafe fn  new() -> &'static mut Self {
-        &mut *(GPIO_ADDR.0 as *mut GPIO)
+        &mut *(super::super::gpio_base().0 as *mut GPIO)
     }
 
     pub fn set_function(&mut self, pin : usize, func :  FunctionSelect) {
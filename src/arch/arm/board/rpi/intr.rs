@@ -6,8 +6,9 @@ use volatile;
 use super::super::super::pic;
 
 // section 3.6 in: http://infocenter.arm.com/help/topic/com.arm.doc.dui0159b/DUI0159B_integratorcp_1_0_ug.pdf
-pub const PIC_BASE_VADDR: ::mem::VirtualAddress = super::MMIO_VSTART.uoffset(0xB000);
-pub const PIC_IRQ_BASE_VADDR: ::mem::VirtualAddress = PIC_BASE_VADDR.uoffset(0x200);
+// offsets in the mmio block
+pub const PIC_BASE_OFFSET: usize = 0xB000;
+pub const PIC_IRQ_BASE_OFFSET: usize = PIC_BASE_OFFSET + 0x200;
 
 
 pub enum Interrupts {
@@ -146,7 +147,7 @@ pub struct PIC  {
 
 impl PIC { 
     pub unsafe fn new() -> &'static mut Self {
-        &mut *(PIC_IRQ_BASE_VADDR.0 as *mut PIC)
+        &mut *(super::mmio_vaddr(PIC_IRQ_BASE_OFFSET).0 as *mut PIC)
     }
 }
 
@@ -174,6 +175,17 @@ impl PICDev {
     }
 }
 
+// the interrupts in the device tree are (bank, number) pairs: bank 0 is the basic interrupts,
+// 1 and 2 are the gpu interrupts 0-31 and 32-63.
+pub fn from_fdt_cells(bank : u32, num : u32) -> Option<usize> {
+    match (bank, num) {
+        (0, 0 ... 7) => Some(64 + num as usize),
+        (1, 0 ... 31) => Some(num as usize),
+        (2, 0 ... 31) => Some(32 + num as usize),
+        _ => None,
+    }
+}
+
 impl pic::InterruptSource for PICDev {
 
     fn range(&self) -> (usize,usize) {
@@ -11,6 +11,7 @@ use collections::boxed::Box;
 use alloc::rc::Rc;
 
 use super::super::mem;
+use super::super::bootinfo;
 use super::super::pic;
 use device;
 use ::platform;
@@ -18,8 +19,13 @@ use rlibc;
 use io;
 
 use mem::MemoryMapper;
+use arch::arm::{DeviceResources, DriverManager};
 
-pub const ticks_in_second : usize = 20;
+pub const DEFAULT_TICKS_IN_SECOND : usize = 20;
+
+// the dma engines see the arm's ram through the videocore's bus addresses; 0xc000_0000 is the
+// alias that bypasses the vc l2 cache.
+pub const DMA_BUS_OFFSET: usize = 0xC000_0000;
 
 fn up(a: usize) -> ::mem::PhysicalAddress {
     ::mem::PhysicalAddress((a + mem::PAGE_MASK) & (!mem::PAGE_MASK))
@@ -32,8 +38,45 @@ fn down(a: usize) -> ::mem::PhysicalAddress {
 // http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.dui0159b/Bbaficij.html
 const MMIO_PSTART: ::mem::PhysicalAddress = ::mem::PhysicalAddress(0x20000000);
 const MMIO_SIZE: usize = (16<<20);
-const MMIO_PEND: ::mem::PhysicalAddress = ::mem::PhysicalAddress(MMIO_PSTART.0 + MMIO_SIZE); //16mb periferals + 16mv arm local
-const MMIO_VSTART: ::mem::VirtualAddress = ::mem::VirtualAddress(0x1000_0000);
+// where the peripherals are mapped; set by PlatformServices::new
+static mut MMIO_VSTART: ::mem::VirtualAddress = ::mem::VirtualAddress(0);
+
+pub fn mmio_vaddr(offset: usize) -> ::mem::VirtualAddress {
+    unsafe { MMIO_VSTART.uoffset(offset) }
+}
+
+// where a peripheral's registers are mapped; all of them are in the mmio block.
+fn mmio_from_phys(p: ::mem::PhysicalAddress) -> Result<::mem::VirtualAddress, ()> {
+    if (p >= MMIO_PSTART) && (p.0 < MMIO_PSTART.0 + MMIO_SIZE) {
+        Ok(mmio_vaddr(p.0 - MMIO_PSTART.0))
+    } else {
+        Err(())
+    }
+}
+
+// the registers, and the interrupt at index of the (bank, number) pairs of a device tree node.
+fn fdt_resources(res: &DeviceResources, index: usize) -> Result<(::mem::VirtualAddress, usize), ()> {
+    let base = try!(mmio_from_phys(try!(res.reg.first().ok_or(())).start));
+    let cells = &res.interrupts;
+    if cells.len() < 2 * (index + 1) {
+        return Err(());
+    }
+    let irq = try!(intr::from_fdt_cells(cells[2 * index], cells[2 * index + 1]).ok_or(()));
+    Ok((base, irq))
+}
+
+fn probe_timer(dm: &mut DriverManager, res: &DeviceResources) -> Result<(), ()> {
+    // the node lists the interrupts of all four matches; we use Match3.
+    let (base, irq) = try!(fdt_resources(res, timer::Matches::Match3 as usize));
+    dm.add_driver_interruptable(timer::SystemTimerDriver::at(base, irq, Box::new(move||{::platform::get_platform_services().clock()})));
+    Ok(())
+}
+
+fn probe_spi(dm: &mut DriverManager, res: &DeviceResources) -> Result<(), ()> {
+    let (base, irq) = try!(fdt_resources(res, 0));
+    dm.add_driver_spi(spi::SPIDev::at(base, irq));
+    Ok(())
+}
 
 
 pub enum Ptr {}
@@ -49,14 +92,16 @@ extern "C" {
     
 }
 
-pub const GPIO_BASE : ::mem::VirtualAddress = ::mem::VirtualAddress(MMIO_VSTART.0 + 0x20_0000);
+pub fn gpio_base() -> ::mem::VirtualAddress {
+    mmio_vaddr(0x20_0000)
+}
 
 // thanks http://sysprogs.com/VisualKernel/tutorials/raspberry/jtagsetup/
 fn set_gpio_alt(gpio : u32, func : u32 ) {
     let register_index : usize = gpio as usize / 10;
     let bit = (gpio % 10) * 3;
 
-    let ptr = (GPIO_BASE.0 + core::mem::size_of::<u32>()*register_index) as *mut u32;
+    let ptr = (gpio_base().0 + core::mem::size_of::<u32>()*register_index) as *mut u32;
 
     let old_value = unsafe{volatile_load(ptr)};
     let mask : u32 = 0b111 << bit;
@@ -80,8 +125,8 @@ fn turn_led_on() {
     let LED_GPCLR    : usize =   10;
     let LED_GPIO_BIT : usize =   15;
 
-    orr(GPIO_BASE.uoffset(4*LED_GPFSEL), 1 << LED_GPFBIT);
-    orr(GPIO_BASE.uoffset(4*LED_GPSET), 1 << LED_GPIO_BIT);
+    orr(gpio_base().uoffset(4*LED_GPFSEL), 1 << LED_GPFBIT);
+    orr(gpio_base().uoffset(4*LED_GPSET), 1 << LED_GPIO_BIT);
 }
 
 
@@ -110,7 +155,8 @@ pub extern "C" fn rpi_main(sp_end_virt: usize,
                                   kernel_start_virt: usize,
                                   kernel_end_virt: usize,
                                   l1table_id: usize,
-                                  l2table_space_id: usize)
+                                  l2table_space_id: usize,
+                                  boot_info: usize)
                                   -> ! {
 
     // first thing - zero out the bss
@@ -123,9 +169,9 @@ pub extern "C" fn rpi_main(sp_end_virt: usize,
         kernel_start_phy: ::mem::PhysicalAddress(kernel_start_phy),
         kernel_start_virt: ::mem::VirtualAddress(kernel_start_virt),
         kernel_end_virt: ::mem::VirtualAddress(kernel_end_virt),
-        // TODO: make stack size a constant and not hard coded
-        stack_phy: ::mem::PhysicalAddress(sp_end_phy - 2*mem::PAGE_SIZE), /* sp points to begining of stack.. */
-        stack_virt: ::mem::VirtualAddress(sp_end_virt - 2*mem::PAGE_SIZE),
+        stack_phy: ::mem::PhysicalAddress(sp_end_phy - mem::boot_stack_size()), /* sp points to begining of stack.. */
+        stack_virt: ::mem::VirtualAddress(sp_end_virt - mem::boot_stack_size()),
+        stack_size: mem::boot_stack_size(),
     };
 
     let kernel_size = kernel_end_virt - kernel_start_virt;
@@ -142,9 +188,14 @@ pub extern "C" fn rpi_main(sp_end_virt: usize,
     // TODO: can remove stub from skip ranges now
 
     
+    // when the boot loader doesn't tell, assume 128mb.
+    let boot_info = unsafe {
+        bootinfo::parse(::mem::VirtualAddress(l1table_id), boot_info, 1 << 27)
+    };
+
     ::arch::arm::arm_main(ml, &skip_ranges,
         ::mem::VirtualAddress(l1table_id),
-        ::mem::VirtualAddress(l2table_space_id), 1 << 27);
+        ::mem::VirtualAddress(l2table_space_id), &boot_info);
 }
 
 pub fn write_to_console(s: &str) {
@@ -164,11 +215,12 @@ impl PlatformServices{
 
     // called when memory is initialized.
     pub fn new() -> Self {
-    platform::get_memory_services().mem_manager.map_device(
-                    MMIO_PSTART,
-                    MMIO_VSTART,
-                    MMIO_PEND - MMIO_PSTART)
-        .unwrap();
+    unsafe {
+        MMIO_VSTART = platform::get_memory_services().mem_manager.ioremap(
+                        MMIO_PSTART,
+                        ::mem::MemorySize::Bytes(MMIO_SIZE))
+            .expect("Can't map mmio");
+    }
 
 
 
@@ -203,15 +255,27 @@ impl PlatformServices{
 
         let dm = unsafe{&mut platform::get_mut_platform_services().arch_services.driver_manager};
 
-        let timer = timer::SystemTimerDriver::new( Box::new(move||{::platform::get_platform_services().clock()}));
-        dm.add_driver_interruptable(timer);
-        let spi = spi::SPIDev::new();
-        dm.add_driver_spi(spi);
+        dm.register_match("brcm,bcm2835-system-timer", probe_timer);
+        dm.register_match("brcm,bcm2835-spi", probe_spi);
+        if let Some(tree) = bootinfo::device_tree() {
+            dm.probe_fdt(&tree);
+        }
+
+        // no tree, or one that doesn't have them (or that we couldn't make sense of): the
+        // addresses every pi has them at. we can't do without the timer.
+        if dm.probed("brcm,bcm2835-system-timer") == 0 {
+            let timer = timer::SystemTimerDriver::new( Box::new(move||{::platform::get_platform_services().clock()}));
+            dm.add_driver_interruptable(timer);
+        }
+        if dm.probed("brcm,bcm2835-spi") == 0 {
+            let spi = spi::SPIDev::new();
+            dm.add_driver_spi(spi);
+        }
 
 
       //  self.register_interrupts(pic);
 
-        write_to_console("Welcome home!");
+        platform::log(::bootparams::LOG_INFO, "Welcome home!");
     }
 
 
@@ -2,7 +2,12 @@ use io;
 use super::super::super::pl011;
 use super::gpio;
 
-const SERIAL_BASE_VADDR: ::mem::VirtualAddress = super::GPIO_BASE.uoffset(0x1000);
+// from the gpio base
+const SERIAL_OFFSET: usize = 0x1000;
+// the name of the uart on the command line
+const CONSOLE_NAME: &'static str = "ttyAMA0";
+// unless the command line says otherwise (console=ttyAMA0,<baud>)
+const DEFAULT_BAUD: u32 = 115200;
 
 pub struct Serial {
     pl: &'static mut pl011::PL011,
@@ -21,8 +26,9 @@ impl Serial {
         gpio.set_pullup_pulldown(GPIORX, gpio::OFF);
         gpio.set_pullup_pulldown(GPIOTX, gpio::OFF);
 
+        let baud = ::bootparams::get().console_baud(CONSOLE_NAME).unwrap_or(DEFAULT_BAUD);
         let s = unsafe { // TODO: init gpio..
-            Serial{ pl : pl011::PL011::new(SERIAL_BASE_VADDR, 3_000_000, 115200)}
+            Serial{ pl : pl011::PL011::new(super::gpio_base().uoffset(SERIAL_OFFSET), 3_000_000, baud as u64)}
         };
 
         s
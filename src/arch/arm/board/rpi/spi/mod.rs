@@ -29,7 +29,8 @@ impl TransferState {
 }
 
 pub struct SPIDev {
-    dev_impl : sync::CpuMutex<SPIDevImpl>
+    dev_impl : sync::CpuMutex<SPIDevImpl>,
+    irq : usize,
 }
 struct SPIDevImpl {
     spi : &'static mut spi::SPI,
@@ -47,7 +48,23 @@ impl SPIDev {
                     spi : spi::SPI::new(),
                     cur_transfer : None
                 }
-                )
+                ),
+           irq : super::intr::Interrupts::SPI as usize,
+        }
+    }
+    }
+
+    // an spi controller at base, as the device tree describes it.
+    pub fn at(base : ::mem::VirtualAddress, irq : usize) -> Self {
+    unsafe {    
+        SPIDev{
+           dev_impl : sync::CpuMutex::new(
+                SPIDevImpl {
+                    spi : spi::SPI::at(base),
+                    cur_transfer : None
+                }
+                ),
+           irq : irq,
         }
     }
     }
@@ -58,7 +75,7 @@ impl InterruptableDriver for SPIDev {}
 impl Driver for SPIDev {
     fn attach(&mut self, dh : DriverHandle) {
         let interrupt_service = &platform::get_platform_services().arch_services.interrupt_service;
-        interrupt_service.register_callback_on_intr(super::intr::Interrupts::SPI as usize, dh);
+        interrupt_service.register_callback_on_intr(self.irq, dh);
 
         // attach to fs node / spi node
     }
@@ -7,7 +7,8 @@ use device::spi::ClockPolarity;
 use device::spi::Configuration;
 use device::spi::Hz;
 
-const SPI0_ADDR : ::mem::VirtualAddress = super::super::GPIO_BASE.uoffset(0x4000);
+// from the gpio base
+const SPI0_OFFSET : usize = 0x4000;
 
 bitflags! {
     #[repr(C,packed)] pub flags ControlStatusFlags: u32 {
@@ -68,7 +69,11 @@ impl Driver for SPI {
 
 impl SPI { 
     pub unsafe fn new() -> &'static mut Self {
-        &mut *(SPI0_ADDR.0 as *mut SPI)
+        Self::at(super::super::gpio_base().uoffset(SPI0_OFFSET))
+    }
+
+    pub unsafe fn at(base : ::mem::VirtualAddress) -> &'static mut Self {
+        &mut *(base.0 as *mut SPI)
     }
 
     pub fn confiure(&mut self, c : Configuration) -> Result<(),()>{
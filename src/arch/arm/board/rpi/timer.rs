@@ -7,10 +7,14 @@ use arch::arm::InterruptableDriver;
 use core::cell::RefCell;
 use collections::boxed::Box;
 
-const SYS_TIMER_BASE_VADDR: ::mem::VirtualAddress = super::MMIO_VSTART.uoffset(0x3000);
+const SYS_TIMER_OFFSET: usize = 0x3000;
 
 const TIMER_HZ : u32 = 1000_000;
-const DELTA_COUNTER : u32 = TIMER_HZ/(platform::ticks_in_second as u32);
+
+// timer counts per tick
+fn delta_counter() -> u32 {
+    TIMER_HZ/(platform::ticks_in_second() as u32)
+}
 
 pub enum Matches {
 // can't use timers 0 and 2 as they are reservered for GPU
@@ -49,7 +53,11 @@ pub struct SystemTimer {
 
 impl SystemTimer {
     pub unsafe fn new() -> &'static mut Self {
- 		&mut *(SYS_TIMER_BASE_VADDR.0 as *mut SystemTimer)
+        Self::at(super::mmio_vaddr(SYS_TIMER_OFFSET))
+    }
+
+    pub unsafe fn at(base : ::mem::VirtualAddress) -> &'static mut Self {
+ 		&mut *(base.0 as *mut SystemTimer)
     }
 
 	pub fn clear_match(&mut self, m : Matches) {
@@ -69,6 +77,8 @@ impl SystemTimer {
 pub struct SystemTimerDriver {
     timer : RefCell<&'static mut SystemTimer>,
     callback:  Box<Fn()>,
+    // the interrupt of Match3
+    irq : usize,
 
 }
 
@@ -77,6 +87,16 @@ impl SystemTimerDriver {
         SystemTimerDriver {
             timer : RefCell::new(unsafe{SystemTimer::new()}),
             callback : callback,
+            irq : super::intr::Interrupts::TIMER3 as usize,
+        }
+    }
+
+    // a timer at base, as the device tree describes it.
+    pub fn at(base : ::mem::VirtualAddress, irq : usize, callback: Box<Fn()>) -> Self {
+        SystemTimerDriver {
+            timer : RefCell::new(unsafe{SystemTimer::at(base)}),
+            callback : callback,
+            irq : irq,
         }
     }
 
@@ -97,11 +117,11 @@ impl InterruptableDriver for SystemTimerDriver {}
 
 impl Driver for SystemTimerDriver {
     fn attach(&mut self, dh : DriverHandle) {
-        platform::get_platform_services().arch_services.interrupt_service.register_callback_on_intr(super::intr::Interrupts::TIMER3 as usize, dh);
+        platform::get_platform_services().arch_services.interrupt_service.register_callback_on_intr(self.irq, dh);
         
 
         let curcounter = {self.timer.borrow().counter_low.read()};
-        self.set_match(curcounter+DELTA_COUNTER);
+        self.set_match(curcounter+delta_counter());
     }
 }
 
@@ -109,7 +129,7 @@ impl platform::Interruptable for SystemTimerDriver {
     fn interrupted(&self) {
         (self.callback)();
         // 100ms
-        self.add_to_match(DELTA_COUNTER);
+        self.add_to_match(delta_counter());
         self.clear();
     }
 }
\ No newline at end of file
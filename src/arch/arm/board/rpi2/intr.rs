@@ -122,10 +122,13 @@ impl CorePIC {
         Self::new_for_cpu(cpuid)
     }
     pub fn new_for_cpu(cpuid : usize) -> Self {
-        let vbase = ::platform::get_memory_services().mem_manager.p2v(PIC_BASE_PADDR).unwrap();
+        let vbase = ::platform::get_memory_services().mem_manager.ioremap(
+            PIC_BASE_PADDR.uoffset(4*cpuid),
+            ::mem::MemorySize::Bytes(INTR_SOURCE_OFFSET + 4))
+            .expect("Can't map core pic");
 
         CorePIC {
-            vbase: vbase.uoffset(4*cpuid),
+            vbase: vbase,
         }
     }
 
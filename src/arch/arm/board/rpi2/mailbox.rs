@@ -56,7 +56,9 @@ const LOCAL_MBOX_ADDR : ::mem::PhysicalAddress = ::mem::PhysicalAddress(super::A
 
 impl LocalMailbox {
     pub fn new() -> Self {
-        let base = ::platform::get_memory_services().mem_manager.p2v(LOCAL_MBOX_ADDR).unwrap();
+        // 4 cpus, 0x10 bytes of write registers each, followed by the read registers
+        let base = ::platform::get_memory_services().mem_manager.ioremap(LOCAL_MBOX_ADDR, ::mem::MemorySize::Bytes(0x80))
+            .expect("Can't map mailboxes");
         LocalMailbox { 
             mailboxes: [CpuLocalMailbox::new(base.uoffset(0x10*0)),
                         CpuLocalMailbox::new(base.uoffset(0x10*1)),
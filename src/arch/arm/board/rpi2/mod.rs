@@ -11,7 +11,9 @@ use collections::boxed::Box;
 use alloc::rc::Rc;
 
 use super::super::mem;
+use super::super::bootinfo;
 use super::super::pic;
+use super::super::pl011;
 use ::platform;
 use ::thread;
 use rlibc;
@@ -22,9 +24,13 @@ use mem::PVMapper;
 use device::serial::SerialMMIO;
 use arch::arm::pic::InterruptSource;
 
-pub const ticks_in_second : usize = 20;
+pub const DEFAULT_TICKS_IN_SECOND : usize = 20;
 pub const NUM_CPUS : usize = 4;
 
+// the dma engines see the arm's ram through the videocore's bus addresses; 0xc000_0000 is the
+// alias that bypasses the vc l2 cache.
+pub const DMA_BUS_OFFSET: usize = 0xC000_0000;
+
 
 static mut current_stack : usize = 0;
 static mut current_page_table: *const () = 0 as  *const ();
@@ -40,13 +46,8 @@ fn down(a: usize) -> ::mem::PhysicalAddress {
 // see:
 // http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.dui0159b/Bbaficij.html
 const MMIO_PSTART: ::mem::PhysicalAddress = ::mem::PhysicalAddress(0x3f000000);
-const MMIO_SIZE: usize = (16<<20);
-const MMIO_PEND: ::mem::PhysicalAddress = MMIO_PSTART.uoffset(MMIO_SIZE); //16mb periferals + 16mv arm local
-const MMIO_VSTART: ::mem::VirtualAddress = ::mem::VirtualAddress(0x1000_0000);
 
 const ARM_LOCAL_PSTART: ::mem::PhysicalAddress = ::mem::PhysicalAddress(0x4000_0000);
-const ARM_LOCAL_PEND: ::mem::PhysicalAddress = ::mem::PhysicalAddress(ARM_LOCAL_PSTART.0 +  (1<<12)); //4kb
-const ARM_LOCAL_VSTART: ::mem::VirtualAddress = ::mem::VirtualAddress(MMIO_VSTART.0 + MMIO_SIZE);
 
 
 
@@ -63,14 +64,17 @@ extern "C" {
     
 }
 
-const GPIO_BASE : ::mem::VirtualAddress = ::mem::VirtualAddress(MMIO_VSTART.0 + 0x200000);
+const GPIO_BASE_PADDR : ::mem::PhysicalAddress = MMIO_PSTART.uoffset(0x200000);
+const GPIO_SIZE : usize = 0xb4;
+// set by init_board
+static mut GPIO_BASE : ::mem::VirtualAddress = ::mem::VirtualAddress(0);
 
 // thanks http://sysprogs.com/VisualKernel/tutorials/raspberry/jtagsetup/
 fn set_gpio_alt(gpio : u32, func : u32 ) {
     let register_index : usize = gpio as usize / 10;
     let bit = (gpio % 10) * 3;
 
-    let ptr = (GPIO_BASE.0 + core::mem::size_of::<u32>()*register_index) as *mut u32;
+    let ptr = (unsafe { GPIO_BASE.0 } + core::mem::size_of::<u32>()*register_index) as *mut u32;
 
     let old_value = unsafe{volatile_load(ptr)};
     let mask : u32 = 0b111 << bit;
@@ -94,8 +98,9 @@ fn turn_led_on() {
     let LED_GPCLR    : usize =   10;
     let LED_GPIO_BIT : usize =   15;
 
-    orr(GPIO_BASE.uoffset(4*LED_GPFSEL), 1 << LED_GPFBIT);
-    orr(GPIO_BASE.uoffset(4*LED_GPSET), 1 << LED_GPIO_BIT);
+    let gpio_base = unsafe { GPIO_BASE };
+    orr(gpio_base.uoffset(4*LED_GPFSEL), 1 << LED_GPFBIT);
+    orr(gpio_base.uoffset(4*LED_GPSET), 1 << LED_GPIO_BIT);
 }
 
 
@@ -124,7 +129,8 @@ pub extern "C" fn rpi_main(sp_end_virt: usize,
                                   kernel_start_virt: usize,
                                   kernel_end_virt: usize,
                                   l1table_id: usize,
-                                  l2table_space_id: usize)
+                                  l2table_space_id: usize,
+                                  boot_info: usize)
                                   -> ! {
 
     // first thing - zero out the bss
@@ -137,9 +143,9 @@ pub extern "C" fn rpi_main(sp_end_virt: usize,
         kernel_start_phy: ::mem::PhysicalAddress(kernel_start_phy),
         kernel_start_virt: ::mem::VirtualAddress(kernel_start_virt),
         kernel_end_virt: ::mem::VirtualAddress(kernel_end_virt),
-        // TODO: make stack size a constant and not hard coded
-        stack_phy: ::mem::PhysicalAddress(sp_end_phy - 2*mem::PAGE_SIZE), /* sp points to begining of stack.. */
-        stack_virt: ::mem::VirtualAddress(sp_end_virt - 2*mem::PAGE_SIZE),
+        stack_phy: ::mem::PhysicalAddress(sp_end_phy - mem::boot_stack_size()), /* sp points to begining of stack.. */
+        stack_virt: ::mem::VirtualAddress(sp_end_virt - mem::boot_stack_size()),
+        stack_size: mem::boot_stack_size(),
     };
 
     let kernel_size = kernel_end_virt - kernel_start_virt;
@@ -152,9 +158,14 @@ pub extern "C" fn rpi_main(sp_end_virt: usize,
                        down(ml.stack_phy.0)..up(sp_end_phy),
                        down(s_begin)..up(s_end)];
 
+    // when the boot loader doesn't tell, assume 128mb.
+    let boot_info = unsafe {
+        bootinfo::parse(::mem::VirtualAddress(l1table_id), boot_info, 1 << 27)
+    };
+
     ::arch::arm::arm_main(ml, &skip_ranges,
         ::mem::VirtualAddress(l1table_id),
-        ::mem::VirtualAddress(l2table_space_id), 1 << 27);
+        ::mem::VirtualAddress(l2table_space_id), &boot_info);
 }
 
 static mut serial_base: ::mem::VirtualAddress = ::mem::VirtualAddress(0);
@@ -202,24 +213,23 @@ extern {
 // TODO make sure we have a scheduler..
 pub fn init_board(pic : &mut pic::PIC< Box<pic::InterruptSource>, Rc<platform::Interruptable> >) -> PlatformServices {
     
-    platform::get_memory_services().mem_manager.map_device(
-                    ARM_LOCAL_PSTART,
-                    ARM_LOCAL_VSTART,
-                    ARM_LOCAL_PEND - ARM_LOCAL_PSTART)
-        .unwrap();
-    
-    platform::get_memory_services().mem_manager.map_device(
-                    MMIO_PSTART,
-                    MMIO_VSTART,
-                    MMIO_PEND - MMIO_PSTART)
-        .unwrap();
-
-    unsafe { serial_base = serial::SERIAL_BASE_VADDR;}
+    let mem_manager = &platform::get_memory_services().mem_manager;
+    unsafe {
+        serial_base = mem_manager.ioremap(serial::SERIAL_BASE_PADDR, ::mem::MemorySize::Bytes(serial::SERIAL_SIZE))
+            .expect("Can't map serial");
+        GPIO_BASE = mem_manager.ioremap(GPIO_BASE_PADDR, ::mem::MemorySize::Bytes(GPIO_SIZE))
+            .expect("Can't map gpio");
+    }
+    // the firmware set the uart up; only the rate is ours to change (console=ttyAMA0,<baud>)
+    if let Some(baud) = ::bootparams::get().console_baud(serial::CONSOLE_NAME) {
+        let _lock = serial_writer.lock();
+        unsafe { pl011::PL011::at(serial_base) }.set_baud_rate(serial::UART_CLOCK, baud as u64);
+    }
 
     // gpio mapped, we can enable JTAG pins!
   //  enable_debugger();
 
-    write_to_console("Welcome home!");
+    platform::log(::bootparams::LOG_INFO, "Welcome home!");
     
     // TODO: init mailbox
 
@@ -239,11 +249,8 @@ pub fn init_board(pic : &mut pic::PIC< Box<pic::InterruptSource>, Rc<platform::I
     //    wake other CPU(i)
     //    wait for CPU
 
-    let mem_manager = &::platform::get_memory_services().mem_manager;
     let fa = &::platform::get_platform_services().frame_alloc;
 
-    let base = mem_manager.p2v(ARM_LOCAL_PSTART).unwrap();
-        
     let mailboxes = mailbox::LocalMailbox::new();
   
 // part of cpu struct?
@@ -286,7 +293,7 @@ pub fn init_board(pic : &mut pic::PIC< Box<pic::InterruptSource>, Rc<platform::I
     unsafe{current_page_table = super::super::cpu::get_ttb0();}
 
 
-    for i in 1 .. NUM_CPUS {
+    for i in 1 .. platform::get_num_cpus() {
         
         let stk = ::thread::Thread::allocate_stack();
 
@@ -372,6 +379,7 @@ pub extern "C" fn rpi_multi_main() -> ! {
 
     // move this to arm_mp_start and call that fuction that will init innterrupt vectors stacks as well
     // enable interrupts
+    super::super::cpu::enable_high_vectors();
     super::super::build_mode_stacks();
 
     // TODO TODO BUG: init interrupt stacks
@@ -381,11 +389,10 @@ pub extern "C" fn rpi_multi_main() -> ! {
     let timers = & ::platform::get_platform_services().arch_services.as_ref().unwrap().board_services.timers;
     timers[::platform::get_current_cpu_id()].start_timer();
 
-    // make set current thread the idle loop in the current cpu
+    // the boot thread of this cpu, until it hands it over to the scheduler
     let tid = platform::ThreadId(::sched::MAIN_THREAD_ID.0 + platform::get_current_cpu_id());
     let mut curth = thread::Thread::new_cur_thread(tid);
-    curth.cpu_affinity = Some(platform::get_current_cpu_id());
-    curth.priority = 0;
+    curth.cpu_affinity = ::cpu::CpuMask::single(platform::get_current_cpu_id());
     platform::get_platform_services().get_current_cpu().set_running_thread(Box::new(curth));
 
 
@@ -393,8 +400,9 @@ pub extern "C" fn rpi_multi_main() -> ! {
 
 
     platform::set_interrupts(true);
-    platform::get_platform_services().get_scheduler().yield_thread();
+    platform::get_platform_services().get_scheduler().exit_thread();
     loop {
+        // never gets here
         platform::wait_for_interrupts();
     }
 
@@ -410,7 +418,9 @@ pub extern "C" fn rpi_multi_pre_main() -> ! {
 // this is a bit hacky as i am hoping that no stack will be used (there's no reason for it, anyway..)
 
     // init real page table - just use the same page table for all cpus..
+    unsafe{ super::super::cpu::set_ttb1(current_page_table); }
     unsafe{ super::super::cpu::set_ttb0(current_page_table); }
+    super::super::cpu::set_ttbcr(super::super::mem::TTBCR_SPLIT);
     // isb to make sure instructino completed
     super::super::cpu::instruction_synchronization_barrier();
     // flush tlb
@@ -3,10 +3,15 @@ use ::device::serial;
 use core::intrinsics::{volatile_load, volatile_store};
 
 pub const SERIAL_BASE_PADDR: ::mem::PhysicalAddress = super::MMIO_PSTART.uoffset(0x0020_1000);
-pub const SERIAL_BASE_VADDR: ::mem::VirtualAddress = super::MMIO_VSTART.uoffset(0x0020_1000);
+// the pl011 register block
+pub const SERIAL_SIZE: usize = 0x90;
 pub const DATA_REG_OFFSET : usize = 0;
 pub const FLAG_REG_OFFSET : usize = 0x18;
 pub const UARTFR_TXFE : u32 = 1 << 7;
+// the name of the uart on the command line
+pub const CONSOLE_NAME: &'static str = "ttyAMA0";
+// what the firmware sets the uart clock to, unless config.txt says otherwise
+pub const UART_CLOCK: u64 = 3_000_000;
 
 pub struct Writer {
     base: *mut u8,
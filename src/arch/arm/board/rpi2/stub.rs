@@ -118,9 +118,10 @@ fn enbable_jtag( ) {
 }
 
 
+// boot_info is the atags / device tree pointer the boot loader gave us, we pass it on.
 #[no_mangle]
 #[link_section=".stub"]
-pub extern "C" fn stub_main() -> ! {
+pub extern "C" fn stub_main(boot_info: usize) -> ! {
     cpu::enable_fpu();
 
     /*
@@ -266,19 +267,21 @@ pub extern "C" fn stub_main() -> ! {
             mov r1, $2
             mov r2, $3
             mov r3, $4
+            push {$8}
             push {$5}
             push {$6}
             push {$7}
             b $0 "
             :: 
-            "i"(super::rpi_main as extern "C" fn(_,_,_,_,_,_,_) -> !),
+            "i"(super::rpi_main as extern "C" fn(_,_,_,_,_,_,_,_) -> !),
             "r"(STACK_POINTER_END),
             "r"(stack_pointer_end_phy),
             "r"(kernel_start_phy),
             "r"(kernel_start_virt),
             "r"(l2table_unsafe),
             "r"(l1table_unsafe),
-            "r"(kernel_end_virt)
+            "r"(kernel_end_virt),
+            "r"(boot_info)
             : "sp","r0","r1","r2","r3" : "volatile"
       )
     }
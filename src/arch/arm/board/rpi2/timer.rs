@@ -9,6 +9,8 @@ const CORE0_IRQ_SOURCE : ::mem::PhysicalAddress = ::mem::PhysicalAddress(super::
 const TIMER_CONTROL_ENABLE  : u32 = 1 << 0;
 const TIMER_CONTROL_IMASK   : u32 = 1 << 1;
 const TIMER_CONTROL_ISTATUS : u32 = 1 << 2;
+// the crystal of the pi 2, for firmware that leaves cntfrq alone
+const DEFAULT_TIMER_HZ : u32 = 19_200_000;
 
 pub struct GlobalTimer {
 	time : u32
@@ -17,8 +19,12 @@ pub struct GlobalTimer {
 impl GlobalTimer {
 
 	pub fn new() -> Self {
+		let hz = match cpu::read_cnt_frq() {
+			0 => DEFAULT_TIMER_HZ,
+			hz => hz,
+		};
 		GlobalTimer{
-			time : 1_000_000
+			time : hz / (platform::ticks_in_second() as u32)
 		}
 	}
 
@@ -0,0 +1,278 @@
+// what the boot loader tells us about the machine: the memory, and the command line. it passes
+// (in r2) either an atag list or a flattened device tree.
+// http://www.simtec.co.uk/products/SWLINUX/files/booting_article.html
+//
+// this runs before there is a heap or our own page table, so everything is kept in fixed size
+// arrays.
+
+use core::slice;
+use core::str;
+
+use fdt;
+use platform;
+use super::mem;
+
+pub const MAX_MEM_REGIONS: usize = 8;
+pub const MAX_RESERVED: usize = 8;
+const MAX_CMDLINE: usize = 1024;
+
+// copied out of the atags / device tree, as those are not mapped after boot.
+static mut CMDLINE: [u8; MAX_CMDLINE] = [0; MAX_CMDLINE];
+static mut CMDLINE_LEN: usize = 0;
+// where the device tree is; it's reserved so it can be read after boot.
+static mut FDT_REGION: Option<MemRegion> = None;
+static mut FDT_VIRT: Option<::mem::VirtualAddress> = None;
+
+const ATAG_NONE: u32 = 0;
+const ATAG_CORE: u32 = 0x5441_0001;
+const ATAG_MEM: u32 = 0x5441_0002;
+const ATAG_INITRD2: u32 = 0x5442_0005;
+const ATAG_CMDLINE: u32 = 0x5441_0009;
+// the atag list is small; this is just a bound in case it is not terminated.
+const MAX_ATAGS_SIZE: usize = 0x4000;
+// a bound on the size in the device tree header, in case it is corrupt. real trees are a few
+// tens of kilobytes.
+const MAX_FDT_SIZE: usize = 2 << 20;
+// the last address we can use, in the 32 bit address space
+const MAX_PHYSICAL: u64 = 0xffff_ffff;
+
+#[derive(Copy, Clone)]
+pub struct MemRegion {
+    pub start: ::mem::PhysicalAddress,
+    pub end: ::mem::PhysicalAddress,
+}
+
+pub struct BootInfo {
+    // sorted by address
+    mem: [MemRegion; MAX_MEM_REGIONS],
+    num_mem: usize,
+    reserved: [MemRegion; MAX_RESERVED],
+    num_reserved: usize,
+    // the device tree, if that's what we got. it is also in reserved, so it stays around.
+    pub fdt: Option<MemRegion>,
+}
+
+const EMPTY_REGION: MemRegion = MemRegion {
+    start: ::mem::PhysicalAddress(0),
+    end: ::mem::PhysicalAddress(0),
+};
+
+impl BootInfo {
+    fn new() -> BootInfo {
+        BootInfo {
+            mem: [EMPTY_REGION; MAX_MEM_REGIONS],
+            num_mem: 0,
+            reserved: [EMPTY_REGION; MAX_RESERVED],
+            num_reserved: 0,
+            fdt: None,
+        }
+    }
+
+    pub fn mem_regions(&self) -> &[MemRegion] {
+        &self.mem[..self.num_mem]
+    }
+
+    // memory that is in the regions but is not ours to use
+    pub fn reserved(&self) -> &[MemRegion] {
+        &self.reserved[..self.num_reserved]
+    }
+
+    // the end of the highest memory region
+    pub fn mem_end(&self) -> usize {
+        self.mem_regions().iter().map(|r| r.end.0).max().unwrap_or(0)
+    }
+
+    // [start, start + size) cut down to the 32 bit address space. the end is exclusive, so a
+    // region that ends at 4gb loses its last page rather than wrapping to 0.
+    fn clamp(start: u64, size: u64) -> Option<MemRegion> {
+        if (size == 0) || (start > MAX_PHYSICAL) {
+            return None;
+        }
+        let last = ::core::cmp::min(start.saturating_add(size - 1), MAX_PHYSICAL);
+        let end = if last == MAX_PHYSICAL {
+            MAX_PHYSICAL & !(platform::PAGE_MASK as u64)
+        } else {
+            last + 1
+        };
+        if end <= start {
+            return None;
+        }
+        Some(MemRegion {
+            start: ::mem::PhysicalAddress(start as usize),
+            end: ::mem::PhysicalAddress(end as usize),
+        })
+    }
+
+    fn add_mem(&mut self, start: u64, size: u64) {
+        if self.num_mem == MAX_MEM_REGIONS {
+            return;
+        }
+        // we can only use what's in the 32 bit address space
+        let region = match BootInfo::clamp(start, size) {
+            Some(r) => r,
+            None => return,
+        };
+
+        let mut i = self.num_mem;
+        while (i > 0) && (self.mem[i - 1].start > region.start) {
+            self.mem[i] = self.mem[i - 1];
+            i -= 1;
+        }
+        self.mem[i] = region;
+        self.num_mem += 1;
+    }
+
+    fn add_reserved(&mut self, start: u64, size: u64) {
+        if self.num_reserved == MAX_RESERVED {
+            return;
+        }
+        if let Some(region) = BootInfo::clamp(start, size) {
+            self.reserved[self.num_reserved] = region;
+            self.num_reserved += 1;
+        }
+    }
+}
+
+// the kernel command line, as the boot loader gave it.
+pub fn cmdline() -> &'static str {
+    unsafe { str::from_utf8(&CMDLINE[..CMDLINE_LEN]).unwrap_or("") }
+}
+
+fn set_cmdline(s: &[u8]) {
+    // stop at the nul, if there is one
+    let len = s.iter().position(|b| *b == 0).unwrap_or(s.len());
+    let len = ::core::cmp::min(len, MAX_CMDLINE);
+    unsafe {
+        CMDLINE[..len].copy_from_slice(&s[..len]);
+        CMDLINE_LEN = len;
+    }
+}
+
+// the device tree the boot loader gave us, if any. it is mapped on first use, so it needs the
+// memory manager. it is in ram, so it's mapped as normal memory, like the rest of ram (frame 0
+// may share a page with it, and is mapped for the vectors).
+pub fn device_tree() -> Option<fdt::Fdt<'static>> {
+    unsafe {
+        if FDT_VIRT.is_none() {
+            let region = match FDT_REGION {
+                Some(r) => r,
+                None => return None,
+            };
+            FDT_VIRT = map_read_only(region);
+        }
+        match FDT_VIRT {
+            Some(v) => fdt::Fdt::from_address(v.0),
+            None => None,
+        }
+    }
+}
+
+fn map_read_only(region: MemRegion) -> Option<::mem::VirtualAddress> {
+    let mem_manager = &platform::get_memory_services().mem_manager;
+    let start = region.start.0 & !platform::PAGE_MASK;
+    let bytes = ((region.end.0 + platform::PAGE_MASK) & !platform::PAGE_MASK) - start;
+    let size = ::mem::MemorySize::Bytes(bytes);
+    let v = match mem_manager.alloc_region(size, platform::PAGE_SIZE) {
+        Some(v) => v,
+        None => return None,
+    };
+    if mem_manager.map(::mem::PhysicalAddress(start), v, size, ::mem::KERNEL_RODATA).is_err() {
+        mem_manager.free_region(v, size);
+        return None;
+    }
+    Some(v.uoffset(region.start.0 - start))
+}
+
+// boot_info is the physical address the boot loader gave us in r2. it's called while we are
+// still on the stub's page table, which is identity mapped at l1table_identity.
+// when the boot loader didn't tell us about the memory, we assume default_mem_size bytes from 0.
+pub unsafe fn parse(l1table_identity: ::mem::VirtualAddress,
+                    boot_info: usize,
+                    default_mem_size: usize)
+                    -> BootInfo {
+    let mut info = BootInfo::new();
+
+    let p = ::mem::PhysicalAddress(boot_info);
+    if (boot_info != 0) && mem::identity_map_boot(l1table_identity, p, 8).is_ok() {
+        let header = slice::from_raw_parts(boot_info as *const u8, 8);
+
+        if fdt::Fdt::is_fdt(header) {
+            let size = fdt::Fdt::size_from_header(header);
+            let tree = if (size <= MAX_FDT_SIZE) && mem::identity_map_boot(l1table_identity, p, size).is_ok() {
+                fdt::Fdt::from_address(boot_info)
+            } else {
+                None
+            };
+            if let Some(tree) = tree {
+                parse_fdt(&mut info, &tree);
+                info.add_reserved(boot_info as u64, size as u64);
+                info.fdt = Some(MemRegion {
+                    start: p,
+                    end: p.uoffset(size),
+                });
+                FDT_REGION = info.fdt;
+            }
+        } else if mem::identity_map_boot(l1table_identity, p, MAX_ATAGS_SIZE).is_ok() {
+            parse_atags(&mut info, boot_info);
+        }
+    }
+
+    if info.num_mem == 0 {
+        info.add_mem(0, default_mem_size as u64);
+    }
+
+    info
+}
+
+unsafe fn parse_atags(info: &mut BootInfo, atags: usize) {
+    let words = slice::from_raw_parts(atags as *const u32, MAX_ATAGS_SIZE / 4);
+    // the list starts with ATAG_CORE, that's how we know it's an atag list.
+    if words[1] != ATAG_CORE {
+        return;
+    }
+
+    // each tag starts with its size in words (including the two word header) and its type.
+    let mut i = 0;
+    while i + 2 <= words.len() {
+        let (size, tag) = (words[i] as usize, words[i + 1]);
+        if (tag == ATAG_NONE) || (size < 2) || (i + size > words.len()) {
+            break;
+        }
+
+        let data = &words[i + 2..i + size];
+        match tag {
+            // size, start
+            ATAG_MEM if data.len() >= 2 => info.add_mem(data[1] as u64, data[0] as u64),
+            // start, size
+            ATAG_INITRD2 if data.len() >= 2 => info.add_reserved(data[0] as u64, data[1] as u64),
+            ATAG_CMDLINE => {
+                set_cmdline(slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * 4))
+            }
+            _ => {}
+        }
+
+        i += size;
+    }
+}
+
+fn parse_fdt(info: &mut BootInfo, tree: &fdt::Fdt) {
+    for (address, size) in tree.reservations() {
+        info.add_reserved(address, size);
+    }
+
+    for node in tree.nodes().filter(|n| n.depth == 1) {
+        match fdt::node_base_name(node.name) {
+            "memory" => {
+                for (address, size) in node.reg() {
+                    info.add_mem(address, size);
+                }
+            }
+            "chosen" => {
+                if let Some(bootargs) = node.prop("bootargs") {
+                    set_cmdline(bootargs);
+                }
+            }
+            _ => {}
+        }
+    }
+}
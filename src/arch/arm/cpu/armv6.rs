@@ -55,4 +55,9 @@ pub fn wait_for_interrupts() {
             "::"r"(0)::"volatile"
             )
     }
-}
\ No newline at end of file
+}
+// the arm1176 has 32 byte cache lines.
+#[inline(always)]
+pub fn dcache_line_size() -> usize {
+    32
+}
@@ -117,3 +117,17 @@ pub fn write_cntv_tval(i : u32){write_reg!("p15,0,$0,c14,c3,0", i)}
 
 pub fn read_cntv_ctl() -> u32 { read_reg!("p15,0,$0,c14,c3,1") }
 pub fn write_cntv_ctl(i : u32){write_reg!("p15,0,$0,c14,c3,1", i)}
+
+// inner shareable tlb maintenance; broadcast to all the cpus in the inner shareable domain
+// (i.e. all the cores), so no ipi is needed.
+pub fn invalidate_tlb_is() { write_reg!("p15,0,$0,c8,c3,0", 0) }
+pub fn invalidate_tlb_mva_is(v: ::mem::VirtualAddress, asid: u32) {
+    write_reg!("p15,0,$0,c8,c3,1", (v.0 as u32 & !0xFFF) | (asid & 0xFF))
+}
+
+// the smallest data cache line, from the cache type register. DminLine is log2 of the number
+// of words.
+pub fn dcache_line_size() -> usize {
+    let ctr = read_reg!("p15,0,$0,c0,c0,1");
+    4 << ((ctr >> 16) & 0xF)
+}
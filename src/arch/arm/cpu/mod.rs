@@ -50,6 +50,116 @@ pub fn invalidate_tlb() {
     }
 }
 
+#[inline(always)]
+pub fn invalidate_tlb_mva(v: ::mem::VirtualAddress) {
+    // Invalidate unified TLB entry by MVA. the low 12 bits are ignored (or are the ASID on v7)
+    // http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0344k/I1001599.html
+    unsafe {
+        asm!("mcr p15, 0, $0, c8, c7, 1"  ::"r"(v.0 & !0xFFF)::"volatile")
+    }
+}
+
+// Invalidate a TLB entry by MVA, for an entry that is not global (i.e. user space) of an ASID
+#[inline(always)]
+pub fn invalidate_tlb_mva_asid(v: ::mem::VirtualAddress, asid: u32) {
+    unsafe {
+        asm!("mcr p15, 0, $0, c8, c7, 1"  ::"r"((v.0 as u32 & !0xFFF) | (asid & 0xFF))::"volatile")
+    }
+}
+
+// tlb maintenance for page table changes. on armv7 smp we use the inner shareable operations,
+// that reach all the cores, so page table changes don't need an ipi.
+#[cfg(all(feature = "armv7", feature = "multicpu"))]
+pub const TLB_BROADCAST: bool = true;
+#[cfg(not(all(feature = "armv7", feature = "multicpu")))]
+pub const TLB_BROADCAST: bool = false;
+
+// a page was changed or unmapped. asid is ignored for global (kernel) pages.
+#[cfg(all(feature = "armv7", feature = "multicpu"))]
+pub fn invalidate_tlb_page(v: ::mem::VirtualAddress, asid: u32) {
+    invalidate_tlb_mva_is(v, asid);
+    data_synchronization_barrier();
+}
+#[cfg(not(all(feature = "armv7", feature = "multicpu")))]
+pub fn invalidate_tlb_page(v: ::mem::VirtualAddress, asid: u32) {
+    invalidate_tlb_mva_asid(v, asid);
+    data_synchronization_barrier();
+}
+
+#[cfg(all(feature = "armv7", feature = "multicpu"))]
+pub fn invalidate_tlb_all_cpus() {
+    invalidate_tlb_is();
+    data_synchronization_barrier();
+}
+#[cfg(not(all(feature = "armv7", feature = "multicpu")))]
+pub fn invalidate_tlb_all_cpus() {
+    invalidate_tlb();
+    data_synchronization_barrier();
+}
+
+// data cache maintenance of a single line by address, to the point of coherency (where the
+// devices see memory).
+// http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0360e/I1014942.html
+#[inline(always)]
+pub fn clean_dcache_mva(v: usize) {
+    unsafe {
+        asm!("mcr p15, 0, $0, c7, c10, 1"  ::"r"(v)::"volatile")
+    }
+}
+
+#[inline(always)]
+pub fn invalidate_dcache_mva(v: usize) {
+    unsafe {
+        asm!("mcr p15, 0, $0, c7, c6, 1"  ::"r"(v)::"volatile")
+    }
+}
+
+#[inline(always)]
+pub fn clean_invalidate_dcache_mva(v: usize) {
+    unsafe {
+        asm!("mcr p15, 0, $0, c7, c14, 1"  ::"r"(v)::"volatile")
+    }
+}
+
+// write the dirty lines of [v, v + len) to memory; before a device reads it.
+pub fn clean_dcache_range(v: ::mem::VirtualAddress, len: usize) {
+    let line = dcache_line_size();
+    let mut a = v.0 & !(line - 1);
+    while a < v.0 + len {
+        clean_dcache_mva(a);
+        a += line;
+    }
+    data_synchronization_barrier();
+}
+
+// drop the lines of [v, v + len); after a device wrote it. lines that are only partly in the
+// range are cleaned too, so we don't lose what the cpu wrote next to it.
+pub fn invalidate_dcache_range(v: ::mem::VirtualAddress, len: usize) {
+    let line = dcache_line_size();
+    let end = v.0 + len;
+    let mut a = v.0 & !(line - 1);
+    while a < end {
+        if (a < v.0) || (a + line > end) {
+            clean_invalidate_dcache_mva(a);
+        } else {
+            invalidate_dcache_mva(a);
+        }
+        a += line;
+    }
+    data_synchronization_barrier();
+}
+
+// write the dirty lines of [v, v + len) to memory and drop them.
+pub fn clean_invalidate_dcache_range(v: ::mem::VirtualAddress, len: usize) {
+    let line = dcache_line_size();
+    let mut a = v.0 & !(line - 1);
+    while a < v.0 + len {
+        clean_invalidate_dcache_mva(a);
+        a += line;
+    }
+    data_synchronization_barrier();
+}
+
 #[inline(always)]
 pub fn set_ttb0(page_table: *const ()) {
     // Set Translation Table Base 0 (TTB0)
@@ -68,6 +178,46 @@ pub fn get_ttb0() -> *const () {
     return ttb0 as *const ();
 }
 
+// Data Fault Address Register - the address that caused the last data abort
+#[inline(always)]
+pub fn get_dfar() -> u32 {
+    let mut dfar: u32;
+    unsafe {
+        asm!("mrc p15, 0, $0, c6, c0, 0":  "=r"(dfar));
+    }
+    dfar
+}
+
+// Data Fault Status Register
+#[inline(always)]
+pub fn get_dfsr() -> u32 {
+    let mut dfsr: u32;
+    unsafe {
+        asm!("mrc p15, 0, $0, c5, c0, 0":  "=r"(dfsr));
+    }
+    dfsr
+}
+
+// Instruction Fault Address Register - the address that caused the last prefetch abort
+#[inline(always)]
+pub fn get_ifar() -> u32 {
+    let mut ifar: u32;
+    unsafe {
+        asm!("mrc p15, 0, $0, c6, c0, 2":  "=r"(ifar));
+    }
+    ifar
+}
+
+// Instruction Fault Status Register
+#[inline(always)]
+pub fn get_ifsr() -> u32 {
+    let mut ifsr: u32;
+    unsafe {
+        asm!("mrc p15, 0, $0, c5, c0, 1":  "=r"(ifsr));
+    }
+    ifsr
+}
+
 #[inline(always)]
 pub fn set_ttb1(page_table: *const ()) {
     // Set Translation Table Base 0 (TTB0)
@@ -78,6 +228,22 @@ pub fn set_ttb1(page_table: *const ()) {
     }
 }
 
+// Context ID Register; the low 8 bits are the current ASID.
+#[inline(always)]
+pub fn set_context_id(context_id: u32) {
+    unsafe {
+        asm!("mcr p15, 0, $0, c13, c0, 1" :: "r"(context_id) :: "volatile");
+    }
+}
+
+// Invalidate all the (non global) TLB entries of an ASID
+#[inline(always)]
+pub fn invalidate_tlb_asid(asid: u32) {
+    unsafe {
+        asm!("mcr p15, 0, $0, c8, c7, 2"  ::"r"(asid & 0xFF)::"volatile")
+    }
+}
+
 #[inline(always)]
 pub fn set_ttbcr(ttbcr: u32) {
     unsafe {
@@ -110,7 +276,7 @@ pub fn write_domain_access_control_register(dcr: u32) {
 fn get_p15_c1() -> u32 {
     let mut cr: u32;
     unsafe {
-        asm!("mcr p15, 0, $0, c1, c0, 0" : "=r"(cr));
+        asm!("mrc p15, 0, $0, c1, c0, 0" : "=r"(cr));
     }
     return cr;
 }
@@ -127,6 +293,7 @@ const MMU_BIT: u32 = 1;
 const DCACHE_BIT: u32 = 1 << 2;
 const ICACHE_BIT: u32 = 1 << 12;
 const XP_BIT: u32 = 1 << 23;
+const HIGH_VECTORS_BIT: u32 = 1 << 13;
 
 #[inline(always)]
 pub fn enable_mmu() {
@@ -146,6 +313,12 @@ pub fn enable_mmu() {
 }
 
 
+// take exceptions from 0xffff_0000 instead of 0, so the low addresses are free for user space.
+pub fn enable_high_vectors() {
+    set_system_control_register(get_p15_c1() | HIGH_VECTORS_BIT);
+    instruction_synchronization_barrier();
+}
+
 // not called from stub goes here:
 
 
@@ -1,7 +1,8 @@
+use core::cmp;
 use core::slice;
 use core::ops::{Index, IndexMut};
-use core::cmp;
 use core::ops;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
 use super::cpu;
 use ::mem::FrameAllocator;
@@ -21,137 +22,179 @@ pub const MB_SHIFT: usize = 20;
 pub const MB_SIZE: usize = 1 << MB_SHIFT;
 pub const MB_MASK: usize = MB_SIZE - 1;
 
-pub struct LameFrameAllocator {
-    cpu_mutex : sync::CpuMutex<LameFrameAllocatorInner>,
-}
-
-pub struct LameFrameAllocatorInner {
-    nextfree: ::mem::PhysicalAddress,
-    max: ::mem::PhysicalAddress,
+// the most ram any of the boards has (rpi2 has 1gb).
+pub const MAX_MEMORY: usize = 1 << 30;
 
-    ranges: [Option<ops::Range<::mem::PhysicalAddress>>;10],
-    free_frames: [Option<ops::Range<::mem::PhysicalAddress>>; 10],
-    free_frames_index: usize,
-}
+// one bit per frame. lives in the bss as we don't have a heap when the frame allocator is created.
+static mut FRAME_BITMAP: [u32; MAX_MEMORY >> (PAGE_SHIFT + 5)] = [0; MAX_MEMORY >> (PAGE_SHIFT + 5)];
 
-impl ::mem::FrameAllocator for LameFrameAllocator {
-    fn allocate(&self, number: usize) -> Option<::mem::PhysicalAddress> {
-        self.cpu_mutex.lock().allocate(number)
-    }
-    fn deallocate(&self, addr: ::mem::PhysicalAddress, size: usize) {
-        self.cpu_mutex.lock().deallocate(addr, size)
-    }
+pub fn new_frame_allocator(skip_ranges: &[ops::Range<::mem::PhysicalAddress>],
+                           mem_size: usize)
+                           -> ::mem::BitmapFrameAllocator {
+    // this is only called once during boot, so we are the only ones using the bitmap.
+    let bitmap = unsafe { &mut FRAME_BITMAP };
+    ::mem::BitmapFrameAllocator::new(bitmap, skip_ranges, mem_size)
 }
 
-impl LameFrameAllocator{
-    pub fn new(ranges: &[ops::Range<::mem::PhysicalAddress>],
-               max_size: usize)
-               -> LameFrameAllocator{
-        let mut copy_ranges : [Option<ops::Range<::mem::PhysicalAddress>>;10] =  [None, None, None, None, None, None, None, None, None, None];
-        let mut i = 0;
-        for r in ranges {
-            copy_ranges[i] = Some(r.clone());
-            i += 1;
-        }
+#[repr(packed)]
+pub struct L2TableDescriptor(u32);
 
-        LameFrameAllocator{cpu_mutex: sync::CpuMutex::new(
-            LameFrameAllocatorInner {
-                max: ::mem::PhysicalAddress(max_size),
-                nextfree: ::mem::PhysicalAddress(PAGE_SIZE), /* don't allocate frame zero cause the vector table is there.. */
-                ranges: copy_ranges,
-                free_frames: [None, None, None, None, None, None, None, None, None, None],
-                free_frames_index: 0,
-                        }
-                )
-        }
-    }
-}
+#[repr(packed)]
+pub struct L1TableDescriptor(u32);
 
-impl LameFrameAllocatorInner {
-    // assume no interrupts here.
-    fn allocate(&mut self, number: usize) -> Option<::mem::PhysicalAddress> {
-        if self.nextfree >= self.max {
-            return None;
-        }
+pub struct FirstLevelTableDescriptor(u32);
 
-        // if (number == 1) && self.free_frames_index > 0 {
-        // self.free_frames_index -= 1;
-        // if let Some(frameRange) =  self.free_frames[self.free_frames_index] {
-        //
-        // TODO if range is bigger than one page take just one page.
-        // if frameRange.
-        //
-        // self.free_frames[self.free_frames_index] = None;
-        // return  Some(frame);
-        // } else {
-        // panic!("Frame not there even though it should");
-        // }
-        // }
-        //
-        let mut cur_free;
-        let mut potential_next;
-
-        'outer: loop {
-            cur_free = self.nextfree;
-
-            potential_next = cur_free.offset((number << PAGE_SHIFT) as isize);
-
-            let cur_range = cur_free..potential_next;
-
-            for mayber in &self.ranges {
-                if let Some(ref r) = *mayber {
-                    if (cur_range.start < r.end) && (r.start < cur_range.end) {
-                        self.nextfree = cmp::max(self.nextfree, r.end);
-                        continue 'outer;
-                    }
-                }
-            }
+// descriptor bits for the extended (XP) format, that enable_mmu turns on.
+// http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0333h/Babifihd.html
+const CACHEABLE: u32 = 1 << 3;
+const BUFFERABLE: u32 = 1 << 2;
 
-            break;
-        }
+const L2_XN: u32 = 1 << 0;
+const L2_XPAGE_TYPE: u32 = 1 << 1;
+const L2_TEX_SHIFT: u32 = 6;
+const L2_AP_SHIFT: u32 = 4;
+const L2_APX: u32 = 1 << 9;
+const L2_SHAREABLE: u32 = 1 << 10;
+
+const L1_SECTION_TYPE: u32 = 0b10;
+const L1_XN: u32 = 1 << 4;
+const L1_AP_SHIFT: u32 = 10;
+const L1_TEX_SHIFT: u32 = 12;
+const L1_APX: u32 = 1 << 15;
+const L1_SHAREABLE: u32 = 1 << 16;
 
-        self.nextfree = potential_next;
+// http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0211k/Caceaije.html
+// AP bits; APX turns these to read only.
+const AP_KERNEL_ONLY: u32 = 0b01;
+// with APX: read only for all. (APX with AP_ALL_ACCESS is reserved on armv6.)
+const AP_USER_READ_ONLY: u32 = 0b10;
+const AP_ALL_ACCESS: u32 = 0b11;
+
+// translate memory attributes to the fields that are common to sections and pages.
+// returns (ap, apx, tex, c and b bits, xn, shareable)
+fn attributes_to_bits(attrs: ::mem::MemoryAttributes) -> (u32, bool, u32, u32, bool, bool) {
+    let (ap, apx) = match (attrs.contains(::mem::WRITE), attrs.contains(::mem::USER)) {
+        // kernel read write, user no access
+        (true, false) => (AP_KERNEL_ONLY, false),
+        // read write for all
+        (true, true) => (AP_ALL_ACCESS, false),
+        // kernel read only, user no access
+        (false, false) => (AP_KERNEL_ONLY, true),
+        // read only for all
+        (false, true) => (AP_USER_READ_ONLY, true),
+    };
+
+    // memory region types, without tex remap.
+    // http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0333h/Babifihd.html
+    let (tex, cb) = if attrs.contains(::mem::STRONGLY_ORDERED) {
+        (0b000, 0)
+    } else if attrs.contains(::mem::DEVICE) {
+        // shared device
+        (0b000, BUFFERABLE)
+    } else if attrs.contains(::mem::UNCACHED) {
+        // normal non-cacheable
+        (0b001, 0)
+    } else if attrs.contains(::mem::WRITE_THROUGH) {
+        (0b000, CACHEABLE)
+    } else {
+        // normal write back, no write allocate
+        (0b000, CACHEABLE | BUFFERABLE)
+    };
+
+    // device and strongly ordered memory are never executable, even if asked for.
+    let xn = !attrs.contains(::mem::EXECUTE) || attrs.intersects(::mem::DEVICE | ::mem::STRONGLY_ORDERED);
+
+    (ap, apx, tex, cb, xn, attrs.contains(::mem::SHAREABLE) || smp_shareable(attrs))
+}
 
-        if self.nextfree > self.max {
-            return None;
-        }
+// all cpus see the same normal memory, so it has to be marked as shareable for the caches to be
+// kept coherent.
+#[cfg(feature = "multicpu")]
+fn smp_shareable(attrs: ::mem::MemoryAttributes) -> bool {
+    !attrs.intersects(::mem::DEVICE | ::mem::STRONGLY_ORDERED)
+}
 
+#[cfg(not(feature = "multicpu"))]
+fn smp_shareable(_: ::mem::MemoryAttributes) -> bool {
+    false
+}
 
-        Some(cur_free)
+// the reverse of attributes_to_bits.
+fn bits_to_attributes(ap: u32, apx: bool, tex: u32, cb: u32, xn: bool, shareable: bool) -> ::mem::MemoryAttributes {
+    let mut attrs = ::mem::MemoryAttributes::empty();
+    if !apx {
+        attrs |= ::mem::WRITE;
     }
-
-    fn deallocate(&mut self, addr: ::mem::PhysicalAddress, size: usize) {
-        if (self.free_frames_index + 1) < self.free_frames.len() {
-            self.free_frames[self.free_frames_index] = Some(addr..addr.uoffset(size << PAGE_SHIFT));
-            self.free_frames_index += 1;
-        }
+    if (ap == AP_ALL_ACCESS) || (apx && (ap == AP_USER_READ_ONLY)) {
+        attrs |= ::mem::USER;
+    }
+    if !xn {
+        attrs |= ::mem::EXECUTE;
     }
+    if shareable {
+        attrs |= ::mem::SHAREABLE;
+    }
+    match (tex, cb) {
+        (0b000, 0) => attrs |= ::mem::STRONGLY_ORDERED,
+        (0b000, BUFFERABLE) => attrs |= ::mem::DEVICE,
+        (0b000, CACHEABLE) => attrs |= ::mem::WRITE_THROUGH,
+        (0b001, 0) => attrs |= ::mem::UNCACHED,
+        _ => {}
+    }
+    attrs
+}
+
+// frames that hold page tables, kernel and user; for the memory stats.
+static PAGE_TABLE_FRAMES: AtomicUsize = ATOMIC_USIZE_INIT;
 
+pub fn page_table_frames() -> usize {
+    PAGE_TABLE_FRAMES.load(Ordering::Relaxed)
 }
 
-#[repr(packed)]
-pub struct L2TableDescriptor(u32);
+// kernel virtual addresses that are handed out on demand (stacks, device memory, ...); from
+// the end of user space to the kernel image. the heap and the page table self map have fixed
+// addresses above the image, as they are needed before there is anything to allocate regions
+// with.
+pub const VMALLOC_START: ::mem::VirtualAddress = USER_SPACE_END;
+pub const VMALLOC_END: ::mem::VirtualAddress = ::mem::VirtualAddress(0xcc00_0000);
 
-#[repr(packed)]
-pub struct L1TableDescriptor(u32);
+// where we gonna map the virt table itself
+const L1_VIRT_ADDRESS: ::mem::VirtualAddress = ::mem::VirtualAddress(0xe000_0000);
 
-pub struct FirstLevelTableDescriptor(u32);
+// the l2 table of every kernel l1 entry is mapped here, a page per entry (in l1 order), for as
+// long as the l2 table exists; so the kernel page table can be edited in place.
+const L2_WINDOW_ADDRESS: ::mem::VirtualAddress = ::mem::VirtualAddress(0xe100_0000);
+const KERNEL_L1_START: usize = USER_SPACE_END.0 >> MB_SHIFT;
+// 8mb; the l2 tables that map the window itself are allocated on boot and never freed.
+const L2_WINDOW_SIZE: usize = (L1TABLE_ENTRIES - KERNEL_L1_START) << PAGE_SHIFT;
 
-// http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0333h/Babifihd.html
-const CACHEABLE: u32 = 1 << 3;
-const BUFFERABLE: u32 = 1 << 2;
-// const L2_SHAREABLE  : u32 = 1 << 10;
+// the reverse map (see ReverseMap); 4 bytes per frame of ram, so 1mb is enough for MAX_MEMORY.
+const REVERSE_MAP_ADDRESS: ::mem::VirtualAddress = ::mem::VirtualAddress(0xe200_0000);
 
-// const L2_NX  : u32 = 1;
-const L2_XPAGE_TYPE: u32 = 1 << 1;
+// only kernel l1 entries have a window; the callers check their addresses.
+fn l2_window_address(l1_index: usize) -> ::mem::VirtualAddress {
+    if l1_index < KERNEL_L1_START {
+        panic!("no l2 window for user addresses!")
+    }
+    L2_WINDOW_ADDRESS.uoffset((l1_index - KERNEL_L1_START) << PAGE_SHIFT)
+}
 
-// http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0211k/Caceaije.html
-// read write all:
-const L2_AP_ALL_ACCESS: u32 = 0b11 << 4;
-const L1_AP_ALL_ACCESS: u32 = 0b11 << 10;
+fn is_l2_window(l1_index: usize) -> bool {
+    let v = l1_index << MB_SHIFT;
+    (v >= L2_WINDOW_ADDRESS.0) && (v < L2_WINDOW_ADDRESS.0 + L2_WINDOW_SIZE)
+}
 
-// where we gonna map the virt table itself
-const L1_VIRT_ADDRESS: ::mem::VirtualAddress = ::mem::VirtualAddress(0xe000_0000);
+// TTBCR.N = 1: ttb0 translates the lower 2gb (user space) and ttb1 the rest (the kernel).
+pub const TTBCR_SPLIT: u32 = 1;
+pub const USER_SPACE_END: ::mem::VirtualAddress = ::mem::VirtualAddress(0x8000_0000);
+const USER_L1TABLE_ENTRIES: usize = L1TABLE_ENTRIES >> TTBCR_SPLIT;
+// the user l1 table is 8kb, and must be aligned to 8kb
+const USER_L1_FRAMES: usize = (USER_L1TABLE_ENTRIES * 4) >> PAGE_SHIFT;
+
+// not global: the tlb entry is tagged with the current asid
+const L2_NG: u32 = 1 << 11;
+
+static mut KERNEL_L1_PHY: ::mem::PhysicalAddress = ::mem::PhysicalAddress(0);
 
 impl L1TableDescriptor {
     fn new(physical_address_of_l2: ::mem::PhysicalAddress) -> L1TableDescriptor {
@@ -170,22 +213,29 @@ impl L1TableDescriptor {
         d
     }
 
-    fn new_section(section_addr: ::mem::PhysicalAddress, cachable: bool) -> L1TableDescriptor {
+    fn new_section(section_addr: ::mem::PhysicalAddress, attrs: ::mem::MemoryAttributes) -> L1TableDescriptor {
 
         if section_addr.0 & MB_MASK != 0 {
             panic!("Can't map unaligned sections")
         }
 
+        let (ap, apx, tex, cb, xn, shareable) = attributes_to_bits(attrs);
+
         let mut d: L1TableDescriptor = L1TableDescriptor(0);
         // 1MB section
-        d.0 |= 0b10;
-
-        if cachable {
-            d.0 |= CACHEABLE;
-            d.0 |= BUFFERABLE;
+        d.0 |= L1_SECTION_TYPE;
+        d.0 |= cb;
+        d.0 |= tex << L1_TEX_SHIFT;
+        d.0 |= ap << L1_AP_SHIFT;
+        if apx {
+            d.0 |= L1_APX;
+        }
+        if xn {
+            d.0 |= L1_XN;
+        }
+        if shareable {
+            d.0 |= L1_SHAREABLE;
         }
-
-        d.0 |= L1_AP_ALL_ACCESS;
 
         d.0 |= section_addr.0 as u32;
 
@@ -200,6 +250,20 @@ impl L1TableDescriptor {
         self.0 & 0b11 == 0b10
     }
 
+    fn is_l2_table(&self) -> bool {
+        self.0 & 0b11 == 0b01
+    }
+
+    fn section_attributes(&self) -> ::mem::MemoryAttributes {
+        let d = self.0;
+        bits_to_attributes((d >> L1_AP_SHIFT) & 0b11,
+                           (d & L1_APX) != 0,
+                           (d >> L1_TEX_SHIFT) & 0b111,
+                           d & (CACHEABLE | BUFFERABLE),
+                           (d & L1_XN) != 0,
+                           (d & L1_SHAREABLE) != 0)
+    }
+
     fn get_physical_address(&self) -> ::mem::PhysicalAddress {
         if !self.is_present() {
             panic!("entry not present!")
@@ -212,39 +276,36 @@ impl L1TableDescriptor {
 }
 
 impl L2TableDescriptor {
+    // kernel read-write data; used for the page table mappings themselves.
     fn new(physical_address_of_page: ::mem::PhysicalAddress) -> L2TableDescriptor {
-        if (physical_address_of_page.0 & PAGE_MASK) != 0 {
-            panic!("Can't map unaligned l2 frames")
-        }
-
-        let mut d: L2TableDescriptor = L2TableDescriptor(0);
-        // 4kb page
-        d.0 |= L2_XPAGE_TYPE;
-        d.0 |= CACHEABLE;
-        d.0 |= BUFFERABLE;
-        d.0 |= L2_AP_ALL_ACCESS;
-
-        // Only one cpu now.. no need to set shareable
-
-        // set permissions
-
-        d.0 |= physical_address_of_page.0 as u32;
-
-        d
+        Self::with_attributes(physical_address_of_page, ::mem::KERNEL_DATA)
     }
 
-    fn new_device(physical_address_of_page: ::mem::PhysicalAddress) -> L2TableDescriptor {
+    fn with_attributes(physical_address_of_page: ::mem::PhysicalAddress,
+                       attrs: ::mem::MemoryAttributes)
+                       -> L2TableDescriptor {
         if (physical_address_of_page.0 & PAGE_MASK) != 0 {
             panic!("Can't map unaligned l2 frames")
         }
 
+        let (ap, apx, tex, cb, xn, shareable) = attributes_to_bits(attrs);
+
         let mut d: L2TableDescriptor = L2TableDescriptor(0);
         // 4kb page
         d.0 |= L2_XPAGE_TYPE;
-        d.0 |= L2_AP_ALL_ACCESS;
+        d.0 |= cb;
+        d.0 |= tex << L2_TEX_SHIFT;
+        d.0 |= ap << L2_AP_SHIFT;
+        if apx {
+            d.0 |= L2_APX;
+        }
+        if xn {
+            d.0 |= L2_XN;
+        }
+        if shareable {
+            d.0 |= L2_SHAREABLE;
+        }
 
-        // Only one cpu now.. no need to set shareable
-        // set permissions
         d.0 |= physical_address_of_page.0 as u32;
 
         d
@@ -260,6 +321,22 @@ impl L2TableDescriptor {
         }
         ::mem::PhysicalAddress((self.0 as usize) & (!PAGE_MASK))
     }
+
+    fn attributes(&self) -> ::mem::MemoryAttributes {
+        let d = self.0;
+        bits_to_attributes((d >> L2_AP_SHIFT) & 0b11,
+                           (d & L2_APX) != 0,
+                           (d >> L2_TEX_SHIFT) & 0b111,
+                           d & (CACHEABLE | BUFFERABLE),
+                           (d & L2_XN) != 0,
+                           (d & L2_SHAREABLE) != 0)
+    }
+}
+
+impl L2Table {
+    fn is_empty(&self) -> bool {
+        self.descriptors.iter().all(|d| !d.is_present())
+    }
 }
 // repr C might not be needed, but let's be on the safe side.
 #[repr(packed)]
@@ -312,27 +389,22 @@ pub struct MemLayout {
     pub kernel_end_virt: ::mem::VirtualAddress,
     pub stack_phy: ::mem::PhysicalAddress,
     pub stack_virt: ::mem::VirtualAddress,
+    pub stack_size: usize,
 }
 
 
 fn get_init_frames(fa: & ::mem::FrameAllocator) -> [::mem::PhysicalAddress; 5] {
-    const NUM_FRAMES: usize = 7; // guaranteed to have somthing aligned here..
-    let mut free_frames: [::mem::PhysicalAddress; NUM_FRAMES] = [::mem::PhysicalAddress(0); NUM_FRAMES];
-    let pa = fa.allocate(NUM_FRAMES).unwrap();
-
-    // find out which one devides with 16k
-    let l1_start_frame = (4 - ((pa.0 >> PAGE_SHIFT) & 0b11)) & 0b11;
-
-    for i in 0..NUM_FRAMES {
-        let shifted_index = (i + l1_start_frame) % NUM_FRAMES;
-        free_frames[i] = pa.offset((shifted_index << PAGE_SHIFT) as isize);
-    }
-
-    // don't need the last two..
-    fa.deallocate(free_frames[5], 1);
-    fa.deallocate(free_frames[6], 1);
-
-    return [free_frames[0], free_frames[1], free_frames[2], free_frames[3], free_frames[4]];
+    // the l1 table is 16kb and must be aligned to 16kb
+    const L1_FRAMES: usize = 4;
+    let l1 = fa.allocate_aligned(L1_FRAMES, L1_FRAMES).expect("no frames for l1 table");
+    let l2 = fa.allocate(1).expect("no frames for l2 table");
+    PAGE_TABLE_FRAMES.fetch_add(L1_FRAMES + 1, Ordering::Relaxed);
+
+    [l1,
+     l1.uoffset(1 << PAGE_SHIFT),
+     l1.uoffset(2 << PAGE_SHIFT),
+     l1.uoffset(3 << PAGE_SHIFT),
+     l2]
 }
 
 fn up(a: usize) -> usize {
@@ -342,18 +414,143 @@ fn down(a: usize) -> usize {
     a & (!PAGE_MASK)
 }
 
-fn mb_up(a: usize) -> usize {
-    (a + MB_MASK) & (!MB_MASK)
+
+// a kernel image section, as laid out by the linker script
+pub struct KernelSection {
+    pub start: ::mem::VirtualAddress,
+    pub end: ::mem::VirtualAddress,
+    pub attrs: ::mem::MemoryAttributes,
+}
+
+enum LinkerSymbol {}
+
+extern "C" {
+    static _kernel_start_virt: *const LinkerSymbol;
+    static _etext: *const LinkerSymbol;
+    static _rodata_start: *const LinkerSymbol;
+    static _rodata_end: *const LinkerSymbol;
+    static _data_start: *const LinkerSymbol;
+    static __bss_start: *const LinkerSymbol;
+    static __bss_end: *const LinkerSymbol;
+    static _boot_stack_size: *const LinkerSymbol;
+}
+
+fn symbol_address(s: &*const LinkerSymbol) -> usize {
+    s as *const *const LinkerSymbol as usize
+}
+
+// the linker script page aligns the sections, so they don't share pages.
+pub fn kernel_sections() -> [KernelSection; 4] {
+    unsafe {
+        [KernelSection {
+             start: ::mem::VirtualAddress(symbol_address(&_kernel_start_virt)),
+             end: ::mem::VirtualAddress(symbol_address(&_etext)),
+             attrs: ::mem::KERNEL_TEXT,
+         },
+         KernelSection {
+             // also has .data.rel.ro and the init arrays, that are never written after linking.
+             start: ::mem::VirtualAddress(symbol_address(&_rodata_start)),
+             end: ::mem::VirtualAddress(symbol_address(&_rodata_end)),
+             attrs: ::mem::KERNEL_RODATA,
+         },
+         KernelSection {
+             // .data and the thread local templates
+             start: ::mem::VirtualAddress(symbol_address(&_data_start)),
+             end: ::mem::VirtualAddress(symbol_address(&__bss_start)),
+             attrs: ::mem::KERNEL_DATA,
+         },
+         KernelSection {
+             start: ::mem::VirtualAddress(symbol_address(&__bss_start)),
+             end: ::mem::VirtualAddress(symbol_address(&__bss_end)),
+             attrs: ::mem::KERNEL_DATA,
+         }]
+    }
+}
+
+// the size of the stack the stub gives the boot cpu. set in the linker script.
+pub fn boot_stack_size() -> usize {
+    unsafe { symbol_address(&_boot_stack_size) }
 }
-fn mb_down(a: usize) -> usize {
-    a & (!MB_MASK)
+
+// maps the l2 tables of the new page table, while it is being built, through a slot of the
+// boot page table.
+struct BootL2<'a> {
+    tmp: &'a mut L2Table,
+    tmp_index: usize,
+    // the l1 entry of the l2 table that is currently in the slot
+    l1_index: Option<usize>,
+}
+
+impl<'a> BootL2<'a> {
+    // returns the l2 table of the new page table for v; allocates it if it's not there yet.
+    fn map(&mut self, fa: & ::mem::FrameAllocator, newl1: &mut L1Table, v: usize) -> L2Table {
+        let l1_index = v >> MB_SHIFT;
+        let frame_address = L1_VIRT_ADDRESS.uoffset(self.tmp_index << PAGE_SHIFT);
+
+        if self.l1_index == Some(l1_index) {
+            return unsafe { L2Table::from_virt_address_no_init(frame_address) };
+        }
+
+        let mut need_init = false;
+        if !newl1[l1_index].is_present() {
+            let frame = fa.allocate(1).unwrap();
+            PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+            newl1[l1_index] = L1TableDescriptor::new(frame);
+            need_init = true;
+        }
+
+        self.tmp[self.tmp_index] = L2TableDescriptor::new(newl1[l1_index].get_physical_address());
+        self.l1_index = Some(l1_index);
+
+        cpu::memory_write_barrier();
+        cpu::flush_caches();
+        cpu::invalidate_tlb_mva(frame_address);
+        cpu::data_synchronization_barrier();
+
+        unsafe {
+            if need_init {
+                L2Table::from_virt_address_init(frame_address)
+            } else {
+                L2Table::from_virt_address_no_init(frame_address)
+            }
+        }
+    }
 }
 
+// makes [p, p + size) readable at the same virtual address, through the page table the stub gave
+// us. used for what the boot loader left us (atags or a device tree), that we read before we have
+// our own page table; the mapping goes away with the stub's page table.
+// fails if the range wraps around the end of the address space.
+pub unsafe fn identity_map_boot(l1table_identity: ::mem::VirtualAddress,
+                                p: ::mem::PhysicalAddress,
+                                size: usize)
+                                -> Result<(), ()> {
+    let end = try!(p.0.checked_add(size).ok_or(()));
+    let mut active_table = L1Table::from_virt_address_no_init(l1table_identity);
+    let mut mb = p.0 & !MB_MASK;
+    while mb < end {
+        // ram is below the kernel's addresses, so this only finds the stub's own section.
+        if !active_table[mb >> MB_SHIFT].is_present() {
+            active_table[mb >> MB_SHIFT] =
+                L1TableDescriptor::new_section(::mem::PhysicalAddress(mb), ::mem::KERNEL_RODATA);
+        }
+        mb = match mb.checked_add(MB_SIZE) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    cpu::memory_write_barrier();
+    cpu::invalidate_tlb();
+    cpu::data_synchronization_barrier();
+    Ok(())
+}
 
 // TODO fix frame allocator to not use stub and stack.
 pub fn init_page_table(l1table_identity: ::mem::VirtualAddress,
                        l2table_identity: ::mem::VirtualAddress,
                        ml: &MemLayout,
+                       mem_end: usize,
                        fa: &mut ::mem::FrameAllocator)
                        -> PageTable {
     let mut active_table = unsafe { L1Table::from_virt_address_no_init(l1table_identity) };
@@ -402,99 +599,62 @@ pub fn init_page_table(l1table_identity: ::mem::VirtualAddress,
     // now when we will switch the page table, the page table itself will be available in the same place.
 
 
-    // map the kernel in the new page table:
-    let kernel_size = up(::mem::to_bytes(ml.kernel_end_virt - ml.kernel_start_virt));
-    // mega bytes rounded up
-    let nummb = ((kernel_size + MB_MASK) & (!MB_MASK)) >> MB_SHIFT;
-
-    // for each meg:
-    for i in 0..nummb {
-
-        // get new frame
-        let frame = fa.allocate(1).unwrap();
-
-        // map the frame
-        l2[next_free_l2_index] = L2TableDescriptor::new(frame);
-
-        // flush changes
-        // TODO not sure i need all of those.. but lets start with that
-        cpu::memory_write_barrier();
-        // make sure all is in main memory.. probably not needed now, but good practice for smp..
-        cpu::flush_caches();
-        cpu::invalidate_tlb();
-
-        // frame now available here:
-        let frame_address = L1_VIRT_ADDRESS.uoffset(next_free_l2_index << PAGE_SHIFT);
-        let mut curr_kernel_l2 = unsafe { L2Table::from_virt_address_init(frame_address) };
-        // for each 4k block in the mb, map it in new_framel2
-        let curphy_start = ml.kernel_start_phy.uoffset(i << MB_SHIFT);
-        // check that in the end we don't map a full MB
-        let nextmb = ml.kernel_start_phy.uoffset((i + 1) << MB_SHIFT);
-        let curphy_end = if (i + 1) == nummb {
-            ml.kernel_start_phy.uoffset(kernel_size)
-        } else {
-            nextmb
-        };
-
-        let mut l2loopindex = 0;
-        for cur_frame in (curphy_start.0..curphy_end.0).step_by(PAGE_SIZE) {
-            curr_kernel_l2[l2loopindex] = L2TableDescriptor::new(::mem::PhysicalAddress(cur_frame));
-            l2loopindex += 1;
-        } // TODO: why do i need the code below?
-        for cur_frame in (curphy_end.0..nextmb.0).step_by(PAGE_SIZE) {
-            curr_kernel_l2[l2loopindex] = L2TableDescriptor::new(::mem::PhysicalAddress(cur_frame));
-            l2loopindex += 1;
+    // map the kernel in the new page table, every section with its own permissions. whole
+    // megabytes of a section are mapped as 1mb sections, the rest with pages.
+    // the kernel is 1mb aligned both physically and virtually, so the two line up.
+    // nothing is recorded until the reverse map gets its memory, after the switch; the stub only
+    // mapped the kernel image, and that may not include all of the bss.
+    let mut boot_l2 = BootL2 {
+        tmp: &mut l2,
+        tmp_index: next_free_l2_index,
+        l1_index: None,
+    };
+    for section in kernel_sections().iter() {
+        let mut v = down(section.start.0);
+        let end = up(section.end.0);
+        while v < end {
+            let p = ml.kernel_start_phy.0 + (v - ml.kernel_start_virt.0);
+            if ((v & MB_MASK) == 0) && (end - v >= MB_SIZE) {
+                newl1[v >> MB_SHIFT] = L1TableDescriptor::new_section(::mem::PhysicalAddress(p), section.attrs);
+                v += MB_SIZE;
+            } else {
+                let mut kernel_l2 = boot_l2.map(fa, &mut newl1, v);
+                kernel_l2[(v >> PAGE_SHIFT) & 0xFF] =
+                    L2TableDescriptor::with_attributes(::mem::PhysicalAddress(p), section.attrs);
+                v += PAGE_SIZE;
+            }
         }
-
-        // add the l2 frame to the l2 map
-        newl1[(ml.kernel_start_virt.0 >> MB_SHIFT) + i] = L1TableDescriptor::new(frame);
-
     }
-    // map the stack
-    // get stack pointer
-    let sp = ml.stack_virt.0 & (!PAGE_MASK);
-    let spframe = ml.stack_phy.0 & (!PAGE_MASK);
 
-    let mut need_init = false;
-
-    if !newl1[sp >> MB_SHIFT].is_present() {
-        let frame = fa.allocate(1).unwrap();
-        need_init = true;
-        newl1[sp >> MB_SHIFT] = L1TableDescriptor::new(frame);
+    // map the boot stack
+    let sp = down(ml.stack_virt.0);
+    let spframe = down(ml.stack_phy.0);
+    for i in 0..(up(ml.stack_size) >> PAGE_SHIFT) {
+        let v = sp + (i << PAGE_SHIFT);
+        let mut stack_l2 = boot_l2.map(fa, &mut newl1, v);
+        stack_l2[(v >> PAGE_SHIFT) & 0xFF] =
+            L2TableDescriptor::with_attributes(::mem::PhysicalAddress(spframe + (i << PAGE_SHIFT)),
+                                               ::mem::KERNEL_DATA);
     }
-    // first, map the existing current stack
-    let stackframe = newl1[sp >> MB_SHIFT].get_physical_address();
-
-    // replaceing page - destroy old page in caches. TODO - is this needed.
-    cpu::memory_write_barrier();
-    cpu::flush_caches();
-    cpu::invalidate_tlb();
-
-    // temporary the l2 entry to memory
-    l2[next_free_l2_index] = L2TableDescriptor::new(stackframe);
-
-    // flush changes
-    cpu::memory_write_barrier();
-    cpu::flush_caches();
-    cpu::invalidate_tlb();
-
-    let frame_address = L1_VIRT_ADDRESS.uoffset(next_free_l2_index << PAGE_SHIFT);
-    let mut stack_l2 = unsafe { if need_init {  L2Table::from_virt_address_init(frame_address) } else { L2Table::from_virt_address_no_init(frame_address) } };
-    // TODO: set nx bit
-    // TODO: make stack size a constant and not hard coded
-    stack_l2[(sp >> PAGE_SHIFT) & 0xFF] = L2TableDescriptor::new(::mem::PhysicalAddress(spframe));
-    stack_l2[((sp >> PAGE_SHIFT) & 0xFF) + 1] = L2TableDescriptor::new(::mem::PhysicalAddress(spframe + PAGE_SIZE));
 
+    // map all the l2 tables to the l2 window. first make sure the l2 tables of the window itself
+    // are there, so they are mapped too.
+    for i in 0..(L2_WINDOW_SIZE >> MB_SHIFT) {
+        boot_l2.map(fa, &mut newl1, L2_WINDOW_ADDRESS.0 + (i << MB_SHIFT));
+    }
+    for l1_index in KERNEL_L1_START..L1TABLE_ENTRIES {
+        if !newl1[l1_index].is_l2_table() {
+            continue;
+        }
+        let l2phy = newl1[l1_index].get_physical_address();
+        let w = l2_window_address(l1_index);
+        let mut window_l2 = boot_l2.map(fa, &mut newl1, w.0);
+        window_l2[(w.0 >> PAGE_SHIFT) & 0xFF] = L2TableDescriptor::new(l2phy);
+    }
 
     // turn on new mmu and free the stub memory
-    // the kernel now has a page table with the l1 mapped to
-    // L1_VIRT_ADDRESS and l2 table that maps the virt table to L1_VIRT_ADDRESS mapping is at
-    // L1_VIRT_ADDRESS + 5*PAGE_SIZE. it has five entries taken. so available from index #5
-    // so once can use that 5th index to init new frames and place them in the page table.
-
-    // when memory is freed, we need to find out the physical addresses so we can free them.
-    // to do that we will need to map the l2 table, and temporary map it again.
-    // in read all the frames it points to and free them.
+    // the kernel now has a page table with the l1 mapped to L1_VIRT_ADDRESS, and all of its l2
+    // tables mapped to the l2 window.
     cpu::memory_write_barrier();
     // disable access checks for domain 0
     // http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0344k/I1001599.html
@@ -502,15 +662,23 @@ pub fn init_page_table(l1table_identity: ::mem::VirtualAddress,
     cpu::flush_caches();
     cpu::data_synchronization_barrier();
     cpu::write_domain_access_control_register(1);
+    // the kernel lives in the upper half, translated by ttb1. ttb0 is switched per address space;
+    // kernel only threads use the kernel table, where nothing is mapped in the lower half.
+    unsafe { KERNEL_L1_PHY = free_frames[0] };
+    cpu::set_ttb1(free_frames[0].0 as *const ());
     cpu::set_ttb0(free_frames[0].0 as *const ());
+    cpu::set_ttbcr(TTBCR_SPLIT);
     cpu::invalidate_tlb();
 
+    // the reverse map is filled from the new table, once it has memory of its own.
+    let mut inner = PageTableInner {
+        descriptors: newl1,
+        reverse: ReverseMap::new(),
+    };
+    inner.init_reverse_map(fa, mem_end);
+
     PageTable{
-        cpu_mutex : sync::CpuMutex::new(PageTableInner {
-            descriptors: newl1,
-            tmp_map: newl2,
-            }
-        )
+        cpu_mutex : sync::CpuMutex::new(inner)
     }
 }
 
@@ -520,116 +688,231 @@ pub struct PageTable {
 
 pub struct PageTableInner {
     pub descriptors: L1Table,
-    tmp_map: L2Table,
+    reverse: ReverseMap,
+}
+
+// p2v support: remembers where every physical page is mapped, so we don't have to walk the page
+// table. if a page is mapped more than once, the first mapping is the one that is kept; once it
+// is unmapped, p2v doesn't find the page through the others.
+// ram (below the end of memory) gets an entry per page, in frames mapped at REVERSE_MAP_ADDRESS;
+// the few pages above it (device memory) are kept in a small table. this is filled while the heap
+// grows, so it can't allocate.
+const MAX_HIGH_MAPPINGS: usize = 64;
+
+struct ReverseMap {
+    // virtual page number of each physical page; 0 means not mapped (nothing is ever mapped
+    // at the first virtual page).
+    ram: &'static mut [u32],
+    // (physical page number, virtual page number); a physical page number of 0 means free.
+    high: [(u32, u32); MAX_HIGH_MAPPINGS],
+}
+
+impl ReverseMap {
+    // empty until init_reverse_map gives it memory.
+    fn new() -> ReverseMap {
+        ReverseMap {
+            ram: &mut [],
+            high: [(0, 0); MAX_HIGH_MAPPINGS],
+        }
+    }
+
+    fn insert(&mut self, p: ::mem::PhysicalAddress, v: ::mem::VirtualAddress) {
+        let (ppn, vpn) = ((p.0 >> PAGE_SHIFT) as u32, (v.0 >> PAGE_SHIFT) as u32);
+        if (ppn as usize) < self.ram.len() {
+            if self.ram[ppn as usize] == 0 {
+                self.ram[ppn as usize] = vpn;
+            }
+            return;
+        }
+
+        if self.high.iter().any(|e| e.0 == ppn) {
+            return;
+        }
+        match self.high.iter().position(|e| e.0 == 0) {
+            Some(i) => self.high[i] = (ppn, vpn),
+            // p2v will not find it, but the mapping itself is fine.
+            None => {}
+        }
+    }
+
+    fn remove(&mut self, p: ::mem::PhysicalAddress, v: ::mem::VirtualAddress) {
+        let (ppn, vpn) = ((p.0 >> PAGE_SHIFT) as u32, (v.0 >> PAGE_SHIFT) as u32);
+        if (ppn as usize) < self.ram.len() {
+            if self.ram[ppn as usize] == vpn {
+                self.ram[ppn as usize] = 0;
+            }
+            return;
+        }
+
+        for e in self.high.iter_mut() {
+            if *e == (ppn, vpn) {
+                *e = (0, 0);
+            }
+        }
+    }
+
+    fn lookup(&self, p: ::mem::PhysicalAddress) -> Option<::mem::VirtualAddress> {
+        let ppn = (p.0 >> PAGE_SHIFT) as u32;
+        let vpn = if (ppn as usize) < self.ram.len() {
+            self.ram[ppn as usize]
+        } else {
+            match self.high.iter().find(|e| e.0 == ppn) {
+                Some(e) => e.1,
+                None => 0,
+            }
+        };
+
+        if vpn == 0 {
+            return None;
+        }
+        Some(::mem::VirtualAddress(((vpn as usize) << PAGE_SHIFT) | (p.0 & PAGE_MASK)))
+    }
+
+    fn insert_section(&mut self, p: ::mem::PhysicalAddress, v: ::mem::VirtualAddress) {
+        for i in 0..(MB_SIZE >> PAGE_SHIFT) {
+            self.insert(p.uoffset(i << PAGE_SHIFT), v.uoffset(i << PAGE_SHIFT));
+        }
+    }
+
+    fn remove_section(&mut self, p: ::mem::PhysicalAddress, v: ::mem::VirtualAddress) {
+        for i in 0..(MB_SIZE >> PAGE_SHIFT) {
+            self.remove(p.uoffset(i << PAGE_SHIFT), v.uoffset(i << PAGE_SHIFT));
+        }
+    }
 }
 
 impl PageTableInner {
-    // TODO add error handling..
+    // give the reverse map its memory and fill it from the page table. called once, on boot,
+    // when the new page table is live.
+    fn init_reverse_map(&mut self, fa: & ::mem::FrameAllocator, mem_end: usize) {
+        let entries = cmp::min(mem_end, MAX_MEMORY) >> PAGE_SHIFT;
+        let pages = up(entries * 4) >> PAGE_SHIFT;
+        for i in 0..pages {
+            let frame = fa.allocate(1).expect("no frames for the reverse map");
+            self.map_single(fa, frame, REVERSE_MAP_ADDRESS.uoffset(i << PAGE_SHIFT), ::mem::KERNEL_DATA)
+                .expect("Can't map the reverse map");
+        }
+
+        let ram = unsafe { slice::from_raw_parts_mut(REVERSE_MAP_ADDRESS.0 as *mut u32, entries) };
+        for e in ram.iter_mut() {
+            *e = 0;
+        }
+        self.reverse = ReverseMap::new();
+        self.reverse.ram = ram;
+
+        for l1_index in KERNEL_L1_START..L1TABLE_ENTRIES {
+            let v = ::mem::VirtualAddress(l1_index << MB_SHIFT);
+            if self.descriptors[l1_index].is_section() {
+                let p = self.descriptors[l1_index].get_physical_address();
+                self.reverse.insert_section(p, v);
+            } else if self.descriptors[l1_index].is_l2_table() {
+                let l2 = self.l2_table(l1_index);
+                for l2_index in 0..L2TABLE_ENTRIES {
+                    if l2[l2_index].is_present() {
+                        self.reverse.insert(l2[l2_index].get_physical_address(),
+                                            v.uoffset(l2_index << PAGE_SHIFT));
+                    }
+                }
+            }
+        }
+    }
+
     fn map_single(&mut self,
                   frameallocator: & ::mem::FrameAllocator,
                   p: ::mem::PhysicalAddress,
-                  v: ::mem::VirtualAddress) {
-        self.map_single_descriptor(frameallocator, L2TableDescriptor::new(p), v)
+                  v: ::mem::VirtualAddress,
+                  attrs: ::mem::MemoryAttributes)
+                  -> Result<(), ()> {
+        self.map_single_descriptor(frameallocator, L2TableDescriptor::with_attributes(p, attrs), v)
     }
 
     fn map_section(&mut self, s: L1TableDescriptor,
                               v: ::mem::VirtualAddress) {
         let l1_index = v.0 >> MB_SHIFT;
+        let p = s.get_physical_address();
         self.descriptors[l1_index] = s;
+        self.reverse.insert_section(p, v);
     }
 
+    // fails if there is no frame for a new l2 table, or v is in a section.
     fn map_single_descriptor(&mut self,
                              frameallocator: & ::mem::FrameAllocator,
                              p: L2TableDescriptor,
-                             v: ::mem::VirtualAddress) {
+                             v: ::mem::VirtualAddress)
+                             -> Result<(), ()> {
         let l1_index = v.0 >> MB_SHIFT;
-        let mut new_frame: bool = false;
-        // get physical addresss
-        // temporary map it to here using the active page table
+        if self.descriptors[l1_index].is_section() {
+            return Err(());
+        }
         if !self.descriptors[l1_index].is_present() {
-            let frame = frameallocator.allocate(1).unwrap();
-            self.descriptors[l1_index] = L1TableDescriptor::new(frame);
-            new_frame = true;
+            let frame = match frameallocator.allocate(1) {
+                Some(frame) => frame,
+                None => return Err(()),
+            };
+            PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+            self.map_l2_table(l1_index, frame);
         }
 
-        let l2phy = self.descriptors[l1_index].get_physical_address();
-
-        // SHOULD WE FLUSH CASHES HERE?
-
-        // 0-3 are page table itself
-        // 4 is the tmp map itself
-        // 5 is free!
-        const FREE_INDEX: usize = 5;
-        self.tmp_map[FREE_INDEX] = L2TableDescriptor::new(l2phy);
+        let mut l2_for_phy = self.l2_table(l1_index);
+        let l2_index = (v.0 >> PAGE_SHIFT) & 0xFF;
 
-        // TODO: find the frame for l2, and temporary map it..
-        // and add teh mapping
+        if l2_for_phy[l2_index].is_present() {
+            self.reverse.remove(l2_for_phy[l2_index].get_physical_address(), v);
+        }
+        self.reverse.insert(p.get_physical_address(), v);
+        l2_for_phy[l2_index] = p;
 
         cpu::memory_write_barrier();
         cpu::flush_caches();
-        cpu::invalidate_tlb();
-        cpu::data_synchronization_barrier();
+        // kernel pages are global, so the asid doesn't matter
+        cpu::invalidate_tlb_page(v, 0);
+        // page should be mapped now
+        Ok(())
+    }
 
-        let mapped_address = L1_VIRT_ADDRESS.offset((FREE_INDEX * PAGE_SIZE) as isize);
-        
-        // frame now available here:
-        let mut l2_for_phy = unsafe { if new_frame {  L2Table::from_virt_address_init(mapped_address) } else { L2Table::from_virt_address_no_init(mapped_address) } };
+    // the l2 table of a kernel l1 entry, through the l2 window.
+    fn l2_table(&self, l1_index: usize) -> L2Table {
+        unsafe { L2Table::from_virt_address_no_init(l2_window_address(l1_index)) }
+    }
 
-        let l2_index = (v.0 >> PAGE_SHIFT) & 0xFF;
+    // a new l2 table: map it to the l2 window, clear it and only then hook it to the l1 table.
+    fn map_l2_table(&mut self, l1_index: usize, l2phy: ::mem::PhysicalAddress) {
+        let w = l2_window_address(l1_index);
+        // the l2 tables of the window are always there.
+        let mut window_l2 = self.l2_table(w.0 >> MB_SHIFT);
+        window_l2[(w.0 >> PAGE_SHIFT) & 0xFF] = L2TableDescriptor::new(l2phy);
+        self.reverse.insert(l2phy, w);
 
-        l2_for_phy[l2_index] = p;
+        cpu::memory_write_barrier();
+        cpu::invalidate_tlb_page(w, 0);
+        cpu::data_synchronization_barrier();
 
+        unsafe { L2Table::from_virt_address_init(w) };
         cpu::memory_write_barrier();
         cpu::flush_caches();
-        cpu::invalidate_tlb();
-        // page should be mapped now
+        self.descriptors[l1_index] = L1TableDescriptor::new(l2phy);
     }
 
-    fn p2v(&mut self, p: ::mem::PhysicalAddress) -> Option<::mem::VirtualAddress> {
-        let l1table = self.descriptors.descriptors.iter();
-        for (index, l1desc) in l1table.enumerate() {
-            if !l1desc.is_present() {
-                continue;
-            }
-
-            if l1desc.is_section() {
-                let phy_mb = l1desc.get_physical_address();
-                if mb_down(p.0) == phy_mb.0 {
-                    return Some(::mem::VirtualAddress((index << MB_SHIFT) + (p.0 & MB_MASK)));
-                }
-                continue;
-            }
-
-            // not a section..
+    fn unmap_l2_table(&mut self, l1_index: usize, l2phy: ::mem::PhysicalAddress) {
+        self.descriptors[l1_index] = L1TableDescriptor(0);
 
-            let l2phy = l1desc.get_physical_address();
+        let w = l2_window_address(l1_index);
+        let mut window_l2 = self.l2_table(w.0 >> MB_SHIFT);
+        window_l2[(w.0 >> PAGE_SHIFT) & 0xFF] = L2TableDescriptor(0);
+        self.reverse.remove(l2phy, w);
 
-            // TODO!
-            const FREE_INDEX: usize = 5;
-            self.tmp_map[FREE_INDEX] = L2TableDescriptor::new(l2phy);
+        cpu::memory_write_barrier();
+        cpu::invalidate_tlb_page(w, 0);
+    }
 
-            cpu::memory_write_barrier();
-            // wait for the data to arrive to physical memory
-            cpu::data_synchronization_barrier();
-            cpu::flush_caches();
-            cpu::invalidate_tlb();
-
-            let mapped_address = L1_VIRT_ADDRESS.uoffset(FREE_INDEX * PAGE_SIZE);
-            let l2_for_phy = unsafe { L2Table::from_virt_address_no_init(mapped_address) };
-            for j in 0..l2_for_phy.descriptors.len() {
-                if l2_for_phy[j].is_present() {
-                    let phy = l2_for_phy.descriptors[j].get_physical_address();
-                    if down(p.0) == phy.0 {
-                        return Some(::mem::VirtualAddress((index << MB_SHIFT) + (j << PAGE_SHIFT) +
-                                                          (p.0 & PAGE_MASK)));
-                    }
-                }
-            }
-        }
-        None
+    fn p2v(&self, p: ::mem::PhysicalAddress) -> Option<::mem::VirtualAddress> {
+        self.reverse.lookup(p)
     }
 
-    fn v2p(&mut self, v: ::mem::VirtualAddress) -> Option<::mem::PhysicalAddress> {
+    fn v2p(&self, v: ::mem::VirtualAddress) -> Option<::mem::PhysicalAddress> {
+        if v < USER_SPACE_END {
+            return None;
+        }
         let l1_index = v.0 >> MB_SHIFT;
         let l1descriptor = &self.descriptors[l1_index];
         if !l1descriptor.is_present() {
@@ -643,18 +926,7 @@ impl PageTableInner {
         }
 
 
-        // map the l2 table
-        const FREE_INDEX: usize = 5;
-        self.tmp_map[FREE_INDEX] = L2TableDescriptor::new(l1descriptor.get_physical_address());
-
-        cpu::memory_write_barrier();
-        cpu::flush_caches();
-        cpu::invalidate_tlb();
-        cpu::data_synchronization_barrier();
-
-        let mapped_address = L1_VIRT_ADDRESS.uoffset(FREE_INDEX << PAGE_SHIFT);
-        // frame now available here:
-        let l2_for_phy = unsafe { L2Table::from_virt_address_no_init(mapped_address) };
+        let l2_for_phy = self.l2_table(l1_index);
 
         let l2_index = (v.0 >> PAGE_SHIFT) & 0xFF;
         let l2descriptor = &l2_for_phy.descriptors[l2_index];
@@ -666,76 +938,236 @@ impl PageTableInner {
 
         Some(p)
     }
-}
 
-impl ::mem::MemoryMapper for PageTable {
+    fn unmap_single(&mut self,
+                    frameallocator: & ::mem::FrameAllocator,
+                    v: ::mem::VirtualAddress)
+                    -> Result<(), ()> {
+        let l1_index = v.0 >> MB_SHIFT;
+        if !self.descriptors[l1_index].is_l2_table() {
+            // either not mapped, or part of a section.
+            return Err(());
+        }
+
+        let l2phy = self.descriptors[l1_index].get_physical_address();
+        let l2_index = (v.0 >> PAGE_SHIFT) & 0xFF;
+
+        let empty = {
+            let mut l2_for_phy = self.l2_table(l1_index);
+            if !l2_for_phy[l2_index].is_present() {
+                return Err(());
+            }
+            self.reverse.remove(l2_for_phy[l2_index].get_physical_address(), v);
+            l2_for_phy[l2_index] = L2TableDescriptor(0);
+            l2_for_phy.is_empty()
+        };
+
+        cpu::memory_write_barrier();
+        cpu::invalidate_tlb_page(v, 0);
+
+        // no one uses this l2 table anymore; give it back. the l2 tables of the window stay.
+        if empty && !is_l2_window(l1_index) {
+            self.unmap_l2_table(l1_index, l2phy);
+            cpu::data_synchronization_barrier();
+            frameallocator.deallocate(l2phy, 1);
+            PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        cpu::data_synchronization_barrier();
+        Ok(())
+    }
+
+    fn unmap_section(&mut self, v: ::mem::VirtualAddress) -> Result<(), ()> {
+        let l1_index = v.0 >> MB_SHIFT;
+        if !self.descriptors[l1_index].is_section() {
+            return Err(());
+        }
+
+        let p = self.descriptors[l1_index].get_physical_address();
+        self.reverse.remove_section(p, ::mem::VirtualAddress(v.0 & !MB_MASK));
+        self.descriptors[l1_index] = L1TableDescriptor(0);
+
+        cpu::memory_write_barrier();
+        // a section can be cached as a single tlb entry, so one invalidate is enough.
+        cpu::invalidate_tlb_page(v, 0);
+        Ok(())
+    }
+
+    fn is_section(&self, v: ::mem::VirtualAddress) -> bool {
+        self.descriptors[v.0 >> MB_SHIFT].is_section()
+    }
+
+    // unmap [v, end), that check_unmap said is mapped.
+    fn unmap_range(&mut self,
+                   frameallocator: & ::mem::FrameAllocator,
+                   v: ::mem::VirtualAddress,
+                   end: ::mem::VirtualAddress)
+                   -> Result<(), ()> {
+        let mut cur = v;
+        while cur < end {
+            if self.is_section(cur) {
+                try!(self.unmap_section(cur));
+                cur = cur.uoffset(MB_SIZE);
+            } else {
+                try!(self.unmap_single(frameallocator, cur));
+                cur = cur.uoffset(PAGE_SIZE);
+            }
+        }
+        Ok(())
+    }
+
+    // Ok if every page of [v, end) is mapped, and sections are covered whole.
+    fn check_unmap(&self, v: ::mem::VirtualAddress, end: ::mem::VirtualAddress) -> Result<(), ()> {
+        let mut cur = v;
+        while cur < end {
+            let l1_index = cur.0 >> MB_SHIFT;
+            if self.descriptors[l1_index].is_section() {
+                if ((cur.0 & MB_MASK) != 0) || (end.0 - cur.0 < MB_SIZE) {
+                    return Err(());
+                }
+                cur = cur.uoffset(MB_SIZE);
+            } else {
+                if !self.descriptors[l1_index].is_l2_table() {
+                    return Err(());
+                }
+                if !self.l2_table(l1_index)[(cur.0 >> PAGE_SHIFT) & 0xFF].is_present() {
+                    return Err(());
+                }
+                cur = cur.uoffset(PAGE_SIZE);
+            }
+        }
+        Ok(())
+    }
+}
+
+// [v, v + bytes) is in the kernel half, and doesn't wrap around the end of the address space.
+fn is_kernel_range(v: ::mem::VirtualAddress, bytes: usize) -> bool {
+    (v >= USER_SPACE_END) && (bytes <= usize::max_value() - v.0)
+}
+
+impl ::mem::MemoryMapper for PageTable {
     fn map(&self,
            fa: &FrameAllocator,
            p: ::mem::PhysicalAddress,
            v: ::mem::VirtualAddress,
-           size: MemorySize)
+           size: MemorySize,
+           attrs: ::mem::MemoryAttributes)
            -> Result<(), ()> {
-        let pages = ::mem::to_pages(size).ok().unwrap();
+        let pages = try!(::mem::to_pages(size));
+        if ((v.0 & PAGE_MASK) != 0) || !is_kernel_range(v, pages << PAGE_SHIFT) {
+            return Err(());
+        }
+
+        let mut inner = self.cpu_mutex.lock();
         for i in 0..pages {
-            self.cpu_mutex.lock().map_single(fa, p.uoffset(i << PAGE_SHIFT), v.uoffset(i << PAGE_SHIFT));
+            let r = inner.map_single(fa, p.uoffset(i << PAGE_SHIFT), v.uoffset(i << PAGE_SHIFT), attrs);
+            if r.is_err() {
+                // all or nothing
+                inner.unmap_range(fa, v, v.uoffset(i << PAGE_SHIFT)).expect("Can't undo a partial map");
+                return r;
+            }
         }
 
         Ok(())
     }
 
-    // TODO add 1mb section; to help speed up things up!
+    // uses 1mb sections where both p and v are section aligned, and pages for the rest.
     fn map_device(&self,
                   frameallocator: &::mem::FrameAllocator,
                   p: ::mem::PhysicalAddress,
                   v: ::mem::VirtualAddress,
                   size: MemorySize)
                   -> Result<(), ()> {
-        let pages = ::mem::to_pages(size).ok().unwrap();
-        let bytes = ::mem::to_bytes(size);
-        let end_physical = p.uoffset(pages << PAGE_SHIFT);
-
-        let bytes_before_mb = mb_up(p.0) - p.0;
-        let bytes_after_mb = end_physical.0 - mb_down(end_physical.0);
-
-        let pages_before_mb = bytes_before_mb >> PAGE_SHIFT;
-        let mega_bytes = bytes >> MB_SHIFT;
-        let pages_after_mb = bytes_after_mb >> PAGE_SHIFT;
-
-        let mut curr_offset: usize = 0;
-
-        for _ in 0..pages_before_mb {
-            self.cpu_mutex.lock().map_single_descriptor(frameallocator,
-                                       L2TableDescriptor::new_device(p.uoffset(curr_offset)),
-                                       v.uoffset(curr_offset));
-            curr_offset += PAGE_SIZE;
-        }
-
-        for _ in 0..mega_bytes {
-            
-            self.cpu_mutex.lock().map_section(L1TableDescriptor::new_section(
-                                                p.uoffset(curr_offset),
-                                                false), v.uoffset(curr_offset));
-            curr_offset += MB_SIZE;
+        let bytes = try!(::mem::to_pages(size)) << PAGE_SHIFT;
+        if ((v.0 & PAGE_MASK) != 0) || !is_kernel_range(v, bytes) {
+            return Err(());
         }
 
-        for _ in 0..pages_after_mb {
-            self.cpu_mutex.lock().map_single_descriptor(frameallocator,
-                                       L2TableDescriptor::new_device(p.uoffset(curr_offset)),
-                                       v.uoffset(curr_offset));
-            curr_offset += PAGE_SIZE;
+        let mut inner = self.cpu_mutex.lock();
+        let mut curr_offset: usize = 0;
+        while curr_offset < bytes {
+            let (cur_p, cur_v) = (p.uoffset(curr_offset), v.uoffset(curr_offset));
+            if (((cur_p.0 | cur_v.0) & MB_MASK) == 0) && (bytes - curr_offset >= MB_SIZE) &&
+               !inner.descriptors[cur_v.0 >> MB_SHIFT].is_l2_table() {
+                inner.map_section(L1TableDescriptor::new_section(cur_p, ::mem::DEVICE_MEMORY), cur_v);
+                curr_offset += MB_SIZE;
+            } else {
+                let r = inner.map_single_descriptor(frameallocator,
+                                                    L2TableDescriptor::with_attributes(cur_p, ::mem::DEVICE_MEMORY),
+                                                    cur_v);
+                if r.is_err() {
+                    inner.unmap_range(frameallocator, v, cur_v).expect("Can't undo a partial map");
+                    return r;
+                }
+                curr_offset += PAGE_SIZE;
+            }
         }
 
         Ok(())
     }
 
+    // unmaps pages and sections (as created by map_device). the frames that were mapped are not
+    // freed, as we don't know who owns them. l2 tables that become empty are freed.
     fn unmap(&self,
-             _: &FrameAllocator,
-             _: ::mem::VirtualAddress,
-             _: MemorySize)
+             frameallocator: &FrameAllocator,
+             v: ::mem::VirtualAddress,
+             size: MemorySize)
              -> Result<(), ()> {
-        unimplemented!();
+        let bytes = ::mem::to_bytes(size);
+        if ((v.0 & PAGE_MASK) != 0) || ((bytes & PAGE_MASK) != 0) || !is_kernel_range(v, bytes) {
+            return Err(());
+        }
+
+        let end = v.uoffset(bytes);
+        let mut inner = self.cpu_mutex.lock();
+        // all or nothing: check the whole range before removing anything, so a failure doesn't
+        // leave some of it unmapped (and in other cpus' tlbs).
+        try!(inner.check_unmap(v, end));
+        inner.unmap_range(frameallocator, v, end)
+    }
+
+    fn for_each_mapping(&self,
+                        f: &mut FnMut(::mem::VirtualAddress, ::mem::PhysicalAddress, usize, ::mem::MemoryAttributes)) {
+        for l1_index in KERNEL_L1_START..L1TABLE_ENTRIES {
+            // copy the entries out, so f runs without the lock.
+            let mut entries = [0u32; L2TABLE_ENTRIES];
+            let section = {
+                let inner = self.cpu_mutex.lock();
+                let d = &inner.descriptors[l1_index];
+                if d.is_section() {
+                    Some((d.get_physical_address(), d.section_attributes()))
+                } else {
+                    if d.is_l2_table() {
+                        let l2 = inner.l2_table(l1_index);
+                        for j in 0..L2TABLE_ENTRIES {
+                            entries[j] = l2[j].0;
+                        }
+                    }
+                    None
+                }
+            };
+            report_entries(::mem::VirtualAddress(l1_index << MB_SHIFT), section, &entries, f);
+        }
+    }
+}
+
+// the mappings of one l1 entry: either a section, or the entries of its l2 table.
+fn report_entries(v: ::mem::VirtualAddress,
+                  section: Option<(::mem::PhysicalAddress, ::mem::MemoryAttributes)>,
+                  entries: &[u32; L2TABLE_ENTRIES],
+                  f: &mut FnMut(::mem::VirtualAddress, ::mem::PhysicalAddress, usize, ::mem::MemoryAttributes)) {
+    if let Some((p, attrs)) = section {
+        f(v, p, MB_SIZE, attrs);
+        return;
+    }
+    for (j, raw) in entries.iter().enumerate() {
+        let d = L2TableDescriptor(*raw);
+        if d.is_present() {
+            f(v.uoffset(j << PAGE_SHIFT), d.get_physical_address(), PAGE_SIZE, d.attributes());
+        }
     }
 }
+
 impl ::mem::PVMapper for PageTable {
     fn p2v(&self, p: ::mem::PhysicalAddress) -> Option<::mem::VirtualAddress> {
         self.cpu_mutex.lock().p2v(p)
@@ -748,9 +1180,7 @@ impl ::mem::PVMapper for PageTable {
 
 impl L1Table {
     unsafe fn from_virt_address_no_init(v: ::mem::VirtualAddress) -> L1Table {
-        let l1slice: &'static mut [L1TableDescriptor] =
-            slice::from_raw_parts_mut(v.0 as *mut L1TableDescriptor, L1TABLE_ENTRIES);
-        L1Table { descriptors: l1slice }
+        Self::from_virt_address_entries(v, L1TABLE_ENTRIES)
     }
     unsafe fn from_virt_address_init(v: ::mem::VirtualAddress) -> L1Table {
         let l1 = Self::from_virt_address_no_init(v);
@@ -759,6 +1189,13 @@ impl L1Table {
         }
         l1
     }
+
+    // a table with less than the full 4096 entries; i.e. the lower part of a split table.
+    unsafe fn from_virt_address_entries(v: ::mem::VirtualAddress, entries: usize) -> L1Table {
+        let l1slice: &'static mut [L1TableDescriptor] =
+            slice::from_raw_parts_mut(v.0 as *mut L1TableDescriptor, entries);
+        L1Table { descriptors: l1slice }
+    }
         
 }
 
@@ -778,3 +1215,578 @@ impl L2Table {
         l2
     }
 }
+
+
+// the page tables of the user half of an address space (translated by ttb0).
+// the l1 table is mapped to a kernel window (a region from the memory manager) so we can edit it; l2 tables are mapped to the page
+// right after it when we need to edit them.
+const WINDOW_PAGES: usize = USER_L1_FRAMES + 1;
+const BITS_PER_WORD: usize = 32;
+
+// asids. on armv7 the tlb entries of user pages are tagged with the asid of their address
+// space, so switching address spaces doesn't need a tlb flush. there are only 255 asids (0 is
+// reserved for switching), so they are handed out when an address space is switched to, and
+// tagged with a generation. when we run out we start a new generation: the tlb is flushed
+// on all cpus, and every address space gets a new asid the next time it runs.
+const ASID_BITS: usize = 8;
+const NUM_ASIDS: usize = 1 << ASID_BITS;
+const ASID_MASK: usize = NUM_ASIDS - 1;
+// the size of the per cpu arrays; cortex-a clusters have at most 4 cores.
+#[cfg(feature = "armv7")]
+const MAX_CPUS: usize = 4;
+
+#[cfg(feature = "armv7")]
+struct AsidAllocator {
+    // kept above ASID_BITS, so generation | asid is a versioned asid. a versioned asid of 0
+    // means none was assigned yet.
+    generation: usize,
+    // asids in use in this generation
+    used: [u32; NUM_ASIDS / BITS_PER_WORD],
+    // the versioned asid each cpu is running with, 0 for none
+    active: [usize; MAX_CPUS],
+    // the asids the cpus were running with on the last rollover. the cpus keep filling the tlb
+    // with them, so they are carried to the new generation as is.
+    reserved: [usize; MAX_CPUS],
+}
+
+#[cfg(feature = "armv7")]
+static ASIDS: sync::CpuMutex<AsidAllocator> = sync::CpuMutex::new(AsidAllocator {
+    generation: NUM_ASIDS,
+    used: [0; NUM_ASIDS / BITS_PER_WORD],
+    active: [0; MAX_CPUS],
+    reserved: [0; MAX_CPUS],
+});
+
+#[cfg(feature = "armv7")]
+impl AsidAllocator {
+    fn is_used(&self, asid: usize) -> bool {
+        (self.used[asid / BITS_PER_WORD] & (1 << (asid % BITS_PER_WORD))) != 0
+    }
+
+    fn set_used(&mut self, asid: usize) {
+        self.used[asid / BITS_PER_WORD] |= 1 << (asid % BITS_PER_WORD);
+    }
+
+    fn find_free(&self) -> Option<usize> {
+        (1..NUM_ASIDS).find(|asid| !self.is_used(*asid))
+    }
+
+    // returns the versioned asid cpu should run the address space that had versioned asid old.
+    fn activate(&mut self, old: usize, cpu: usize) -> usize {
+        let new = if (old != 0) && ((old & !ASID_MASK) == self.generation) {
+            old
+        } else {
+            self.new_asid(old)
+        };
+        self.active[cpu] = new;
+        new
+    }
+
+    fn new_asid(&mut self, old: usize) -> usize {
+        if old != 0 {
+            // keep the same asid if we can; tlb entries of it may be still around.
+            let asid = old & ASID_MASK;
+            let new = self.generation | asid;
+            let mut reserved = false;
+            for r in self.reserved.iter_mut() {
+                if *r == old {
+                    *r = new;
+                    reserved = true;
+                }
+            }
+            if reserved {
+                return new;
+            }
+            if !self.is_used(asid) {
+                self.set_used(asid);
+                return new;
+            }
+        }
+
+        let asid = match self.find_free() {
+            Some(asid) => asid,
+            None => {
+                self.rollover();
+                self.find_free().expect("no free asid after rollover")
+            }
+        };
+        self.set_used(asid);
+        self.generation | asid
+    }
+
+    fn rollover(&mut self) {
+        self.generation += NUM_ASIDS;
+        self.used = [0; NUM_ASIDS / BITS_PER_WORD];
+        for cpu in 0..MAX_CPUS {
+            let active = self.active[cpu];
+            self.reserved[cpu] = active;
+            if active != 0 {
+                self.set_used(active & ASID_MASK);
+            }
+        }
+        cpu::invalidate_tlb_all_cpus();
+    }
+}
+
+// get an asid for the address space that runs on the current cpu.
+#[cfg(feature = "armv7")]
+fn activate_asid(asid: &AtomicUsize) -> u32 {
+    let mut asids = ASIDS.lock();
+    let new = asids.activate(asid.load(Ordering::Relaxed), cpu::get_current_cpu_id());
+    asid.store(new, Ordering::Relaxed);
+    (new & ASID_MASK) as u32
+}
+
+#[cfg(feature = "armv7")]
+fn deactivate_asid() {
+    ASIDS.lock().active[cpu::get_current_cpu_id()] = 0;
+}
+
+// no asids in use on armv6; we flush the tlb on every address space switch.
+#[cfg(not(feature = "armv7"))]
+fn activate_asid(_: &AtomicUsize) -> u32 {
+    0
+}
+
+#[cfg(not(feature = "armv7"))]
+fn deactivate_asid() {}
+
+pub struct UserPageTable {
+    cpu_mutex: sync::CpuMutex<UserPageTableInner>,
+    l1_phy: ::mem::PhysicalAddress,
+    // versioned asid, see AsidAllocator
+    asid: AtomicUsize,
+    window: ::mem::VirtualAddress,
+}
+
+struct UserPageTableInner {
+    descriptors: L1Table,
+    // where we map l2 tables to edit them
+    l2_window: ::mem::VirtualAddress,
+}
+
+impl UserPageTable {
+    pub fn new(fa: &FrameAllocator) -> Result<UserPageTable, ()> {
+        let mem_manager = &::platform::get_memory_services().mem_manager;
+        let window = match mem_manager.alloc_region(MemorySize::PageSizes(WINDOW_PAGES), PAGE_SIZE) {
+            Some(v) => v,
+            None => return Err(()),
+        };
+        let l1_phy = match fa.allocate_aligned(USER_L1_FRAMES, USER_L1_FRAMES) {
+            Some(p) => p,
+            None => {
+                mem_manager.free_region(window, MemorySize::PageSizes(WINDOW_PAGES));
+                return Err(());
+            }
+        };
+
+        let r = mem_manager.map(l1_phy, window, MemorySize::PageSizes(USER_L1_FRAMES), ::mem::KERNEL_DATA);
+        if r.is_err() {
+            fa.deallocate(l1_phy, USER_L1_FRAMES);
+            mem_manager.free_region(window, MemorySize::PageSizes(WINDOW_PAGES));
+            return Err(());
+        }
+        // map the l2 window once, so it has a kernel l2 entry (and table) of its own; map_l2
+        // repoints it from then on. it starts out at the l1 table, that is already mapped, so
+        // the reverse map keeps the first mapping and never learns about the window.
+        let l2_window = window.uoffset(USER_L1_FRAMES << PAGE_SHIFT);
+        let r = mem_manager.map(l1_phy, l2_window, MemorySize::PageSizes(1), ::mem::KERNEL_DATA);
+        if r.is_err() {
+            mem_manager.unmap(window, MemorySize::PageSizes(USER_L1_FRAMES)).expect("Can't unmap l1 table");
+            fa.deallocate(l1_phy, USER_L1_FRAMES);
+            mem_manager.free_region(window, MemorySize::PageSizes(WINDOW_PAGES));
+            return Err(());
+        }
+
+        let descriptors = unsafe {
+            let l1 = L1Table::from_virt_address_entries(window, USER_L1TABLE_ENTRIES);
+            for elem in l1.descriptors.iter_mut() {
+                *elem = L1TableDescriptor(0);
+            }
+            l1
+        };
+        cpu::memory_write_barrier();
+        cpu::flush_caches();
+        PAGE_TABLE_FRAMES.fetch_add(USER_L1_FRAMES, Ordering::Relaxed);
+
+        Ok(UserPageTable {
+            cpu_mutex: sync::CpuMutex::new(UserPageTableInner {
+                descriptors: descriptors,
+                l2_window: l2_window,
+            }),
+            l1_phy: l1_phy,
+            asid: AtomicUsize::new(0),
+            window: window,
+        })
+    }
+
+    // how v is mapped; None if it isn't.
+    pub fn attributes(&self, v: ::mem::VirtualAddress) -> Option<::mem::MemoryAttributes> {
+        if v >= USER_SPACE_END {
+            return None;
+        }
+        self.cpu_mutex.lock().attributes(v)
+    }
+
+    // change how the page at v is mapped, in place. the caller tells the other cpus.
+    pub fn protect(&self, v: ::mem::VirtualAddress, attrs: ::mem::MemoryAttributes) -> Result<(), ()> {
+        if ((v.0 & PAGE_MASK) != 0) || (v >= USER_SPACE_END) {
+            return Err(());
+        }
+        self.cpu_mutex.lock().protect(v, attrs, &self.asid)
+    }
+}
+
+fn is_user_range(v: ::mem::VirtualAddress, bytes: usize) -> bool {
+    (bytes <= USER_SPACE_END.0) && (v.0 <= USER_SPACE_END.0 - bytes)
+}
+
+impl UserPageTableInner {
+    // point the l2 window at l2phy. the window's kernel l2 entry is only changed here, under our
+    // lock, and the kernel l2 table it is in stays as long as the entry is there; so it is
+    // written in place, without the kernel page table lock (or its allocations). other cpus may
+    // still have an older table of ours in their tlb, but they come through here, and
+    // invalidate it, before they read through the window.
+    fn map_l2(&mut self, l2phy: ::mem::PhysicalAddress, init: bool) -> L2Table {
+        self.point_l2_window(l2phy);
+        unsafe {
+            if init {
+                L2Table::from_virt_address_init(self.l2_window)
+            } else {
+                L2Table::from_virt_address_no_init(self.l2_window)
+            }
+        }
+    }
+
+    fn point_l2_window(&mut self, p: ::mem::PhysicalAddress) {
+        let w = self.l2_window;
+        // the kernel l2 table the window is in, through the kernel's own l2 window
+        let mut kernel_l2 = unsafe { L2Table::from_virt_address_no_init(l2_window_address(w.0 >> MB_SHIFT)) };
+        kernel_l2[(w.0 >> PAGE_SHIFT) & 0xFF] = L2TableDescriptor::new(p);
+
+        cpu::memory_write_barrier();
+        cpu::invalidate_tlb_page(w, 0);
+        cpu::data_synchronization_barrier();
+    }
+
+    // fails if there is no frame for a new l2 table, or v is already mapped: remapping would
+    // need a tlb invalidate here, and an all or nothing map couldn't put the old page back.
+    fn map_single(&mut self,
+                  fa: &FrameAllocator,
+                  p: ::mem::PhysicalAddress,
+                  v: ::mem::VirtualAddress,
+                  attrs: ::mem::MemoryAttributes)
+                  -> Result<(), ()> {
+        let l1_index = v.0 >> MB_SHIFT;
+        let mut l2 = if self.descriptors[l1_index].is_present() {
+            let l2phy = self.descriptors[l1_index].get_physical_address();
+            self.map_l2(l2phy, false)
+        } else {
+            let frame = match fa.allocate(1) {
+                Some(frame) => frame,
+                None => return Err(()),
+            };
+            PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+            // like the kernel's map_l2_table: clear it before the l1 table points at it, so
+            // no table walk ever sees garbage in it.
+            let l2 = self.map_l2(frame, true);
+            cpu::memory_write_barrier();
+            cpu::flush_caches();
+            self.descriptors[l1_index] = L1TableDescriptor::new(frame);
+            l2
+        };
+
+        let l2_index = (v.0 >> PAGE_SHIFT) & 0xFF;
+        if l2[l2_index].is_present() {
+            return Err(());
+        }
+        let mut d = L2TableDescriptor::with_attributes(p, attrs);
+        d.0 |= L2_NG;
+        l2[l2_index] = d;
+
+        // make sure the table walk sees it
+        cpu::memory_write_barrier();
+        cpu::flush_caches();
+        Ok(())
+    }
+
+    fn unmap_single(&mut self,
+                    fa: &FrameAllocator,
+                    v: ::mem::VirtualAddress,
+                    asid: &AtomicUsize)
+                    -> Result<(), ()> {
+        let l1_index = v.0 >> MB_SHIFT;
+        if !self.descriptors[l1_index].is_l2_table() {
+            return Err(());
+        }
+        let l2phy = self.descriptors[l1_index].get_physical_address();
+
+        let empty = {
+            let mut l2 = self.map_l2(l2phy, false);
+            let l2_index = (v.0 >> PAGE_SHIFT) & 0xFF;
+            if !l2[l2_index].is_present() {
+                return Err(());
+            }
+            l2[l2_index] = L2TableDescriptor(0);
+            l2.is_empty()
+        };
+
+        cpu::memory_write_barrier();
+        // read the asid only after the descriptor is gone; if it changes after that, the
+        // new one never saw the old mapping.
+        cpu::invalidate_tlb_page(v, (asid.load(Ordering::Relaxed) & ASID_MASK) as u32);
+
+        if empty {
+            self.descriptors[l1_index] = L1TableDescriptor(0);
+            cpu::memory_write_barrier();
+            // the walk of any address in this mb may be cached with the l1 entry; drop it
+            // before the frame can be handed out again.
+            cpu::invalidate_tlb_page(v, (asid.load(Ordering::Relaxed) & ASID_MASK) as u32);
+            cpu::data_synchronization_barrier();
+            fa.deallocate(l2phy, 1);
+            PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+        }
+        cpu::data_synchronization_barrier();
+        Ok(())
+    }
+
+    fn protect(&mut self,
+               v: ::mem::VirtualAddress,
+               attrs: ::mem::MemoryAttributes,
+               asid: &AtomicUsize)
+               -> Result<(), ()> {
+        let l1_index = v.0 >> MB_SHIFT;
+        if !self.descriptors[l1_index].is_l2_table() {
+            return Err(());
+        }
+        let l2phy = self.descriptors[l1_index].get_physical_address();
+        {
+            let mut l2 = self.map_l2(l2phy, false);
+            let l2_index = (v.0 >> PAGE_SHIFT) & 0xFF;
+            if !l2[l2_index].is_present() {
+                return Err(());
+            }
+            let mut d = L2TableDescriptor::with_attributes(l2[l2_index].get_physical_address(), attrs);
+            d.0 |= L2_NG;
+            l2[l2_index] = d;
+        }
+
+        cpu::memory_write_barrier();
+        cpu::flush_caches();
+        cpu::invalidate_tlb_page(v, (asid.load(Ordering::Relaxed) & ASID_MASK) as u32);
+        cpu::data_synchronization_barrier();
+        Ok(())
+    }
+
+    fn v2p(&mut self, v: ::mem::VirtualAddress) -> Option<::mem::PhysicalAddress> {
+        let l1_index = v.0 >> MB_SHIFT;
+        if !self.descriptors[l1_index].is_l2_table() {
+            return None;
+        }
+        let l2phy = self.descriptors[l1_index].get_physical_address();
+        let l2 = self.map_l2(l2phy, false);
+        let l2descriptor = &l2[(v.0 >> PAGE_SHIFT) & 0xFF];
+        if !l2descriptor.is_present() {
+            return None;
+        }
+        Some(::mem::PhysicalAddress(l2descriptor.get_physical_address().0 | (v.0 & PAGE_MASK)))
+    }
+
+    fn attributes(&mut self, v: ::mem::VirtualAddress) -> Option<::mem::MemoryAttributes> {
+        let l1_index = v.0 >> MB_SHIFT;
+        if !self.descriptors[l1_index].is_l2_table() {
+            return None;
+        }
+        let l2phy = self.descriptors[l1_index].get_physical_address();
+        let l2 = self.map_l2(l2phy, false);
+        let l2descriptor = &l2[(v.0 >> PAGE_SHIFT) & 0xFF];
+        if !l2descriptor.is_present() {
+            return None;
+        }
+        Some(l2descriptor.attributes())
+    }
+
+    fn p2v(&mut self, p: ::mem::PhysicalAddress) -> Option<::mem::VirtualAddress> {
+        for index in 0..USER_L1TABLE_ENTRIES {
+            if !self.descriptors[index].is_l2_table() {
+                continue;
+            }
+            let l2phy = self.descriptors[index].get_physical_address();
+            let l2 = self.map_l2(l2phy, false);
+            for j in 0..L2TABLE_ENTRIES {
+                if l2[j].is_present() && (l2[j].get_physical_address().0 == down(p.0)) {
+                    return Some(::mem::VirtualAddress((index << MB_SHIFT) + (j << PAGE_SHIFT) +
+                                                      (p.0 & PAGE_MASK)));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl ::mem::MemoryMapper for UserPageTable {
+    fn map(&self,
+           fa: &FrameAllocator,
+           p: ::mem::PhysicalAddress,
+           v: ::mem::VirtualAddress,
+           size: MemorySize,
+           attrs: ::mem::MemoryAttributes)
+           -> Result<(), ()> {
+        let pages = try!(::mem::to_pages(size));
+        if ((v.0 & PAGE_MASK) != 0) || !is_user_range(v, pages << PAGE_SHIFT) {
+            return Err(());
+        }
+        let mut inner = self.cpu_mutex.lock();
+        for i in 0..pages {
+            let r = inner.map_single(fa, p.uoffset(i << PAGE_SHIFT), v.uoffset(i << PAGE_SHIFT), attrs);
+            if r.is_err() {
+                // all or nothing; the pages before this one were not mapped before.
+                for j in 0..i {
+                    inner.unmap_single(fa, v.uoffset(j << PAGE_SHIFT), &self.asid)
+                        .expect("Can't undo a partial map");
+                }
+                return r;
+            }
+        }
+        Ok(())
+    }
+
+    // like the kernel's, the mapping is for the kernel only; a device that user space should
+    // reach is mapped with map, and DEVICE_MEMORY | USER.
+    fn map_device(&self,
+                  fa: &FrameAllocator,
+                  p: ::mem::PhysicalAddress,
+                  v: ::mem::VirtualAddress,
+                  size: MemorySize)
+                  -> Result<(), ()> {
+        self.map(fa, p, v, size, ::mem::DEVICE_MEMORY)
+    }
+
+    // the frames that were mapped are not freed; l2 tables that become empty are.
+    fn unmap(&self,
+             fa: &FrameAllocator,
+             v: ::mem::VirtualAddress,
+             size: MemorySize)
+             -> Result<(), ()> {
+        let pages = try!(::mem::to_pages(size));
+        if ((v.0 & PAGE_MASK) != 0) || !is_user_range(v, pages << PAGE_SHIFT) {
+            return Err(());
+        }
+        let mut inner = self.cpu_mutex.lock();
+        for i in 0..pages {
+            try!(inner.unmap_single(fa, v.uoffset(i << PAGE_SHIFT), &self.asid));
+        }
+        Ok(())
+    }
+    fn for_each_mapping(&self,
+                        f: &mut FnMut(::mem::VirtualAddress, ::mem::PhysicalAddress, usize, ::mem::MemoryAttributes)) {
+        for l1_index in 0..USER_L1TABLE_ENTRIES {
+            let mut entries = [0u32; L2TABLE_ENTRIES];
+            {
+                let mut inner = self.cpu_mutex.lock();
+                if !inner.descriptors[l1_index].is_l2_table() {
+                    continue;
+                }
+                let l2phy = inner.descriptors[l1_index].get_physical_address();
+                let l2 = inner.map_l2(l2phy, false);
+                for j in 0..L2TABLE_ENTRIES {
+                    entries[j] = l2[j].0;
+                }
+            }
+            report_entries(::mem::VirtualAddress(l1_index << MB_SHIFT), None, &entries, f);
+        }
+    }
+}
+
+impl ::mem::PVMapper for UserPageTable {
+    fn p2v(&self, p: ::mem::PhysicalAddress) -> Option<::mem::VirtualAddress> {
+        self.cpu_mutex.lock().p2v(p)
+    }
+
+    fn v2p(&self, v: ::mem::VirtualAddress) -> Option<::mem::PhysicalAddress> {
+        self.cpu_mutex.lock().v2p(v)
+    }
+}
+
+impl Drop for UserPageTable {
+    fn drop(&mut self) {
+        let memory_services = ::platform::get_memory_services();
+        {
+            let mut inner = self.cpu_mutex.lock();
+            for index in 0..USER_L1TABLE_ENTRIES {
+                if inner.descriptors[index].is_l2_table() {
+                    memory_services.frame_alloc.deallocate(inner.descriptors[index].get_physical_address(), 1);
+                    PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+            // back to where it started, so unmapping it leaves the reverse map as it was
+            inner.point_l2_window(self.l1_phy);
+        }
+
+        let window = self.window;
+        memory_services.mem_manager.unmap(window, MemorySize::PageSizes(WINDOW_PAGES)).expect("Can't unmap l1 table");
+        memory_services.frame_alloc.deallocate(self.l1_phy, USER_L1_FRAMES);
+        PAGE_TABLE_FRAMES.fetch_sub(USER_L1_FRAMES, Ordering::Relaxed);
+
+        // our asid is not handed out again before the next rollover, that flushes the tlb, so
+        // stale entries of it are harmless.
+        memory_services.mem_manager.free_region(window, MemorySize::PageSizes(WINDOW_PAGES));
+    }
+}
+
+// make pt the user half of the current cpu. None means no user space (kernel only threads).
+pub fn switch_user_page_table(pt: Option<&UserPageTable>) {
+    let (ttb0, asid) = match pt {
+        Some(pt) => (pt.l1_phy, activate_asid(&pt.asid)),
+        None => {
+            deactivate_asid();
+            (unsafe { KERNEL_L1_PHY }, 0)
+        }
+    };
+    set_user_translation(ttb0, asid);
+}
+
+#[cfg(feature = "armv7")]
+fn set_user_translation(ttb0: ::mem::PhysicalAddress, asid: u32) {
+    // go through the reserved asid, so walks from the new table are never tagged with the
+    // old asid (and vice versa)
+    cpu::set_context_id(0);
+    cpu::instruction_synchronization_barrier();
+    cpu::set_ttb0(ttb0.0 as *const ());
+    cpu::instruction_synchronization_barrier();
+    cpu::set_context_id(asid);
+    cpu::instruction_synchronization_barrier();
+}
+
+#[cfg(not(feature = "armv7"))]
+fn set_user_translation(ttb0: ::mem::PhysicalAddress, _: u32) {
+    cpu::set_ttb0(ttb0.0 as *const ());
+    cpu::instruction_synchronization_barrier();
+    cpu::invalidate_tlb();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{attributes_to_bits, bits_to_attributes};
+
+    #[test]
+    fn access_round_trip() {
+        let all = [::mem::MemoryAttributes::empty(),
+                   ::mem::WRITE,
+                   ::mem::USER,
+                   ::mem::WRITE | ::mem::USER];
+        for &access in all.iter() {
+            let (ap, apx, tex, cb, xn, shareable) = attributes_to_bits(access);
+            let back = bits_to_attributes(ap, apx, tex, cb, xn, shareable);
+            assert_eq!(back & (::mem::WRITE | ::mem::USER), access);
+        }
+    }
+
+    #[test]
+    fn user_read_only_is_not_reserved() {
+        // apx with ap 0b11 is reserved on armv6
+        let (ap, apx, _, _, _, _) = attributes_to_bits(::mem::USER);
+        assert!(apx);
+        assert_eq!(ap, 0b10);
+    }
+}
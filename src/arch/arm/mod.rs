@@ -8,9 +8,10 @@ pub mod cpu;
 pub mod thread;
 pub mod pic;
 pub mod pl011;
+pub mod bootinfo;
 
 pub use self::board::write_to_console;
-pub use self::board::ticks_in_second;
+pub use self::board::DMA_BUS_OFFSET;
 
 #[cfg(feature = "multicpu")]
 pub use self::board::send_ipi;
@@ -21,10 +22,16 @@ use alloc::rc::Rc;
 use collections::boxed::Box;
 use collections::Vec;
 use device;
+use fdt;
+
+// the board's, unless the command line says otherwise (hz=<ticks>)
+pub fn ticks_in_second() -> usize {
+    ::bootparams::get().ticks_in_second(board::DEFAULT_TICKS_IN_SECOND)
+}
 
 #[cfg(feature = "multicpu")]
 pub fn get_num_cpus() -> usize {
-    board::NUM_CPUS
+    ::bootparams::get().num_cpus(board::NUM_CPUS)
 }
 #[cfg(not(feature = "multicpu"))]
 pub fn get_num_cpus() -> usize {
@@ -44,9 +51,12 @@ fn init_vectors() {
     platform::get_memory_services().mem_manager.map(
              ::mem::PhysicalAddress(0),
              vector::VECTORS_ADDR,
-             ::mem::MemorySize::PageSizes(1))
+             ::mem::MemorySize::PageSizes(1),
+             // the vector table is written by init_interrupts and then executed
+             ::mem::KERNEL_RWX)
         .unwrap();
     vector::init_interrupts();
+    cpu::enable_high_vectors();
     build_mode_stacks();
 }
 
@@ -55,15 +65,27 @@ pub fn arm_main(
     skip_frames : &[ops::Range<::mem::PhysicalAddress>],
     initial_l1 : ::mem::VirtualAddress,
     initial_l2 : ::mem::VirtualAddress,
-    mem_size : usize) -> ! {
+    boot_info : &bootinfo::BootInfo) -> ! {
 
-    
-    let mut frame_allocator =
-        mem::LameFrameAllocator::new(&skip_frames, mem_size);
+    ::bootparams::init(bootinfo::cmdline());
+
+    let mut frame_allocator = mem::new_frame_allocator(&skip_frames, boot_info.mem_end());
+    // the holes between the memory regions, and what the boot loader wants us to leave alone.
+    let mut prev_end = ::mem::PhysicalAddress(0);
+    for r in boot_info.mem_regions() {
+        if r.start > prev_end {
+            frame_allocator.reserve(prev_end..r.start);
+        }
+        prev_end = r.end;
+    }
+    for r in boot_info.reserved() {
+        frame_allocator.reserve(r.start..r.end);
+    }
 
     let page_table = mem::init_page_table(initial_l1,
                                         initial_l2,
                                         &ml,
+                                        boot_info.mem_end(),
                                         &mut frame_allocator);
     // DONE. install_interrupt_handlers();
     // DONE: init_timer
@@ -104,11 +126,29 @@ pub trait InterruptableDriver : Driver+platform::Interruptable {}
 
 pub trait SPIDriver : Driver + device::spi::SPIMaster + platform::Interruptable {}
 
+// what a driver gets from its device tree node: its registers, as cpu physical addresses, and
+// its interrupts, as the cells of the node (what they mean is up to the interrupt controller).
+pub struct DeviceResources {
+    pub reg : Vec<ops::Range<::mem::PhysicalAddress>>,
+    pub interrupts : Vec<u32>,
+}
+
+// creates the driver for a device tree node and adds it to the driver manager.
+pub type ProbeFn = fn(&mut DriverManager, &DeviceResources) -> Result<(), ()>;
+
+struct DriverMatch {
+    compatible : &'static str,
+    probe : ProbeFn,
+    // how many nodes it added drivers for
+    probed : usize,
+}
+
 pub struct DriverManager{
     drivers : Vec<Box<Driver>>,
     interruptable : Vec<Box<InterruptableDriver>>,
     // TODO: unpub
     pub spi : Vec<Box<SPIDriver>>,
+    matches : Vec<DriverMatch>,
 
 }
 
@@ -119,7 +159,59 @@ impl DriverManager {
             drivers : vec![],
             interruptable : vec![],
             spi : vec![],
+            matches : vec![],
+        }
+    }
+
+    // probe_fdt calls probe for the nodes that are compatible with the given string.
+    pub fn register_match(&mut self, compatible : &'static str, probe : ProbeFn) {
+        self.matches.push(DriverMatch {
+            compatible : compatible,
+            probe : probe,
+            probed : 0,
+        });
+    }
+
+    // how many drivers probe_fdt added through the match for compatible; boards fall back to
+    // their fixed addresses for what the tree didn't have.
+    pub fn probed(&self, compatible : &str) -> usize {
+        self.matches.iter().filter(|m| m.compatible == compatible).map(|m| m.probed).sum()
+    }
+
+    // adds the drivers for the enabled nodes of the tree. the compatible list of a node goes from
+    // the most specific to the most generic, so the first one we have a driver for wins.
+    // the drivers are attached with the rest, in attach_all. returns how many were added.
+    pub fn probe_fdt(&mut self, tree : &fdt::Fdt) -> usize {
+        let mut probed = 0;
+        for node in tree.nodes() {
+            if !node.is_enabled() {
+                continue;
+            }
+
+            let index = node.compatible()
+                .filter_map(|c| self.matches.iter().position(|m| m.compatible == c))
+                .next();
+            let index = match index {
+                Some(i) => i,
+                None => continue,
+            };
+            let probe = self.matches[index].probe;
+
+            let resources = DeviceResources {
+                reg : node.reg()
+                    .map(|(a, s)| ::mem::PhysicalAddress(a as usize)..::mem::PhysicalAddress((a + s) as usize))
+                    .collect(),
+                interrupts : node.interrupts().collect(),
+            };
+            match probe(self, &resources) {
+                Ok(()) => {
+                    self.matches[index].probed += 1;
+                    probed += 1;
+                }
+                Err(()) => platform::log(::bootparams::LOG_ERR, "driver probe failed"),
+            }
         }
+        probed
     }
 
     pub fn attach_all(&mut self) {
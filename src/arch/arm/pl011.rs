@@ -129,20 +129,12 @@ pub struct PL011 {
 
 impl PL011 {
     pub unsafe fn new(v: ::mem::VirtualAddress, uart_clock : u64, bps : u64) -> &'static mut Self {
-        let p = &mut *(v.0 as *mut PL011);
+        let p = Self::at(v);
 
         // disable all
         p.control.write(ControlFlags::empty());
 
-        let scale = uart_clock/16;
-        let integer = scale / bps;
-        let reminder = scale % bps; 
-        // reminder / bps == fractional / 64
-        let fractional = 64*reminder/bps;
-
-        
-        p.integer_baud_rate.write(integer as u32);
-        p.fractional_baud_rate.write(fractional as u32);
+        p.set_divisors(uart_clock, bps);
         // update, as according to spec there are bits that should not be
         // modified
         p.line_control.update(|line_control| { *line_control |= ENABLE_FIFO | WLEN_8 | PARITY_ENABLE | TWO_STOP_BITS_SELECT; });
@@ -155,6 +147,36 @@ impl PL011 {
 
         p
     }
+
+    // a uart that is already set up (e.g. by the boot loader)
+    pub unsafe fn at(v: ::mem::VirtualAddress) -> &'static mut Self {
+        &mut *(v.0 as *mut PL011)
+    }
+
+    fn set_divisors(&mut self, uart_clock : u64, bps : u64) {
+        let scale = uart_clock/16;
+        let integer = scale / bps;
+        let reminder = scale % bps;
+        // reminder / bps == fractional / 64
+        let fractional = 64*reminder/bps;
+
+        self.integer_baud_rate.write(integer as u32);
+        self.fractional_baud_rate.write(fractional as u32);
+    }
+
+    // change the rate and leave the rest of the setup as it is.
+    pub fn set_baud_rate(&mut self, uart_clock : u64, bps : u64) {
+        // let what is being sent go out first
+        while self.flags.read().contains(BUSY) {}
+        let control = self.control.read();
+        self.control.write(ControlFlags::empty());
+
+        self.set_divisors(uart_clock, bps);
+        // the divisors are only latched by a write to the line control register
+        self.line_control.update(|_| {});
+
+        self.control.write(control);
+    }
 }
 
 // TODO implement interrupt handler (template over something that can borrow a slice??)
@@ -4,9 +4,8 @@ use platform;
 use collections::boxed::Box;
 use core::borrow::Borrow;
 
-// processor jumps to address 0 so must be ID mapper here for now, till (if?) if will relocate the vectors.
-// pub const VECTORS_ADDR : ::mem::VirtualAddress  = ::mem::VirtualAddress(0xea00_0000);
-pub const VECTORS_ADDR: ::mem::VirtualAddress = ::mem::VirtualAddress(0x0);
+// we use high vectors, so the low addresses are left for user space.
+pub const VECTORS_ADDR: ::mem::VirtualAddress = ::mem::VirtualAddress(0xffff_0000);
 
 
 /* TODO: only use this macro for interrupts */
@@ -39,7 +38,9 @@ const SIZE_OF_INT_CTX : usize = 4*(1+1+1+13+1);
 
 
 macro_rules! inthandler {
-    ( $handler:ident ) => {{ 
+    ( $handler:ident ) => { inthandler!($handler, "") };
+    // extra is inserted right after switching to supervisor mode, before calling the handler
+    ( $handler:ident, $extra:expr ) => {{ 
 
 extern "C" fn vector_with_context(ctx : &InterruptContext) {
 
@@ -95,7 +96,7 @@ extern "C" fn vector_entry() -> !{
     // r13,r14 are saved in the cpu when we switch modes else; r15 will be the current r14
 
     unsafe{
-    asm!("sub lr,lr, #4
+    asm!(concat!("sub lr,lr, #4
           push {lr}
           push {r0-r12}
           mrs r1, spsr
@@ -139,10 +140,11 @@ extern "C" fn vector_entry() -> !{
           orr   r1, r1, r2
           /* change mode! */
           msr cpsr_c, r1
+          ", $extra, "
           /* move on */
           bl $0
           /* should not get here */
-    ":: "i"(vector_with_context as extern "C" fn(_) ),
+    "):: "i"(vector_with_context as extern "C" fn(_) ),
         "i"(SIZE_OF_INT_CTX - 2*4 /* lr and sp are pushed after stack is fixed */),
         "i"(super::cpu::MODE_MASK),
         "i"(super::cpu::SUPER_MODE),
@@ -188,8 +190,10 @@ pub fn init_interrupts() {
         vec_table[8 + 0] = inthandler!(vector_reset_handler) as u32;
         vec_table[8 + 1] = inthandler!(vector_undefined_handler) as u32;
         vec_table[8 + 2] = inthandler!(vector_softint_handler) as u32;
-        vec_table[8 + 3] = inthandler!(vector_prefetch_abort_handler) as u32;
-        vec_table[8 + 4] = inthandler!(vector_data_abort_handler) as u32;
+        // aborts run on the abort mode stack (right below the saved context), as the stack of the
+        // interrupted thread may be the thing that faulted.
+        vec_table[8 + 3] = inthandler!(vector_prefetch_abort_handler, "mov sp, r0") as u32;
+        vec_table[8 + 4] = inthandler!(vector_data_abort_handler, "mov sp, r0") as u32;
         vec_table[8 + 5] = 0;
         vec_table[8 + 6] = inthandler!(vector_irq_handler) as u32;
         vec_table[8 + 7] = inthandler!(vector_fiq_handler) as u32;
@@ -253,31 +257,136 @@ fn vector_softint_handler(ctx: &mut InterruptContext) {
 }
 
 fn vector_prefetch_abort_handler(ctx: &mut InterruptContext) {
-    use collections::String;
-    use core::fmt::Write;
-    platform::write_to_console("prefetch abort!");
-    let mut w = String::new();
-    write!(&mut w, "Context: {:?}", ctx);
-    platform::write_to_console(&w);
+    // prefetch abort is lr - 4; the macro already gave us that in pc.
+    let fault = ::mem::PageFault {
+        addr: ::mem::VirtualAddress(super::cpu::get_ifar() as usize),
+        fault_type: decode_fault_status(super::cpu::get_ifsr()),
+        write: false,
+        instruction: true,
+        user: is_user_mode(ctx),
+    };
 
-    loop {}
+    handle_abort(ctx, &fault, "Prefetch abort!");
 }
 
 fn vector_data_abort_handler(ctx: &mut InterruptContext) {
-    use collections::String;
-    use core::fmt::Write;
     // data about is lr - 8; the macro gave us lr -4 in pc, so just fix the missing 4 bytes
 
     ctx.pc -= 4;
 
-    platform::write_to_console("Data abort!");
+    let dfsr = super::cpu::get_dfsr();
+    let fault = ::mem::PageFault {
+        addr: ::mem::VirtualAddress(super::cpu::get_dfar() as usize),
+        fault_type: decode_fault_status(dfsr),
+        write: (dfsr & FSR_WNR) != 0,
+        instruction: false,
+        user: is_user_mode(ctx),
+    };
+
+    handle_abort(ctx, &fault, "Data abort!");
+}
+
+// write not read bit of the DFSR
+const FSR_WNR: u32 = 1 << 11;
+const THUMB_BIT: u32 = 1 << 5;
+
+// decode the fault status (short descriptor format); fs[4] is bit 10 of the fsr.
+fn decode_fault_status(fsr: u32) -> ::mem::FaultType {
+    let fs = (fsr & 0xF) | ((fsr >> 6) & 0x10);
+    match fs {
+        // 0b00011 is an alignment fault on armv6 and an access flag fault on armv7; we don't
+        // enable the access flag so it can only be alignment.
+        0b00001 | 0b00011 => ::mem::FaultType::Alignment,
+        0b00101 | 0b00111 => ::mem::FaultType::Translation,
+        0b01001 | 0b01011 => ::mem::FaultType::Domain,
+        0b01101 | 0b01111 => ::mem::FaultType::Permission,
+        _ => ::mem::FaultType::Other(fs),
+    }
+}
+
+fn is_user_mode(ctx: &InterruptContext) -> bool {
+    (ctx.cpsr & super::cpu::MODE_MASK) == super::cpu::USER_MODE
+}
+
+fn handle_abort(ctx: &mut InterruptContext, fault: &::mem::PageFault, what: &str) {
+    use collections::String;
+    use core::fmt::Write;
+
+    if platform::get_memory_services().fault_dispatcher.dispatch(fault) {
+        // fixed; return and retry the access
+        return;
+    }
+
+    platform::write_to_console(what);
+    check_stack_overflow(fault.addr);
     let mut w = String::new();
-    write!(&mut w, "Context: {:?}", ctx);
+    write!(&mut w, "Fault: {:?} at {:#x}; Context: {:?}", fault.fault_type, fault.addr.0, ctx);
     platform::write_to_console(&w);
 
+    kill_faulting_thread(ctx);
+}
+
+// make the interrupted thread "return" to exit_faulted_thread instead of retrying the access.
+// the thread's stack may be garbage (it may even be the thing that faulted), so start over from
+// the top of it.
+fn kill_faulting_thread(ctx: &mut InterruptContext) {
+    let mode = ctx.cpsr & super::cpu::MODE_MASK;
+    let in_thread = (mode == super::cpu::SUPER_MODE) || (mode == super::cpu::SYS_MODE) ||
+                    (mode == super::cpu::USER_MODE);
+
+    let mut stack_top = None;
+    // faults in interrupt handlers, or before the scheduler is up, can't be blamed on a thread.
+    if in_thread && platform::is_system_ready() {
+        let curthread_cell = platform::get_platform_services().get_current_cpu().get_running_thread();
+        if let Ok(curthread) = curthread_cell.try_borrow() {
+            if let Some(ref t) = *curthread {
+                stack_top = t.stack.as_ref().map(|s| s.top());
+            }
+        }
+    }
+
+    match stack_top {
+        Some(top) => {
+            ctx.sp = top.0 as u32;
+            ctx.lr = 0;
+            ctx.pc = exit_faulted_thread as u32;
+            ctx.cpsr = (ctx.cpsr & !(super::cpu::MODE_MASK | THUMB_BIT)) | super::cpu::SUPER_MODE |
+                       super::cpu::DISABLE_IRQ;
+        }
+        None => {
+            platform::write_to_console("Unrecoverable fault!");
+            loop {}
+        }
+    }
+}
+
+extern "C" fn exit_faulted_thread() -> ! {
+    platform::log(::bootparams::LOG_ERR, "killing faulted thread");
+    platform::get_platform_services().get_scheduler().exit_thread_with(::thread::ExitStatus::Faulted);
+    // never gonna get here..
     loop {}
 }
 
+fn check_stack_overflow(fault_addr: ::mem::VirtualAddress) {
+    use collections::String;
+    use core::fmt::Write;
+    // before the scheduler is up there are no platform services, and no threads to blame.
+    if !platform::is_system_ready() {
+        return;
+    }
+    // the fault might have happened while the running thread was borrowed.
+    let curthread_cell = platform::get_platform_services().get_current_cpu().get_running_thread();
+    if let Ok(curthread) = curthread_cell.try_borrow() {
+        if let Some(ref t) = *curthread {
+            if t.is_stack_overflow(fault_addr) {
+                let mut w = String::new();
+                write!(&mut w, "stack overflow in thread {}", t.id.0);
+                platform::write_to_console(&w);
+            }
+        }
+    }
+}
+
 fn vector_irq_handler(ctx: &mut InterruptContext) {
     unsafe {
         if let Some(ref mut func) = VEC_TABLE.irq_callback {
@@ -0,0 +1,268 @@
+// the kernel command line: space separated "key=value" parameters and flags, e.g.
+// "console=ttyAMA0,115200 heap=8M maxcpus=2 loglevel=4 hz=100".
+// it is parsed before there is a heap (the heap size is one of the parameters), so nothing here
+// allocates; values point into the command line.
+
+use mem::MemorySize;
+
+// message levels, as in linux: 0 is an emergency and 7 is debug. a message is printed when its
+// level is below the loglevel; the default prints everything but debug.
+pub const LOG_ERR: usize = 3;
+pub const LOG_WARNING: usize = 4;
+pub const LOG_INFO: usize = 6;
+pub const LOG_DEBUG: usize = 7;
+const DEFAULT_LOGLEVEL: usize = LOG_DEBUG;
+
+#[derive(Copy, Clone)]
+pub struct Console {
+    // e.g. "ttyAMA0"
+    pub name: &'static str,
+    pub baud: Option<u32>,
+}
+
+pub struct BootParams {
+    cmdline: &'static str,
+    pub console: Option<Console>,
+    pub loglevel: usize,
+    // the initial heap size
+    pub heap: Option<MemorySize>,
+    // what the main thread runs
+    pub init: Option<&'static str>,
+    pub nosmp: bool,
+    pub maxcpus: Option<usize>,
+    // the scheduler's time slice, in milliseconds
+    pub quantum: Option<usize>,
+    // timer ticks per second
+    pub hz: Option<usize>,
+}
+
+static mut BOOT_PARAMS: Option<BootParams> = None;
+
+// called once on boot, before the heap is initialized.
+pub fn init(cmdline: &'static str) {
+    unsafe { BOOT_PARAMS = Some(BootParams::parse(cmdline)) };
+}
+
+// messages of level are printed; everything is, before the command line is parsed.
+pub fn is_logged(level: usize) -> bool {
+    unsafe {
+        match BOOT_PARAMS {
+            Some(ref p) => level < p.loglevel,
+            None => true,
+        }
+    }
+}
+
+pub fn get() -> &'static BootParams {
+    unsafe {
+        match BOOT_PARAMS {
+            Some(ref p) => p,
+            None => panic!("boot params are not initialized!"),
+        }
+    }
+}
+
+impl BootParams {
+    pub fn parse(cmdline: &'static str) -> BootParams {
+        let mut p = BootParams {
+            cmdline: cmdline,
+            console: None,
+            loglevel: DEFAULT_LOGLEVEL,
+            heap: None,
+            init: None,
+            nosmp: false,
+            maxcpus: None,
+            quantum: None,
+            hz: None,
+        };
+
+        p.console = p.get("console").map(parse_console);
+        p.loglevel = p.get("loglevel").and_then(parse_number).unwrap_or(DEFAULT_LOGLEVEL);
+        p.heap = p.get("heap").and_then(parse_size);
+        p.init = p.get("init");
+        p.nosmp = p.has("nosmp");
+        p.maxcpus = p.get("maxcpus").and_then(parse_number);
+        p.quantum = p.get("quantum").and_then(parse_number);
+        p.hz = p.get("hz").and_then(parse_number);
+        p
+    }
+
+    pub fn cmdline(&self) -> &'static str {
+        self.cmdline
+    }
+
+    // the value of key=value. when a key is there more than once, the last one wins.
+    pub fn get(&self, key: &str) -> Option<&'static str> {
+        self.cmdline
+            .split_whitespace()
+            .filter_map(|param| {
+                let mut kv = param.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) if k == key => Some(v),
+                    _ => None,
+                }
+            })
+            .last()
+    }
+
+    // a flag, like nosmp
+    pub fn has(&self, flag: &str) -> bool {
+        self.cmdline.split_whitespace().any(|param| param == flag)
+    }
+
+    // the baud rate of the console, if the command line makes the console named name (e.g.
+    // "ttyAMA0") and says what rate.
+    pub fn console_baud(&self, name: &str) -> Option<u32> {
+        match self.console {
+            Some(c) if c.name == name => c.baud,
+            _ => None,
+        }
+    }
+
+    // the timer ticks per second: hz, if a tick is a whole number of milliseconds at that rate,
+    // or else the board's default.
+    pub fn ticks_in_second(&self, default: usize) -> usize {
+        match self.hz {
+            Some(hz) if (hz > 0) && (hz <= 1000) && (1000 % hz == 0) => hz,
+            _ => default,
+        }
+    }
+
+    // how many cpus to use, out of the ones we have.
+    pub fn num_cpus(&self, available: usize) -> usize {
+        if self.nosmp {
+            return 1;
+        }
+        match self.maxcpus {
+            Some(max) if (max > 0) && (max < available) => max,
+            _ => available,
+        }
+    }
+}
+
+// "ttyAMA0,115200n8" - the name and the baud rate. what comes after the rate (parity and such)
+// is ignored.
+fn parse_console(value: &'static str) -> Console {
+    let mut parts = value.splitn(2, ',');
+    let name = parts.next().unwrap_or("");
+    let baud = parts.next().and_then(|options| {
+        let digits = options.find(|c: char| !c.is_digit(10)).unwrap_or(options.len());
+        match options[..digits].parse::<u32>() {
+            Ok(0) | Err(_) => None,
+            Ok(baud) => Some(baud),
+        }
+    });
+    Console {
+        name: name,
+        baud: baud,
+    }
+}
+
+// decimal, or hex with 0x.
+fn parse_number(value: &str) -> Option<usize> {
+    if value.starts_with("0x") {
+        usize::from_str_radix(&value[2..], 16).ok()
+    } else {
+        value.parse::<usize>().ok()
+    }
+}
+
+// a number with an optional K, M or G suffix. sizes that don't fit in a usize are rejected,
+// rather than wrapping to something small.
+fn parse_size(value: &str) -> Option<MemorySize> {
+    let (number, shift) = match value.chars().last() {
+        Some('k') | Some('K') => (&value[..value.len() - 1], 10),
+        Some('m') | Some('M') => (&value[..value.len() - 1], 20),
+        Some('g') | Some('G') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    parse_number(number)
+        .and_then(|n| n.checked_mul(1 << shift))
+        .map(MemorySize::Bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_console, parse_number, parse_size, BootParams, LOG_INFO};
+    use mem;
+
+    fn size(value: &str) -> Option<usize> {
+        parse_size(value).map(mem::to_bytes)
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(size("4096"), Some(4096));
+        assert_eq!(size("0x1000"), Some(4096));
+        assert_eq!(size("8K"), Some(8 << 10));
+        assert_eq!(size("8k"), Some(8 << 10));
+        assert_eq!(size("16M"), Some(16 << 20));
+        assert_eq!(size("1G"), Some(1 << 30));
+        assert_eq!(size(""), None);
+        assert_eq!(size("M"), None);
+        assert_eq!(size("12X"), None);
+        assert_eq!(size("-1M"), None);
+    }
+
+    #[test]
+    fn sizes_that_overflow() {
+        // 4G doesn't fit on 32 bit targets
+        assert_eq!(size("4G"), 4usize.checked_mul(1 << 30));
+        assert_eq!(size("4194304K"), 4194304usize.checked_mul(1 << 10));
+        // 2^64 bytes doesn't fit anywhere
+        assert_eq!(size("17179869184G"), None);
+        assert_eq!(size("99999999999999999999999G"), None);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("100"), Some(100));
+        assert_eq!(parse_number("0x1f"), Some(0x1f));
+        assert_eq!(parse_number("0x"), None);
+        assert_eq!(parse_number("1f"), None);
+    }
+
+    #[test]
+    fn key_values() {
+        let p = BootParams::parse("  console=ttyAMA0,115200n8 heap=8M  nosmp loglevel=6 init=a=b \
+                                   quantum=10 quantum=20 hz=");
+        assert_eq!(p.get("heap"), Some("8M"));
+        assert_eq!(p.heap.map(mem::to_bytes), Some(8 << 20));
+        assert_eq!(p.loglevel, LOG_INFO);
+        // only the first = splits
+        assert_eq!(p.init, Some("a=b"));
+        // the last one wins
+        assert_eq!(p.quantum, Some(20));
+        // there, but empty
+        assert_eq!(p.get("hz"), Some(""));
+        assert_eq!(p.hz, None);
+        assert_eq!(p.get("nosmp"), None);
+        assert!(p.has("nosmp"));
+        assert!(!p.has("heap"));
+        assert_eq!(p.get("maxcpus"), None);
+        assert_eq!(p.console_baud("ttyAMA0"), Some(115200));
+        assert_eq!(p.console_baud("ttyS0"), None);
+        assert_eq!(p.num_cpus(4), 1);
+    }
+
+    #[test]
+    fn consoles() {
+        let c = parse_console("ttyAMA0");
+        assert_eq!(c.name, "ttyAMA0");
+        assert_eq!(c.baud, None);
+        assert_eq!(parse_console("ttyAMA0,0").baud, None);
+        assert_eq!(parse_console("ttyAMA0,n8").baud, None);
+        assert_eq!(parse_console("ttyAMA0,9600").baud, Some(9600));
+    }
+
+    #[test]
+    fn defaults() {
+        let p = BootParams::parse("");
+        assert_eq!(p.heap.map(mem::to_bytes), None);
+        assert_eq!(p.num_cpus(4), 4);
+        assert_eq!(p.ticks_in_second(100), 100);
+        assert_eq!(BootParams::parse("hz=250").ticks_in_second(100), 250);
+        assert_eq!(BootParams::parse("hz=300").ticks_in_second(100), 100);
+        assert_eq!(BootParams::parse("maxcpus=2").num_cpus(4), 2);
+    }
+}
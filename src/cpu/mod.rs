@@ -3,12 +3,34 @@ use core::cell::RefCell;
 use core::mem;
 use core::cell::Cell;
 
+// a set of cpus, a bit per cpu id
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CpuMask(pub usize);
+
+impl CpuMask {
+    pub const fn all() -> CpuMask {
+        CpuMask(!0)
+    }
+
+    pub const fn single(cpu: usize) -> CpuMask {
+        CpuMask(1 << cpu)
+    }
+
+    pub fn contains(&self, cpu: usize) -> bool {
+        (cpu < 32) && ((self.0 & (1 << cpu)) != 0)
+    }
+}
+
 pub struct CPU {
     // no need to lock this, as it should only be modified
     // from the same CPU and with no interrupts
     running_thread : RefCell<Option<Box<::thread::Thread>>>,
     id : usize,
     pub should_resched : Cell<bool>,
+    // ticks since this cpu started; only touched by this cpu, in its timer interrupt
+    pub ticks : Cell<usize>,
+    // the ticks of those that found the idle thread running
+    pub idle_ticks : Cell<usize>,
 //    pub arch_services : RefCell<ArchCPUServices>,
 }
 
@@ -24,6 +46,8 @@ impl CPU {
             running_thread: RefCell::new(None),
             id : id,
             should_resched : Cell::new(false),
+            ticks : Cell::new(0),
+            idle_ticks : Cell::new(0),
         }
     }
 
@@ -49,7 +73,8 @@ impl CPU {
     pub fn interrupted(&self, ipi : IPI) {
         match ipi {
             IPI::MemChanged => ::platform::invalidate_tlb(),
-            IPI::SchedChanged => /* if on idle thread - yeild();*/{} ,
+            // a thread was put in our run queue; see if it should run when the interrupt returns
+            IPI::SchedChanged => self.should_resched.set(true),
         }
         
     }
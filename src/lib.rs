@@ -12,12 +12,18 @@
 #![feature(const_fn)]
 #![feature(fnbox)]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 #[macro_use]
 extern crate collections;
 extern crate alloc;
 extern crate spin;
 extern crate rlibc;
 extern crate kernel_alloc;
+extern crate fdt;
+extern crate frame_bitmap;
 extern crate volatile;
 
 #[macro_use]
@@ -32,6 +38,8 @@ pub mod thread;
 pub mod platform;
 pub mod cpu;
 pub mod io;
+pub mod bootparams;
+pub mod time;
 
 mod drivers;
 
@@ -41,16 +49,78 @@ use collections::Vec;
 use alloc::rc::Rc;
 use alloc::arc::Arc;
 
+const HEAP_BASE: ::mem::VirtualAddress = mem::VirtualAddress(0xf000_0000);
+// the heap starts with HEAP_INITIAL_SIZE (or heap= on the command line) and grows on demand up
+// to HEAP_MAX_SIZE
+const HEAP_INITIAL_SIZE: mem::MemorySize = mem::MemorySize::MegaBytes(4);
+const HEAP_MAX_SIZE: mem::MemorySize = mem::MemorySize::MegaBytes(128);
+
+// rounded up to pages, and no more than the max.
+fn heap_initial_size() -> mem::MemorySize {
+    let size = mem::to_bytes(bootparams::get().heap.unwrap_or(HEAP_INITIAL_SIZE));
+    let size = core::cmp::min(size, mem::to_bytes(HEAP_MAX_SIZE));
+    let pages = (size + platform::PAGE_MASK) >> platform::PAGE_SHIFT;
+    mem::MemorySize::PageSizes(core::cmp::max(pages, 1))
+}
+
 fn init_heap(mapper: &mut ::mem::MemoryMapper, frame_allocator: &mut ::mem::FrameAllocator) {
-    const HEAP_BASE: ::mem::VirtualAddress = mem::VirtualAddress(0xf000_0000);
-    const HEAP_SIZE: mem::MemorySize = mem::MemorySize::MegaBytes(40);
-    let pa = frame_allocator.allocate(mem::to_pages(HEAP_SIZE).ok().unwrap()).unwrap();
-    mapper.map(frame_allocator, pa, HEAP_BASE, HEAP_SIZE).unwrap();
+    let initial_size = heap_initial_size();
+    // the initial heap is mapped directly, as the memory services are allocated on the heap.
+    let pa = frame_allocator.allocate(mem::to_pages(initial_size).ok().unwrap()).unwrap();
+    mapper.map(frame_allocator, pa, HEAP_BASE, initial_size, mem::KERNEL_DATA).unwrap();
     kernel_alloc::init_heap(HEAP_BASE.0,
-                            mem::to_bytes(HEAP_SIZE),
+                            mem::to_bytes(initial_size),
+                            mem::to_bytes(HEAP_MAX_SIZE),
                             platform::get_interrupts,
-                            platform::set_interrupts);
+                            platform::set_interrupts,
+                            platform::get_current_cpu_id,
+                            grow_heap,
+                            shrink_heap);
+    init_heap_debug();
+}
+
+#[cfg(feature = "heap-debug")]
+fn init_heap_debug() {
+    kernel_alloc::set_debug_console(platform::write_to_console);
+}
+
+#[cfg(not(feature = "heap-debug"))]
+fn init_heap_debug() {}
+
+// called by the allocator, with the heap locked - so no heap allocations here.
+// the frames don't need to be contiguous, so map them one by one.
+fn grow_heap(start: usize, size: usize) -> bool {
+    let memory_services = platform::get_memory_services();
+    let pages = size >> platform::PAGE_SHIFT;
+    for i in 0..pages {
+        let v = mem::VirtualAddress(start).uoffset(i << platform::PAGE_SHIFT);
+        let mapped = match memory_services.frame_alloc.allocate(1) {
+            Some(pa) => {
+                let r = memory_services.mem_manager.map(pa, v, mem::MemorySize::PageSizes(1), mem::KERNEL_DATA);
+                if r.is_err() {
+                    memory_services.frame_alloc.deallocate(pa, 1);
+                }
+                r.is_ok()
+            }
+            None => false,
+        };
+        if !mapped {
+            shrink_heap(start, i << platform::PAGE_SHIFT);
+            return false;
+        }
+    }
+    true
+}
 
+fn shrink_heap(start: usize, size: usize) {
+    let memory_services = platform::get_memory_services();
+    let pages = size >> platform::PAGE_SHIFT;
+    for i in 0..pages {
+        let v = mem::VirtualAddress(start).uoffset(i << platform::PAGE_SHIFT);
+        let pa = memory_services.mem_manager.v2p(v).expect("heap page not mapped");
+        memory_services.mem_manager.unmap(v, mem::MemorySize::PageSizes(1)).expect("Can't unmap heap");
+        memory_services.frame_alloc.deallocate(pa, 1);
+    }
 }
 
 pub fn rust_main<M, F>(mut mapper: M, mut frame_allocator: F)
@@ -78,8 +148,10 @@ pub fn rust_main<M, F>(mut mapper: M, mut frame_allocator: F)
                 )
             ), 
             frame_alloc: farc.clone(),
+            fault_dispatcher: mem::FaultDispatcher::new(),
         });
     }
+    mem::object::init();
     unsafe{
         platform::set_platform_services(platform::PlatformServices {
             scheduler: sched::Sched::new(),
@@ -88,10 +160,9 @@ pub fn rust_main<M, F>(mut mapper: M, mut frame_allocator: F)
         });
     }
 
-    // set current thread
+    // set current thread; it is only around until the scheduler takes over.
     let mut curth = thread::Thread::new_cur_thread(sched::MAIN_THREAD_ID);
-    curth.cpu_affinity = Some(platform::get_current_cpu_id());
-    curth.priority = 0;
+    curth.cpu_affinity = cpu::CpuMask::single(platform::get_current_cpu_id());
     platform::get_platform_services().get_current_cpu().set_running_thread(Box::new(curth));
 
 
@@ -119,18 +190,19 @@ pub fn rust_main<M, F>(mut mapper: M, mut frame_allocator: F)
     //
     
     // to do:
-    // create idle thread with lowest priority, that just does wait_for_interurpts
     // create isr thread with highest priority that responds to interrupts. (need semaphore for that..)
 
-    platform::get_platform_services()
-        .get_scheduler()
+    thread::ThreadBuilder::new()
+        .name("main")
         .spawn(move || {
             main_thread();
         });
 
 
-    platform::get_platform_services().get_scheduler().yield_thread();
+    // the boot thread is done; from now on the cpu runs threads, or its idle thread.
+    platform::get_platform_services().get_scheduler().exit_thread();
     loop {
+        // never gets here
         platform::wait_for_interrupts();
     }
 
@@ -153,8 +225,23 @@ pub fn rust_main<M, F>(mut mapper: M, mut frame_allocator: F)
 }
 
 
+// what main_thread runs, unless the command line says otherwise with init=; a comma separated
+// list of demos, or "none".
+const DEFAULT_INIT: &'static str = "sema,leds";
+
 fn main_thread() {
+    let init = bootparams::get().init.unwrap_or(DEFAULT_INIT);
+    for demo in init.split(',') {
+        match demo {
+            "sema" => sema_demo(),
+            "leds" => leds_demo(),
+            "none" | "" => {}
+            _ => platform::log(bootparams::LOG_ERR, "unknown init demo"),
+        }
+    }
+}
 
+fn sema_demo() {
     // sema
     let sema = Arc::new(sync::Semaphore::new(1));
 
@@ -171,7 +258,7 @@ fn main_thread() {
                     sema.acquire();
                     platform::write_to_console("t1 acquireD");
 
-                    platform::get_platform_services().get_scheduler().sleep(1000);
+                    platform::get_platform_services().get_scheduler().sleep(time::Duration::from_secs(1));
                     platform::write_to_console("t1 release");
                     sema.release();
                     platform::write_to_console("t1 sem releaseD");
@@ -193,7 +280,7 @@ fn main_thread() {
                     platform::write_to_console("t2 acquireD");
 
                     platform::write_to_console("t2 sleep 1000");
-                    platform::get_platform_services().get_scheduler().sleep(1000);
+                    platform::get_platform_services().get_scheduler().sleep(time::Duration::from_secs(1));
                     platform::write_to_console("t2 slept");
 
                     platform::write_to_console("t2 release");
@@ -202,9 +289,9 @@ fn main_thread() {
                 }
             });
     }
+}
 
-{
-
+fn leds_demo() {
         platform::get_platform_services()
         .get_scheduler()
         .spawn(move || {
@@ -226,11 +313,10 @@ fn main_thread() {
 
                     }));
 
-                        platform::get_platform_services().get_scheduler().sleep(1000);
+                        platform::get_platform_services().get_scheduler().sleep(time::Duration::from_secs(1));
                 }
         });
 }
-}
 
 
 #[lang = "eh_personality"]
@@ -0,0 +1,198 @@
+use core::sync::atomic;
+use alloc::arc::Arc;
+use collections::Vec;
+
+use platform;
+use sync;
+
+use super::{FaultResult, MemoryMapper, MemoryAttributes, MemorySize, PageFault, PhysicalAddress,
+            PVMapper, VirtualAddress};
+use super::object::{Mapping, MemoryObject};
+
+static ADDRESS_SPACE_ID_COUNTER: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+
+// the user half of the memory map. the kernel half is the same in all address spaces, so
+// threads of different address spaces only differ in what they see below USER_SPACE_END.
+pub struct AddressSpace {
+    id: usize,
+    page_table: platform::UserPageTable,
+    // memory objects mapped here, sorted by address
+    mappings: sync::CpuMutex<Vec<Mapping>>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, ()> {
+        let page_table = try!(platform::UserPageTable::new(platform::get_memory_services().frame_alloc.as_ref()));
+        Ok(AddressSpace {
+            id: ADDRESS_SPACE_ID_COUNTER.fetch_add(1, atomic::Ordering::SeqCst),
+            page_table: page_table,
+            mappings: sync::CpuMutex::new(vec![]),
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn map(&self,
+               p: PhysicalAddress,
+               v: VirtualAddress,
+               size: MemorySize,
+               attrs: MemoryAttributes)
+               -> Result<(), ()> {
+        self.page_table.map(platform::get_memory_services().frame_alloc.as_ref(), p, v, size, attrs)
+    }
+
+    // how v is mapped; None if it isn't.
+    pub fn attributes(&self, v: VirtualAddress) -> Option<MemoryAttributes> {
+        self.page_table.attributes(v)
+    }
+
+    pub fn unmap(&self, v: VirtualAddress, size: MemorySize) -> Result<(), ()> {
+        let r = self.page_table.unmap(platform::get_memory_services().frame_alloc.as_ref(), v, size);
+        // threads of this address space may be running on other cpus. even on error, as some of
+        // the range may be gone.
+        super::send_ipi();
+        r
+    }
+
+    // map object at v. its pages are mapped when they are first touched.
+    pub fn map_object(&self,
+                      object: Arc<MemoryObject>,
+                      v: VirtualAddress,
+                      attrs: MemoryAttributes)
+                      -> Result<(), ()> {
+        let mapping = Mapping {
+            start: v,
+            object: object,
+            attrs: attrs,
+        };
+        if ((v.0 & platform::PAGE_MASK) != 0) || (mapping.end() > platform::USER_SPACE_END) ||
+           (mapping.end() <= v) {
+            return Err(());
+        }
+
+        let mut mappings = self.mappings.lock();
+        let index = mappings.iter().position(|m| m.start > v).unwrap_or(mappings.len());
+        let overlaps_prev = (index > 0) && (mappings[index - 1].end() > v);
+        let overlaps_next = (index < mappings.len()) && (mappings[index].start < mapping.end());
+        if overlaps_prev || overlaps_next {
+            return Err(());
+        }
+        mappings.insert(index, mapping);
+        Ok(())
+    }
+
+    // remove the mapping that starts at v, and drop the pages it mapped. the mapping is gone
+    // even if some page fails to unmap; the others are still unmapped and released.
+    pub fn unmap_object(&self, v: VirtualAddress) -> Result<Arc<MemoryObject>, ()> {
+        let mapping = {
+            let mut mappings = self.mappings.lock();
+            let index = try!(mappings.iter().position(|m| m.start == v).ok_or(()));
+            mappings.remove(index)
+        };
+        let frame_alloc = platform::get_memory_services().frame_alloc.as_ref();
+        let mut result = Ok(());
+        for page in 0..mapping.object.pages() {
+            let v = mapping.start.uoffset(page << platform::PAGE_SHIFT);
+            if let Some(p) = self.v2p(v) {
+                match self.unmap(v, MemorySize::PageSizes(1)) {
+                    Ok(()) => {
+                        if mapping.is_counted(p) {
+                            frame_alloc.release(p);
+                        }
+                    }
+                    Err(e) => result = Err(e),
+                }
+            }
+        }
+        result.map(|_| mapping.object)
+    }
+
+    // a copy of this address space: shared mappings are shared with the copy, and private pages
+    // become copy-on-write in both.
+    pub fn fork(&self) -> Result<AddressSpace, ()> {
+        let child = try!(AddressSpace::new());
+        let r = self.share_with(&child);
+        // our private pages were write protected in place; other cpus may still see them
+        // writable. even on error, as some of them may be.
+        super::send_ipi();
+        r.map(|_| child)
+    }
+
+    fn share_with(&self, child: &AddressSpace) -> Result<(), ()> {
+        let frame_alloc = platform::get_memory_services().frame_alloc.as_ref();
+        let mappings = self.mappings.lock();
+        for m in mappings.iter() {
+            // before its pages, so if we fail half way, the ones the child got are released
+            // when it is dropped.
+            child.mappings.lock().push(m.clone());
+            let cow_attrs = if m.is_private() {
+                m.attrs - super::WRITE
+            } else {
+                m.attrs
+            };
+            for page in 0..m.object.pages() {
+                let v = m.start.uoffset(page << platform::PAGE_SHIFT);
+                let p = match self.v2p(v) {
+                    Some(p) => p,
+                    None => continue,
+                };
+                if m.is_private() {
+                    // write protect our own copy; the next write here copies it again.
+                    try!(self.page_table.protect(v, cow_attrs));
+                }
+                try!(child.map(p, v, MemorySize::PageSizes(1), cow_attrs));
+                if m.is_counted(p) {
+                    frame_alloc.add_ref(p);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // a fault in the user half, while this address space is active.
+    pub fn handle_fault(&self, fault: &PageFault) -> FaultResult {
+        let mappings = self.mappings.lock();
+        let resolved = match mappings.iter().find(|m| m.contains(fault.addr)) {
+            Some(m) => m.resolve(self, fault).is_ok(),
+            None => false,
+        };
+        if resolved {
+            FaultResult::Resolved
+        } else {
+            FaultResult::NotHandled
+        }
+    }
+
+    // make this the user half of the current cpu; None is for threads that only live in the kernel.
+    pub fn activate(address_space: Option<&AddressSpace>) {
+        platform::switch_user_page_table(address_space.map(|a| &a.page_table))
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // the page table goes away with us, so the pages are only released, not unmapped.
+        let frame_alloc = platform::get_memory_services().frame_alloc.as_ref();
+        for m in self.mappings.lock().iter() {
+            for page in 0..m.object.pages() {
+                let v = m.start.uoffset(page << platform::PAGE_SHIFT);
+                if let Some(p) = self.page_table.v2p(v) {
+                    if m.is_counted(p) {
+                        frame_alloc.release(p);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl PVMapper for AddressSpace {
+    fn v2p(&self, v: VirtualAddress) -> Option<PhysicalAddress> {
+        self.page_table.v2p(v)
+    }
+    fn p2v(&self, p: PhysicalAddress) -> Option<VirtualAddress> {
+        self.page_table.p2v(p)
+    }
+}
@@ -0,0 +1,100 @@
+use core::slice;
+
+use platform;
+
+use super::{MemorySize, PhysicalAddress, PVMapper, VirtualAddress};
+
+// memory that is shared with a device. dma engines work on physical memory, bypassing the
+// cpu caches; so a buffer is physically contiguous and mapped uncached, and the device is given
+// its bus address (which is not always the physical address). it is normal memory, not device
+// memory, as the cpu accesses it through slices, and memcpy does unaligned accesses.
+pub struct DmaBuffer {
+    pub virt: VirtualAddress,
+    pub phys: PhysicalAddress,
+    pub bus_addr: usize,
+    pub len: usize,
+}
+
+// the address a device uses for a physical address.
+pub fn bus_address(p: PhysicalAddress) -> usize {
+    p.0 + platform::DMA_BUS_OFFSET
+}
+
+impl DmaBuffer {
+    pub fn new(len: usize) -> Result<DmaBuffer, ()> {
+        if len == 0 {
+            return Err(());
+        }
+
+        let memory_services = platform::get_memory_services();
+        let pages = (len + platform::PAGE_MASK) >> platform::PAGE_SHIFT;
+
+        let phys = try!(memory_services.frame_alloc.allocate(pages).ok_or(()));
+        let virt = match memory_services.mem_manager.alloc_region(MemorySize::PageSizes(pages), platform::PAGE_SIZE) {
+            Some(v) => v,
+            None => {
+                memory_services.frame_alloc.deallocate(phys, pages);
+                return Err(());
+            }
+        };
+
+        if let Err(e) = memory_services.mem_manager.map(phys, virt, MemorySize::PageSizes(pages), super::DMA_MEMORY) {
+            memory_services.mem_manager.free_region(virt, MemorySize::PageSizes(pages));
+            memory_services.frame_alloc.deallocate(phys, pages);
+            return Err(e);
+        }
+
+        // the frames may still have dirty lines from a previous (cached) mapping, that would be
+        // written over what the device puts there.
+        platform::clean_invalidate_dcache_range(virt, pages << platform::PAGE_SHIFT);
+
+        Ok(DmaBuffer {
+            virt: virt,
+            phys: phys,
+            bus_addr: bus_address(phys),
+            len: len,
+        })
+    }
+
+    fn pages(&self) -> usize {
+        (self.len + platform::PAGE_MASK) >> platform::PAGE_SHIFT
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt.0 as *const u8, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt.0 as *mut u8, self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let memory_services = platform::get_memory_services();
+        let size = MemorySize::PageSizes(self.pages());
+        memory_services.mem_manager.unmap(self.virt, size).expect("Can't unmap dma buffer");
+        memory_services.mem_manager.free_region(self.virt, size);
+        memory_services.frame_alloc.deallocate(self.phys, self.pages());
+    }
+}
+
+// streaming dma: the device works on normal (cached) kernel memory, and the caches are
+// maintained around the transfer.
+
+// the cpu wrote [v, v + len); make it visible to a device that is about to read it.
+pub fn sync_for_device(v: VirtualAddress, len: usize) {
+    platform::clean_dcache_range(v, len);
+}
+
+// a device wrote [v, v + len); drop what the cpu has cached of it, so it reads what the device
+// wrote. call it after the transfer is done.
+pub fn sync_for_cpu(v: VirtualAddress, len: usize) {
+    platform::invalidate_dcache_range(v, len);
+}
+
+// the bus address of kernel memory, for streaming dma. only valid for one page, as the pages
+// around it are not physically contiguous.
+pub fn bus_address_of(v: VirtualAddress) -> Option<usize> {
+    platform::get_memory_services().mem_manager.v2p(v).map(bus_address)
+}
@@ -0,0 +1,73 @@
+use alloc::arc::Arc;
+use collections::Vec;
+
+use sync;
+
+use super::VirtualAddress;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FaultType {
+    // nothing is mapped at the address
+    Translation,
+    // something is mapped, but the access is not allowed (i.e. write to read only memory)
+    Permission,
+    Alignment,
+    Domain,
+    // external aborts, parity errors, debug events and the such; holds the raw status
+    Other(u32),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PageFault {
+    pub addr: VirtualAddress,
+    pub fault_type: FaultType,
+    pub write: bool,
+    // prefetch abort; i.e. we tried to execute addr
+    pub instruction: bool,
+    pub user: bool,
+}
+
+pub enum FaultResult {
+    // the mapping was fixed, the faulting instruction can be retried
+    Resolved,
+    // not ours, ask the next handler
+    NotHandled,
+}
+
+pub trait FaultHandler {
+    // called with interrupts disabled, on the abort stack.
+    fn handle_fault(&self, fault: &PageFault) -> FaultResult;
+}
+
+// holds the fault handlers of the memory subsystem; things like demand-zero regions,
+// growing stacks and copy-on-write register here.
+pub struct FaultDispatcher {
+    // replaced, never changed in place, so a fault can take its own reference and run the
+    // handlers without the lock (and without allocating).
+    handlers: sync::CpuMutex<Arc<Vec<Arc<FaultHandler>>>>,
+}
+
+impl FaultDispatcher {
+    pub fn new() -> FaultDispatcher {
+        FaultDispatcher { handlers: sync::CpuMutex::new(Arc::new(vec![])) }
+    }
+
+    pub fn register(&self, handler: Arc<FaultHandler>) {
+        let mut handlers = self.handlers.lock();
+        let mut new_handlers: Vec<Arc<FaultHandler>> = handlers.iter().cloned().collect();
+        new_handlers.push(handler);
+        *handlers = Arc::new(new_handlers);
+    }
+
+    // returns true if one of the handlers resolved the fault. the handlers run without our lock,
+    // so faults on other cpus, and faults in a handler, don't wait for it.
+    pub fn dispatch(&self, fault: &PageFault) -> bool {
+        let handlers = self.handlers.lock().clone();
+        for h in handlers.iter() {
+            if let FaultResult::Resolved = h.handle_fault(fault) {
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,143 @@
+use core::ops;
+use collections::BTreeMap;
+use frame_bitmap::FrameBitmap;
+
+use platform;
+use sync;
+
+use super::{FrameStats, PhysicalAddress};
+
+// A bitmap based physical frame allocator; the bitmap itself is in lib/frame_bitmap.
+// the bitmap memory is provided by the caller, as this is created before we have a heap.
+pub struct BitmapFrameAllocator {
+    cpu_mutex: sync::CpuMutex<BitmapFrameAllocatorInner>,
+    // frames with more than one reference, and how many extra they have; most frames have one
+    // owner and are not here. it has its own lock, as the map allocates from the heap, and the
+    // heap allocates frames.
+    shared: sync::CpuMutex<BTreeMap<usize, usize>>,
+}
+
+impl ::mem::FrameAllocator for BitmapFrameAllocator {
+    fn allocate(&self, number: usize) -> Option<PhysicalAddress> {
+        self.cpu_mutex.lock().allocate(number, 1)
+    }
+
+    fn allocate_aligned(&self, number: usize, align: usize) -> Option<PhysicalAddress> {
+        self.cpu_mutex.lock().allocate(number, align)
+    }
+
+    fn deallocate(&self, addr: PhysicalAddress, size: usize) {
+        self.cpu_mutex.lock().deallocate(addr, size)
+    }
+
+    fn add_ref(&self, frame: PhysicalAddress) {
+        if self.ref_count(frame) == 0 {
+            panic!("reference to a free frame!")
+        }
+        *self.shared.lock().entry(frame_down(frame.0)).or_insert(0) += 1;
+    }
+
+    fn release(&self, frame: PhysicalAddress) -> bool {
+        let index = frame_down(frame.0);
+        let was_shared = {
+            let mut shared = self.shared.lock();
+            let remaining = match shared.get_mut(&index) {
+                Some(extra) => {
+                    *extra -= 1;
+                    Some(*extra)
+                }
+                None => None,
+            };
+            if remaining == Some(0) {
+                shared.remove(&index);
+            }
+            remaining.is_some()
+        };
+        if !was_shared {
+            self.deallocate(frame, 1);
+        }
+        !was_shared
+    }
+
+    fn ref_count(&self, frame: PhysicalAddress) -> usize {
+        let index = frame_down(frame.0);
+        {
+            let inner = self.cpu_mutex.lock();
+            if (index >= inner.frames.num_frames()) || !inner.frames.is_used(index) {
+                return 0;
+            }
+        }
+        1 + self.shared.lock().get(&index).map_or(0, |extra| *extra)
+    }
+
+    fn stats(&self) -> FrameStats {
+        let inner = self.cpu_mutex.lock();
+        FrameStats {
+            total: inner.frames.num_frames(),
+            free: inner.frames.free_frames(),
+            reserved: inner.frames.reserved_frames(),
+        }
+    }
+}
+
+impl BitmapFrameAllocator {
+    // bitmap must have at least one bit per frame in mem_size.
+    pub fn new(bitmap: &'static mut [u32],
+               skip_ranges: &[ops::Range<PhysicalAddress>],
+               mem_size: usize)
+               -> BitmapFrameAllocator {
+        let mut inner = BitmapFrameAllocatorInner::new(bitmap, mem_size);
+
+        // don't allocate frame zero cause the vector table is there..
+        inner.reserve(PhysicalAddress(0)..PhysicalAddress(platform::PAGE_SIZE));
+        for r in skip_ranges {
+            inner.reserve(r.clone());
+        }
+
+        BitmapFrameAllocator {
+            cpu_mutex: sync::CpuMutex::new(inner),
+            shared: sync::CpuMutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn reserve(&self, range: ops::Range<PhysicalAddress>) {
+        self.cpu_mutex.lock().reserve(range)
+    }
+}
+
+fn frame_up(a: usize) -> usize {
+    (a + platform::PAGE_MASK) >> platform::PAGE_SHIFT
+}
+
+fn frame_down(a: usize) -> usize {
+    a >> platform::PAGE_SHIFT
+}
+
+// the bitmap, in addresses
+struct BitmapFrameAllocatorInner {
+    frames: FrameBitmap<'static>,
+}
+
+impl BitmapFrameAllocatorInner {
+    fn new(bitmap: &'static mut [u32], mem_size: usize) -> BitmapFrameAllocatorInner {
+        BitmapFrameAllocatorInner { frames: FrameBitmap::new(bitmap, mem_size >> platform::PAGE_SHIFT) }
+    }
+
+    // the range is rounded out to whole frames.
+    fn reserve(&mut self, range: ops::Range<PhysicalAddress>) {
+        self.frames.reserve(frame_down(range.start.0)..frame_up(range.end.0))
+    }
+
+    // assume no interrupts here.
+    // align is in frames, and must be a power of two.
+    fn allocate(&mut self, number: usize, align: usize) -> Option<PhysicalAddress> {
+        self.frames.allocate(number, align).map(|f| PhysicalAddress(f << platform::PAGE_SHIFT))
+    }
+
+    fn deallocate(&mut self, addr: PhysicalAddress, number: usize) {
+        if (addr.0 & platform::PAGE_MASK) != 0 {
+            panic!("deallocating unaligned frame!")
+        }
+        self.frames.deallocate(frame_down(addr.0), number)
+    }
+}
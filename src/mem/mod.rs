@@ -4,6 +4,22 @@ use collections::boxed::Box;
 use super::platform;
 use super::cpu;
 
+pub mod frame_alloc;
+pub mod fault;
+pub mod address_space;
+pub mod region;
+pub mod dma;
+pub mod object;
+pub mod stats;
+
+pub use self::frame_alloc::BitmapFrameAllocator;
+pub use self::fault::{FaultDispatcher, FaultHandler, FaultResult, FaultType, PageFault};
+pub use self::address_space::AddressSpace;
+pub use self::region::RegionAllocator;
+pub use self::dma::DmaBuffer;
+pub use self::object::{MemoryObject, ObjectKind};
+pub use self::stats::{stats, dump_stats, dump_vm_map, MemStats};
+
 #[derive(Copy, Clone, Debug)]
 pub enum MemorySize {
     Bytes(usize),
@@ -73,9 +89,56 @@ impl Sub for PhysicalAddress {
     }
 }
 
+bitflags! {
+    // how a mapping may be accessed. everything that is mapped is readable by the kernel.
+    pub flags MemoryAttributes: u32 {
+        const WRITE            = 1 << 0,
+        const EXECUTE          = 1 << 1,
+        // accessible from user mode, not just the kernel
+        const USER             = 1 << 2,
+        // coherent between cpus
+        const SHAREABLE        = 1 << 3,
+        // cache policy; none of these means normal write-back memory
+        const WRITE_THROUGH    = 1 << 4,
+        const DEVICE           = 1 << 5,
+        const STRONGLY_ORDERED = 1 << 6,
+        // normal memory that is not cached; unlike device memory, unaligned accesses are fine.
+        const UNCACHED         = 1 << 7,
+
+        const KERNEL_TEXT   = EXECUTE.bits,
+        const KERNEL_RODATA = 0,
+        const KERNEL_DATA   = WRITE.bits,
+        const KERNEL_RWX    = WRITE.bits | EXECUTE.bits,
+        const DEVICE_MEMORY = WRITE.bits | DEVICE.bits,
+        const DMA_MEMORY    = WRITE.bits | UNCACHED.bits,
+        const USER_TEXT     = USER.bits | EXECUTE.bits,
+        const USER_DATA     = USER.bits | WRITE.bits,
+    }
+}
+
 pub trait FrameAllocator {
     fn allocate(&self, num_frames: usize) -> Option<PhysicalAddress>;
+    // allocate num_frames contiguous frames, where the first frame index is a multiple of align.
+    fn allocate_aligned(&self, num_frames: usize, align: usize) -> Option<PhysicalAddress>;
     fn deallocate(&self, start: PhysicalAddress, num_frames: usize);
+
+    // frames can have more than one owner (i.e. shared or copy-on-write pages). an allocated
+    // frame starts with one reference; release drops one, and frees the frame with the last.
+    fn add_ref(&self, frame: PhysicalAddress);
+    // returns true if the frame was freed.
+    fn release(&self, frame: PhysicalAddress) -> bool;
+    // 0 for free frames.
+    fn ref_count(&self, frame: PhysicalAddress) -> usize;
+
+    fn stats(&self) -> FrameStats;
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    // never handed out: the kernel image, holes in memory, what the boot loader left us
+    pub reserved: usize,
 }
 
 pub trait PVMapper {
@@ -88,7 +151,8 @@ pub trait MemoryMapper : PVMapper{
            fa: &FrameAllocator,
            p: PhysicalAddress,
            v: VirtualAddress,
-           size: MemorySize)
+           size: MemorySize,
+           attrs: MemoryAttributes)
            -> Result<(), ()>;
     fn unmap(&self,
              fa: &FrameAllocator,
@@ -102,6 +166,9 @@ pub trait MemoryMapper : PVMapper{
                   size: MemorySize)
                   -> Result<(), ()>;
 
+    // calls f with every mapping, in address order: (v, p, bytes, attrs), where bytes is a page
+    // or a section. the page table is not locked while f runs, so f may map and allocate.
+    fn for_each_mapping(&self, f: &mut FnMut(VirtualAddress, PhysicalAddress, usize, MemoryAttributes));
 }
 
 
@@ -109,7 +176,8 @@ pub trait MemoryManagaer : PVMapper {
     fn map(&self,
            p: PhysicalAddress,
            v: VirtualAddress,
-           size: MemorySize)
+           size: MemorySize,
+           attrs: MemoryAttributes)
            -> Result<(), ()>;
     fn unmap(&self,
              v: VirtualAddress,
@@ -120,12 +188,25 @@ pub trait MemoryManagaer : PVMapper {
                   v: VirtualAddress,
                   size: MemorySize)
                   -> Result<(), ()>;
+
+    // reserve a range of kernel virtual addresses. nothing is mapped there.
+    fn alloc_region(&self, size: MemorySize, align: usize) -> Option<VirtualAddress>;
+    fn free_region(&self, v: VirtualAddress, size: MemorySize);
+
+    // map device memory at a kernel address of our choosing. p and size don't have to be page
+    // aligned; the returned address points at p.
+    fn ioremap(&self, p: PhysicalAddress, size: MemorySize) -> Result<VirtualAddress, ()>;
+    fn iounmap(&self, v: VirtualAddress, size: MemorySize);
+
+    // the kernel half of the memory map, see MemoryMapper::for_each_mapping
+    fn for_each_mapping(&self, f: &mut FnMut(VirtualAddress, PhysicalAddress, usize, MemoryAttributes));
     }
 
 
 pub struct DefaultMemoryManagaer {
     frame_allocator : Rc<FrameAllocator>,
-    mem_mapper : Box<MemoryMapper>
+    mem_mapper : Box<MemoryMapper>,
+    regions : RegionAllocator,
 }
 
 impl DefaultMemoryManagaer {
@@ -133,14 +214,26 @@ impl DefaultMemoryManagaer {
     pub fn new(m: Box<MemoryMapper>, fa : Rc<FrameAllocator>) -> Self {
         DefaultMemoryManagaer{
             frame_allocator : fa,
-            mem_mapper: m
+            mem_mapper: m,
+            regions : RegionAllocator::new(platform::VMALLOC_START, platform::VMALLOC_END),
         }
     }
 }
 
+// the pages that hold [p, p + size)
+fn page_range(p: usize, size: usize) -> (usize, usize) {
+    let start = p & !platform::PAGE_MASK;
+    let end = (p + size + platform::PAGE_MASK) & !platform::PAGE_MASK;
+    (start, end - start)
+}
+
+// tell the other cpus to drop their stale tlb entries. not needed when the page table code
+// already broadcasts its tlb maintenance to all of them.
 #[cfg(feature = "multicpu")]
 fn send_ipi() {
-    platform::get_platform_services().get_current_cpu().send_ipi_to_others(cpu::IPI::MemChanged)
+    if !platform::TLB_BROADCAST {
+        platform::get_platform_services().get_current_cpu().send_ipi_to_others(cpu::IPI::MemChanged)
+    }
 }
 
 #[cfg(not(feature = "multicpu"))]
@@ -151,9 +244,10 @@ impl MemoryManagaer for DefaultMemoryManagaer {
     fn map(&self,
            p: PhysicalAddress,
            v: VirtualAddress,
-           size: MemorySize)
+           size: MemorySize,
+           attrs: MemoryAttributes)
            -> Result<(), ()> {
-        let r = self.mem_mapper.map(self.frame_allocator.as_ref(), p, v, size);
+        let r = self.mem_mapper.map(self.frame_allocator.as_ref(), p, v, size, attrs);
         if let Ok(_) = r {
             send_ipi();
         }
@@ -166,9 +260,8 @@ impl MemoryManagaer for DefaultMemoryManagaer {
              size: MemorySize)
              -> Result<(), ()> {
         let r = self.mem_mapper.unmap(self.frame_allocator.as_ref(), v, size);
-        if let Ok(_) = r {
-            send_ipi();
-        }
+        // even on error; other cpus may not see what was mapped there anymore.
+        send_ipi();
         r
     }
 
@@ -177,13 +270,48 @@ impl MemoryManagaer for DefaultMemoryManagaer {
                   v: VirtualAddress,
                   size: MemorySize)
                   -> Result<(), ()> {
-        // TODO: add IPI
         let r = self.mem_mapper.map_device(self.frame_allocator.as_ref(), p, v, size);
         if let Ok(_) = r {
             send_ipi();
         }
         r
     }
+
+    fn alloc_region(&self, size: MemorySize, align: usize) -> Option<VirtualAddress> {
+        let (_, bytes) = page_range(0, to_bytes(size));
+        self.regions.allocate(bytes, align)
+    }
+
+    fn free_region(&self, v: VirtualAddress, size: MemorySize) {
+        let (_, bytes) = page_range(0, to_bytes(size));
+        self.regions.free(v, bytes)
+    }
+
+    fn ioremap(&self, p: PhysicalAddress, size: MemorySize) -> Result<VirtualAddress, ()> {
+        let (start, bytes) = page_range(p.0, to_bytes(size));
+        // big mappings are 1mb aligned, so they can use sections
+        let align = if bytes >= to_bytes(MemorySize::MegaBytes(1)) {
+            to_bytes(MemorySize::MegaBytes(1))
+        } else {
+            platform::PAGE_SIZE
+        };
+        let v = try!(self.regions.allocate(bytes, align).ok_or(()));
+        if let Err(e) = self.map_device(PhysicalAddress(start), v, MemorySize::Bytes(bytes)) {
+            self.regions.free(v, bytes);
+            return Err(e);
+        }
+        Ok(v.uoffset(p.0 - start))
+    }
+
+    fn iounmap(&self, v: VirtualAddress, size: MemorySize) {
+        let (start, bytes) = page_range(v.0, to_bytes(size));
+        self.unmap(VirtualAddress(start), MemorySize::Bytes(bytes)).expect("Can't unmap device memory");
+        self.regions.free(VirtualAddress(start), bytes);
+    }
+
+    fn for_each_mapping(&self, f: &mut FnMut(VirtualAddress, PhysicalAddress, usize, MemoryAttributes)) {
+        self.mem_mapper.for_each_mapping(f)
+    }
 }
 
 impl PVMapper for DefaultMemoryManagaer {
@@ -0,0 +1,341 @@
+use alloc::arc::Arc;
+use collections::Vec;
+use core::cmp;
+use core::ptr;
+
+use platform;
+use sync;
+
+use super::{AddressSpace, FaultHandler, FaultResult, FaultType, MemoryAttributes, MemorySize,
+            PageFault, PhysicalAddress, PVMapper, VirtualAddress};
+
+// what is behind a memory object
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ObjectKind {
+    // zero filled memory, allocated on first touch. each address space that maps it gets its own
+    // copy of a page when it writes it (copy-on-write).
+    Anonymous,
+    // like anonymous, but writes are seen by everyone that maps it.
+    Shared,
+    // a fixed range of physical memory (a frame buffer, device registers). the frames are not
+    // ours, and are never freed.
+    Physical(PhysicalAddress),
+}
+
+// memory that can be mapped into address spaces.
+pub struct MemoryObject {
+    kind: ObjectKind,
+    pages: usize,
+    // the frames of anonymous and shared objects; None until the page is first touched. the
+    // object holds a reference to each of them, and so does every mapping of the page.
+    frames: sync::CpuMutex<Vec<Option<PhysicalAddress>>>,
+}
+
+impl MemoryObject {
+    pub fn anonymous(size: MemorySize) -> Result<Arc<MemoryObject>, ()> {
+        Self::new(ObjectKind::Anonymous, size)
+    }
+
+    pub fn shared(size: MemorySize) -> Result<Arc<MemoryObject>, ()> {
+        Self::new(ObjectKind::Shared, size)
+    }
+
+    pub fn physical(p: PhysicalAddress, size: MemorySize) -> Result<Arc<MemoryObject>, ()> {
+        if (p.0 & platform::PAGE_MASK) != 0 {
+            return Err(());
+        }
+        Self::new(ObjectKind::Physical(p), size)
+    }
+
+    fn new(kind: ObjectKind, size: MemorySize) -> Result<Arc<MemoryObject>, ()> {
+        let pages = try!(super::to_pages(size));
+        if pages == 0 {
+            return Err(());
+        }
+        let frames = match kind {
+            ObjectKind::Physical(_) => vec![],
+            _ => vec![None; pages],
+        };
+        Ok(Arc::new(MemoryObject {
+            kind: kind,
+            pages: pages,
+            frames: sync::CpuMutex::new(frames),
+        }))
+    }
+
+    pub fn kind(&self) -> ObjectKind {
+        self.kind
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    // the frame of page index, if it has one yet.
+    fn peek(&self, index: usize) -> Option<PhysicalAddress> {
+        match self.kind {
+            ObjectKind::Physical(p) => Some(p.uoffset(index << platform::PAGE_SHIFT)),
+            _ => self.frames.lock()[index],
+        }
+    }
+
+    // the frame of page index; anonymous and shared pages are allocated (and zeroed) on first use.
+    pub fn frame(&self, index: usize) -> Result<PhysicalAddress, ()> {
+        if index >= self.pages {
+            return Err(());
+        }
+        if let ObjectKind::Physical(p) = self.kind {
+            return Ok(p.uoffset(index << platform::PAGE_SHIFT));
+        }
+
+        let mut frames = self.frames.lock();
+        if let Some(p) = frames[index] {
+            return Ok(p);
+        }
+        let p = try!(allocate_zeroed_frame());
+        frames[index] = Some(p);
+        Ok(p)
+    }
+
+    // whether p is reference counted, i.e. not one of the frames of a physical object.
+    fn is_counted(&self, p: PhysicalAddress) -> bool {
+        match self.kind {
+            ObjectKind::Physical(base) => {
+                (p < base) || (p >= base.uoffset(self.pages << platform::PAGE_SHIFT))
+            }
+            _ => true,
+        }
+    }
+
+    // fill the object from the kernel (e.g. to load a program into it), starting offset bytes in.
+    pub fn write(&self, offset: usize, data: &[u8]) -> Result<(), ()> {
+        if (offset > (self.pages << platform::PAGE_SHIFT)) ||
+           (data.len() > (self.pages << platform::PAGE_SHIFT) - offset) {
+            return Err(());
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done;
+            let in_page = pos & platform::PAGE_MASK;
+            let len = cmp::min(platform::PAGE_SIZE - in_page, data.len() - done);
+            let p = try!(self.frame(pos >> platform::PAGE_SHIFT));
+            with_scratch(|scratch| {
+                map_scratch(scratch, 0, p);
+                unsafe {
+                    ptr::copy_nonoverlapping(data[done..].as_ptr(),
+                                             (scratch.0 + in_page) as *mut u8,
+                                             len);
+                }
+                unmap_scratch(scratch, 0);
+            });
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MemoryObject {
+    fn drop(&mut self) {
+        let frame_alloc = &platform::get_memory_services().frame_alloc;
+        for p in self.frames.lock().iter().filter_map(|f| *f) {
+            frame_alloc.release(p);
+        }
+    }
+}
+
+// an object mapped into an address space, at start. pages are mapped when they are first
+// touched.
+#[derive(Clone)]
+pub struct Mapping {
+    pub start: VirtualAddress,
+    pub object: Arc<MemoryObject>,
+    pub attrs: MemoryAttributes,
+}
+
+impl Mapping {
+    pub fn end(&self) -> VirtualAddress {
+        self.start.uoffset(self.object.pages << platform::PAGE_SHIFT)
+    }
+
+    pub fn contains(&self, v: VirtualAddress) -> bool {
+        (v >= self.start) && (v < self.end())
+    }
+
+    // writes to it are copy-on-write
+    pub fn is_private(&self) -> bool {
+        (self.object.kind == ObjectKind::Anonymous) && self.attrs.contains(super::WRITE)
+    }
+
+    pub fn is_counted(&self, p: PhysicalAddress) -> bool {
+        self.object.is_counted(p)
+    }
+
+    // a fault in the mapping, that is in address_space. a copy-on-write page is mapped read only
+    // while it may be shared, and copied on the first write.
+    pub fn resolve(&self, address_space: &AddressSpace, fault: &PageFault) -> Result<(), ()> {
+        let frame_alloc = platform::get_memory_services().frame_alloc.as_ref();
+        let v = VirtualAddress(fault.addr.0 & !platform::PAGE_MASK);
+        let index = (v.0 - self.start.0) >> platform::PAGE_SHIFT;
+        let one_page = MemorySize::PageSizes(1);
+
+        let mapped = address_space.v2p(v).map(|p| PhysicalAddress(p.0 & !platform::PAGE_MASK));
+        match mapped {
+            None => {
+                if self.is_private() && fault.write {
+                    // our own copy right away; when the object doesn't have the page yet, there
+                    // is nothing to copy.
+                    let copy = match self.object.peek(index) {
+                        Some(p) => try!(copy_frame(p)),
+                        None => try!(allocate_zeroed_frame()),
+                    };
+                    return address_space.map(copy, v, one_page, self.attrs)
+                        .map_err(|e| {
+                            frame_alloc.release(copy);
+                            e
+                        });
+                }
+
+                let p = try!(self.object.frame(index));
+                let attrs = if self.is_private() {
+                    self.attrs - super::WRITE
+                } else {
+                    self.attrs
+                };
+                try!(address_space.map(p, v, one_page, attrs));
+                if self.is_counted(p) {
+                    frame_alloc.add_ref(p);
+                }
+                Ok(())
+            }
+            Some(p) => {
+                // it's there. when it already allows the access, someone fixed it while we waited
+                // for the mappings lock: another thread of the address space that touched it
+                // first, or a fork that remapped it.
+                let writable = address_space.attributes(v).map_or(false, |a| a.contains(super::WRITE));
+                if !fault.write || writable {
+                    return Ok(());
+                }
+                // the only thing we fix is a write to a copy-on-write page.
+                if !self.is_private() {
+                    return Err(());
+                }
+                try!(address_space.unmap(v, one_page));
+                if self.is_counted(p) && (frame_alloc.ref_count(p) == 1) {
+                    // nobody else has it; just make it writable.
+                    return address_space.map(p, v, one_page, self.attrs);
+                }
+
+                let copy = match copy_frame(p) {
+                    Ok(copy) => copy,
+                    Err(e) => {
+                        // put the old one back, so the mapping stays consistent
+                        let _ = address_space.map(p, v, one_page, self.attrs - super::WRITE);
+                        return Err(e);
+                    }
+                };
+                try!(address_space.map(copy, v, one_page, self.attrs));
+                if self.is_counted(p) {
+                    frame_alloc.release(p);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// a kernel window to reach frames that are not mapped in the kernel; to zero and copy them.
+const SCRATCH_PAGES: usize = 2;
+static SCRATCH: sync::CpuMutex<Option<VirtualAddress>> = sync::CpuMutex::new(None);
+
+fn with_scratch<F: FnOnce(VirtualAddress) -> R, R>(f: F) -> R {
+    let scratch = SCRATCH.lock();
+    f(scratch.expect("memory objects are not initialized!"))
+}
+
+fn map_scratch(scratch: VirtualAddress, slot: usize, p: PhysicalAddress) {
+    platform::get_memory_services()
+        .mem_manager
+        .map(p,
+             scratch.uoffset(slot << platform::PAGE_SHIFT),
+             MemorySize::PageSizes(1),
+             super::KERNEL_DATA)
+        .expect("Can't map scratch page");
+}
+
+fn unmap_scratch(scratch: VirtualAddress, slot: usize) {
+    let v = scratch.uoffset(slot << platform::PAGE_SHIFT);
+    // the frame is also mapped in user space, at another address; on aliasing caches what we
+    // wrote here must be in memory before it is seen there.
+    platform::clean_dcache_range(v, platform::PAGE_SIZE);
+    platform::get_memory_services()
+        .mem_manager
+        .unmap(v, MemorySize::PageSizes(1))
+        .expect("Can't unmap scratch page");
+}
+
+fn allocate_zeroed_frame() -> Result<PhysicalAddress, ()> {
+    let p = try!(platform::get_memory_services().frame_alloc.allocate(1).ok_or(()));
+    with_scratch(|scratch| {
+        map_scratch(scratch, 0, p);
+        unsafe { ptr::write_bytes(scratch.0 as *mut u8, 0, platform::PAGE_SIZE) };
+        unmap_scratch(scratch, 0);
+    });
+    Ok(p)
+}
+
+// a new frame with the contents of p
+fn copy_frame(p: PhysicalAddress) -> Result<PhysicalAddress, ()> {
+    let copy = try!(platform::get_memory_services().frame_alloc.allocate(1).ok_or(()));
+    with_scratch(|scratch| {
+        map_scratch(scratch, 0, p);
+        map_scratch(scratch, 1, copy);
+        unsafe {
+            ptr::copy_nonoverlapping(scratch.0 as *const u8,
+                                     scratch.uoffset(platform::PAGE_SIZE).0 as *mut u8,
+                                     platform::PAGE_SIZE);
+        }
+        unmap_scratch(scratch, 1);
+        unmap_scratch(scratch, 0);
+    });
+    Ok(copy)
+}
+
+// resolves faults in the mappings of the running thread's address space.
+struct ObjectFaultHandler;
+
+impl FaultHandler for ObjectFaultHandler {
+    fn handle_fault(&self, fault: &PageFault) -> FaultResult {
+        let interesting = (fault.fault_type == FaultType::Translation) ||
+                          (fault.fault_type == FaultType::Permission);
+        if !interesting || (fault.addr >= platform::USER_SPACE_END) || !platform::is_system_ready() {
+            return FaultResult::NotHandled;
+        }
+
+        let address_space = {
+            let curthread_cell = platform::get_platform_services().get_current_cpu().get_running_thread();
+            let curthread = match curthread_cell.try_borrow() {
+                Ok(t) => t,
+                Err(_) => return FaultResult::NotHandled,
+            };
+            match *curthread {
+                Some(ref t) => t.address_space.clone(),
+                None => None,
+            }
+        };
+
+        match address_space {
+            Some(a) => a.handle_fault(fault),
+            None => FaultResult::NotHandled,
+        }
+    }
+}
+
+// called once the memory services are up.
+pub fn init() {
+    let memory_services = platform::get_memory_services();
+    let scratch = memory_services.mem_manager
+        .alloc_region(MemorySize::PageSizes(SCRATCH_PAGES), platform::PAGE_SIZE)
+        .expect("Can't allocate scratch pages");
+    *SCRATCH.lock() = Some(scratch);
+    memory_services.fault_dispatcher.register(Arc::new(ObjectFaultHandler));
+}
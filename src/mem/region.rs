@@ -0,0 +1,175 @@
+use core::ops::Range;
+use collections::Vec;
+
+use sync;
+
+use super::VirtualAddress;
+
+// hands out ranges of kernel virtual addresses, for stacks, device mappings and the such.
+// only the addresses are managed here; mapping them is up to the caller.
+pub struct RegionAllocator {
+    // free ranges, sorted by address. adjacent ranges are always merged.
+    free: sync::CpuMutex<Vec<Range<usize>>>,
+}
+
+fn align_up(a: usize, align: usize) -> usize {
+    (a + align - 1) & !(align - 1)
+}
+
+impl RegionAllocator {
+    pub fn new(start: VirtualAddress, end: VirtualAddress) -> RegionAllocator {
+        RegionAllocator { free: sync::CpuMutex::new(vec![start.0..end.0]) }
+    }
+
+    // first fit. align must be a power of two. there are no empty regions.
+    pub fn allocate(&self, size: usize, align: usize) -> Option<VirtualAddress> {
+        if size == 0 {
+            return None;
+        }
+        let mut free = self.free.lock();
+        for i in 0..free.len() {
+            let r = free[i].clone();
+            let start = align_up(r.start, align);
+            if (start < r.start) || (start > r.end) || (r.end - start < size) {
+                continue;
+            }
+
+            let end = start + size;
+            match (r.start == start, r.end == end) {
+                (true, true) => {
+                    free.remove(i);
+                }
+                (true, false) => free[i].start = end,
+                (false, true) => free[i].end = start,
+                (false, false) => {
+                    free[i].end = start;
+                    free.insert(i + 1, end..r.end);
+                }
+            }
+            return Some(VirtualAddress(start));
+        }
+        None
+    }
+
+    pub fn free(&self, v: VirtualAddress, size: usize) {
+        if size == 0 {
+            panic!("freeing an empty region!")
+        }
+        let (start, end) = (v.0, v.0 + size);
+        let mut free = self.free.lock();
+
+        // the first free range after the freed one
+        let i = free.iter().position(|r| r.start >= end).unwrap_or(free.len());
+        if (i > 0) && (free[i - 1].end > start) {
+            panic!("freeing a region that is not allocated!")
+        }
+
+        let merge_prev = (i > 0) && (free[i - 1].end == start);
+        let merge_next = (i < free.len()) && (free[i].start == end);
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                free[i - 1].end = free[i].end;
+                free.remove(i);
+            }
+            (true, false) => free[i - 1].end = end,
+            (false, true) => free[i].start = start,
+            (false, false) => free.insert(i, start..end),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RegionAllocator;
+    use mem::VirtualAddress;
+
+    const PAGE: usize = 0x1000;
+
+    fn new() -> RegionAllocator {
+        RegionAllocator::new(VirtualAddress(0x1000_0000), VirtualAddress(0x1001_0000))
+    }
+
+    fn free_ranges(a: &RegionAllocator) -> usize {
+        a.free.lock().len()
+    }
+
+    #[test]
+    fn first_fit() {
+        let a = new();
+        let v1 = a.allocate(PAGE, PAGE).unwrap();
+        let v2 = a.allocate(2 * PAGE, PAGE).unwrap();
+        let v3 = a.allocate(PAGE, PAGE).unwrap();
+        assert_eq!(v1.0, 0x1000_0000);
+        assert_eq!(v2.0, 0x1000_1000);
+        assert_eq!(v3.0, 0x1000_3000);
+
+        // the hole v2 leaves is the first that fits
+        a.free(v2, 2 * PAGE);
+        assert_eq!(a.allocate(PAGE, PAGE).map(|v| v.0), Some(v2.0));
+        // but not this one
+        assert_eq!(a.allocate(2 * PAGE, PAGE).map(|v| v.0), Some(0x1000_4000));
+    }
+
+    #[test]
+    fn aligned() {
+        let a = new();
+        a.allocate(PAGE, PAGE).unwrap();
+        let v = a.allocate(PAGE, 4 * PAGE).unwrap();
+        assert_eq!(v.0, 0x1000_4000);
+        // the space before the aligned region is still free
+        assert_eq!(a.allocate(3 * PAGE, PAGE).map(|v| v.0), Some(0x1000_1000));
+    }
+
+    #[test]
+    fn merging() {
+        let a = new();
+        let v1 = a.allocate(PAGE, PAGE).unwrap();
+        let v2 = a.allocate(PAGE, PAGE).unwrap();
+        let v3 = a.allocate(PAGE, PAGE).unwrap();
+        assert_eq!(free_ranges(&a), 1);
+
+        a.free(v1, PAGE);
+        assert_eq!(free_ranges(&a), 2);
+        a.free(v3, PAGE);
+        // merged with the free space after it
+        assert_eq!(free_ranges(&a), 2);
+        // fills the gap: everything is one range again
+        a.free(v2, PAGE);
+        assert_eq!(free_ranges(&a), 1);
+        assert_eq!(a.allocate(0x10000, PAGE).map(|v| v.0), Some(v1.0));
+    }
+
+    #[test]
+    fn exhaustion() {
+        let a = new();
+        assert!(a.allocate(0x10000 + PAGE, PAGE).is_none());
+        let all = a.allocate(0x10000, PAGE).unwrap();
+        assert_eq!(free_ranges(&a), 0);
+        assert!(a.allocate(PAGE, PAGE).is_none());
+        a.free(all, 0x10000);
+        assert_eq!(a.allocate(PAGE, PAGE).map(|v| v.0), Some(all.0));
+    }
+
+    #[test]
+    fn zero_size() {
+        let a = new();
+        assert!(a.allocate(0, PAGE).is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn free_zero_size() {
+        let a = new();
+        let v = a.allocate(PAGE, PAGE).unwrap();
+        a.free(v, 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let a = new();
+        let v = a.allocate(PAGE, PAGE).unwrap();
+        a.free(v, PAGE);
+        a.free(v, PAGE);
+    }
+}
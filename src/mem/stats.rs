@@ -0,0 +1,106 @@
+use collections::String;
+use collections::Vec;
+use core::fmt::Write;
+
+use kernel_alloc;
+use platform;
+use thread;
+
+use super::{FrameStats, MemoryAttributes, PhysicalAddress, VirtualAddress};
+
+pub struct MemStats {
+    pub frames: FrameStats,
+    pub heap: kernel_alloc::AllocStats,
+    // frames that hold page tables, kernel and user
+    pub page_table_frames: usize,
+    pub stacks: Vec<thread::StackUsage>,
+}
+
+// a snapshot; other cpus keep allocating while it is taken, so the numbers don't have to add up.
+pub fn stats() -> MemStats {
+    let stacks = if platform::is_system_ready() {
+        platform::get_platform_services().get_scheduler().stack_usage()
+    } else {
+        vec![]
+    };
+    MemStats {
+        frames: platform::get_memory_services().frame_alloc.stats(),
+        heap: kernel_alloc::stats(),
+        page_table_frames: platform::page_table_frames(),
+        stacks: stacks,
+    }
+}
+
+pub fn dump_stats() {
+    let s = stats();
+    let mut w = String::new();
+
+    let _ = write!(&mut w,
+                   "frames: {} total, {} free, {} reserved, {} page tables",
+                   s.frames.total,
+                   s.frames.free,
+                   s.frames.reserved,
+                   s.page_table_frames);
+    platform::write_to_console(&w);
+
+    w.clear();
+    let _ = write!(&mut w,
+                   "heap: {} mapped, {} used, {} free, {} largest free block",
+                   s.heap.heap_size,
+                   s.heap.heap_used,
+                   s.heap.heap_free,
+                   s.heap.largest_free_block);
+    platform::write_to_console(&w);
+
+    for stack in s.stacks.iter() {
+        w.clear();
+        let _ = write!(&mut w,
+                       "thread {} stack: {} of {} used",
+                       stack.thread.0,
+                       stack.used,
+                       stack.size);
+        platform::write_to_console(&w);
+    }
+}
+
+// a run of pages that are mapped contiguously, with the same attributes
+struct Run {
+    v: VirtualAddress,
+    p: PhysicalAddress,
+    bytes: usize,
+    attrs: MemoryAttributes,
+}
+
+fn print_run(r: &Run) {
+    let mut w = String::new();
+    let _ = write!(&mut w,
+                   "{:#010x}-{:#010x} -> {:#010x} {:?}",
+                   r.v.0,
+                   r.v.0 + r.bytes,
+                   r.p.0,
+                   r.attrs);
+    platform::write_to_console(&w);
+}
+
+// print the kernel half of the memory map, merging what is contiguous.
+pub fn dump_vm_map() {
+    let mut run: Option<Run> = None;
+    platform::get_memory_services().mem_manager.for_each_mapping(&mut |v, p, bytes, attrs| {
+        if let Some(ref mut r) = run {
+            if (r.v.uoffset(r.bytes) == v) && (r.p.uoffset(r.bytes) == p) && (r.attrs == attrs) {
+                r.bytes += bytes;
+                return;
+            }
+            print_run(r);
+        }
+        run = Some(Run {
+            v: v,
+            p: p,
+            bytes: bytes,
+            attrs: attrs,
+        });
+    });
+    if let Some(ref r) = run {
+        print_run(r);
+    }
+}
@@ -1,4 +1,10 @@
 pub use ::arch::arm::mem::PAGE_SHIFT;
+pub use ::arch::arm::mem::USER_SPACE_END;
+pub use ::arch::arm::mem::VMALLOC_START;
+pub use ::arch::arm::mem::VMALLOC_END;
+pub use ::arch::arm::mem::UserPageTable;
+pub use ::arch::arm::mem::switch_user_page_table;
+pub use ::arch::arm::mem::page_table_frames;
 
 pub use ::arch::arm::cpu::set_interrupts;
 pub use ::arch::arm::cpu::get_interrupts;
@@ -6,6 +12,12 @@ pub use ::arch::arm::cpu::wait_for_interrupts;
 pub use ::arch::arm::cpu::memory_write_barrier;
 pub use ::arch::arm::cpu::memory_read_barrier;
 pub use ::arch::arm::cpu::invalidate_tlb;
+pub use ::arch::arm::cpu::TLB_BROADCAST;
+pub use ::arch::arm::cpu::flush_caches;
+pub use ::arch::arm::cpu::clean_dcache_range;
+pub use ::arch::arm::cpu::invalidate_dcache_range;
+pub use ::arch::arm::cpu::clean_invalidate_dcache_range;
+pub use ::arch::arm::DMA_BUS_OFFSET;
 
 pub type Context = ::arch::arm::vector::InterruptContext;
 pub type ThreadContext = ::arch::arm::thread::Context;
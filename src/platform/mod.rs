@@ -13,6 +13,14 @@ mod arm;
 pub use self::arm::*;
 
 
+// write s to the console, if the loglevel boot parameter lets messages of level through (see
+// bootparams::LOG_ERR and friends). write_to_console always writes.
+pub fn log(level: usize, s: &str) {
+    if ::bootparams::is_logged(level) {
+        write_to_console(s);
+    }
+}
+
 pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
 pub const PAGE_MASK: usize = PAGE_SIZE - 1;
 
@@ -40,6 +48,7 @@ pub struct MemoryServices {
 // TODO: remove box and rc from here
     pub mem_manager: Box<::mem::MemoryManagaer>, 
     pub frame_alloc: Rc<::mem::FrameAllocator>,
+    pub fault_dispatcher: ::mem::FaultDispatcher,
 }
 
 pub struct PlatformServices {
@@ -97,7 +106,9 @@ impl PlatformServices {
         if get_current_cpu_id() == 0 {
             self.scheduler.clock();
         }
-        self.get_current_cpu().should_resched.set(true);
+        if self.scheduler.tick() {
+            self.get_current_cpu().should_resched.set(true);
+        }
     }
 
     // called with interrupts disabled..
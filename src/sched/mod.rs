@@ -1,35 +1,234 @@
 use collections::Vec;
+use collections::String;
 use collections::boxed::Box;
+use alloc::arc::Arc;
 use  core::sync::atomic;
 use super::platform;
 use super::thread;
 
 use platform::ThreadId;
 use sync;
+use time::{Duration, Instant};
+
+mod runqueue;
+mod timer;
+
+pub use self::runqueue::{RunQueue, NUM_PRIORITIES};
+pub use self::timer::TimerQueue;
 
 // TODO: make this Thread and SMP safe.
 // TODO this is the one mega unsafe class, so it needs to take care of it's on safety.
 pub struct Sched {
-    threads: sync::CpuMutex<Vec<Box<thread::Thread>>>,
+    // one per cpu; a ready thread waits in the queue of the cpu it will run on.
+    run_queues: Vec<sync::CpuMutex<RunQueue>>,
+    // one per cpu, when it is not running: what the cpu runs when nothing else is ready.
+    idle_threads: Vec<sync::CpuMutex<Option<Box<thread::Thread>>>>,
+    // a bit per cpu that runs its idle thread
+    idle_cpus: atomic::AtomicUsize,
+    // threads that wait for a wakeup
+    blocked: sync::CpuMutex<Blocked>,
+    // threads that sleep, by the time they wake
+    sleepers: sync::CpuMutex<TimerQueue>,
     dying_threads: sync::CpuMutex<Vec<Box<thread::Thread>>>,
     thread_id_counter: atomic::AtomicUsize,
     time_since_boot_millies: atomic::AtomicUsize,
+    // how many ticks a thread runs before the next thread of its priority gets the cpu
+    quantum_ticks: usize,
+    // how often each cpu looks for work on the others
+    balance_ticks: usize,
+}
+
+struct Blocked {
+    threads: Vec<Box<thread::Thread>>,
+    // threads that marked themselves blocked (or asleep), but still run on their way here.
+    blocking: Vec<ThreadId>,
+    // wakeups that came before the thread got here: it was one of the blocking ones when it was
+    // woken.
+    pending_wakeups: Vec<ThreadId>,
 }
 
 pub const MAIN_THREAD_ID: ThreadId = ThreadId(0);
+// the idle thread of cpu n is IDLE_THREAD_ID + n
+pub const IDLE_THREAD_ID: ThreadId = ThreadId(100);
+
+// how busy a cpu was since it started
+#[derive(Copy, Clone)]
+pub struct CpuUsage {
+    pub cpu: usize,
+    pub ticks: usize,
+    pub idle_ticks: usize,
+}
+
+impl CpuUsage {
+    pub fn busy_percent(&self) -> usize {
+        if self.ticks == 0 {
+            return 0;
+        }
+        (self.ticks - self.idle_ticks) * 100 / self.ticks
+    }
+}
 
+fn idle_loop() {
+    loop {
+        platform::wait_for_interrupts();
+    }
+}
+
+fn new_idle_thread(cpu: usize) -> Box<thread::Thread> {
+    let mut t = thread::Thread::new(ThreadId(IDLE_THREAD_ID.0 + cpu), Box::new(idle_loop), thread::DEFAULT_STACK_PAGES);
+    t.name = Some(String::from("idle"));
+    t.priority = 0;
+    t.cpu_affinity = ::cpu::CpuMask::single(cpu);
+    t.last_cpu = Some(cpu);
+    t.is_idle = true;
+    Box::new(t)
+}
+
+// the time slice, unless the command line says otherwise (quantum=<millis>)
+const DEFAULT_QUANTUM_MILLIS: usize = 100;
+
+const BALANCE_MILLIS: usize = 200;
+
+// at least one
+fn millis_to_ticks(millis: usize) -> usize {
+    let ticks = millis * platform::ticks_in_second() / 1000;
+    if ticks == 0 { 1 } else { ticks }
+}
+
+fn quantum_ticks() -> usize {
+    millis_to_ticks(::bootparams::get().quantum.unwrap_or(DEFAULT_QUANTUM_MILLIS))
+}
 
 impl Sched {
     pub fn new() -> Sched {
         Sched {
-            // fake thread as this main thread..
-            threads: sync::CpuMutex::new(vec![]),
+            run_queues: (0..platform::get_num_cpus()).map(|_| sync::CpuMutex::new(RunQueue::new())).collect(),
+            idle_threads: (0..platform::get_num_cpus()).map(|cpu| sync::CpuMutex::new(Some(new_idle_thread(cpu)))).collect(),
+            idle_cpus: atomic::AtomicUsize::new(0),
+            blocked: sync::CpuMutex::new(Blocked {
+                threads: vec![],
+                blocking: vec![],
+                pending_wakeups: vec![],
+            }),
+            sleepers: sync::CpuMutex::new(TimerQueue::new()),
             dying_threads: sync::CpuMutex::new(vec![]),
             thread_id_counter : atomic::AtomicUsize::new(1000),
             time_since_boot_millies :  atomic::AtomicUsize::new(0),
+            quantum_ticks: quantum_ticks(),
+            balance_ticks: millis_to_ticks(BALANCE_MILLIS),
+        }
+    }
+
+    // the cpu whose run queue the thread goes to: where it ran last, if it still may (its cache
+    // may still be warm there) and no other cpu it may run on is idle. otherwise an idle cpu, or
+    // the cpu it may run on with the shortest queue.
+    fn cpu_for(&self, t: &thread::Thread) -> usize {
+        let idle = self.idle_cpus.load(atomic::Ordering::Relaxed) & t.cpu_affinity.0;
+        if let Some(cpu) = t.last_cpu {
+            let last_is_idle = (idle & (1 << cpu)) != 0;
+            if t.cpu_affinity.contains(cpu) && (cpu < self.run_queues.len()) && ((idle == 0) || last_is_idle) {
+                return cpu;
+            }
+        }
+        if idle != 0 {
+            let curcpuid = platform::get_current_cpu_id();
+            if (idle & (1 << curcpuid)) != 0 {
+                return curcpuid;
+            }
+            return idle.trailing_zeros() as usize;
+        }
+        // pinned to cpus we don't run (maxcpus=); better here than nowhere.
+        self.least_loaded(t.cpu_affinity).unwrap_or(platform::get_current_cpu_id())
+    }
+
+    fn least_loaded(&self, allowed: ::cpu::CpuMask) -> Option<usize> {
+        let curcpuid = platform::get_current_cpu_id();
+        let mut best: Option<(usize, usize)> = None;
+        for cpu in (0..self.run_queues.len()).filter(|cpu| allowed.contains(*cpu)) {
+            let len = self.run_queues[cpu].lock().len();
+            // on a tie, stay here
+            let better = match best {
+                Some((best_cpu, best_len)) => {
+                    (len < best_len) || ((len == best_len) && (cpu == curcpuid) && (best_cpu != curcpuid))
+                }
+                None => true,
+            };
+            if better {
+                best = Some((cpu, len));
+            }
+        }
+        best.map(|(cpu, _)| cpu)
+    }
+
+    // a thread that is ready goes to the back of its priority level.
+    fn make_ready(&self, t: Box<thread::Thread>) {
+        let cpu = self.cpu_for(&t);
+        let priority = t.priority;
+        self.run_queues[cpu].lock().push_back(t);
+        if cpu == platform::get_current_cpu_id() {
+            self.check_preempt(priority);
+        } else {
+            Self::kick_cpu(cpu);
         }
     }
 
+    // another cpu got a thread; it may be idle, waiting for interrupts.
+    #[cfg(feature = "multicpu")]
+    fn kick_cpu(cpu: usize) {
+        platform::get_platform_services().cpus[cpu].interrupt(::cpu::IPI::SchedChanged);
+    }
+
+    #[cfg(not(feature = "multicpu"))]
+    fn kick_cpu(_: usize) {}
+
+    // a thread of priority became ready on this cpu; if it is more important than the running
+    // one, switch to it on the way out of the next interrupt.
+    fn check_preempt(&self, priority: usize) {
+        let cpu = platform::get_platform_services().get_current_cpu();
+        if let Ok(running) = cpu.get_running_thread().try_borrow() {
+            if running.as_ref().map_or(false, |r| r.is_idle || (RunQueue::level(priority) > RunQueue::level(r.priority))) {
+                cpu.should_resched.set(true);
+            }
+        }
+    }
+
+    // put a thread that stopped running where it belongs: a run queue, the timer queue, or with
+    // the blocked ones.
+    fn enqueue(&self, mut t: Box<thread::Thread>) {
+        if t.is_idle {
+            *self.idle_threads[platform::get_current_cpu_id()].lock() = Some(t);
+            return;
+        }
+        if !t.is_ready() {
+            // the blocked lock is taken before the sleepers lock, here and in wakeup_no_intr
+            let mut blocked = self.blocked.lock();
+            if let Some(i) = blocked.blocking.iter().position(|id| *id == t.id) {
+                blocked.blocking.swap_remove(i);
+            }
+            match blocked.pending_wakeups.iter().position(|id| *id == t.id) {
+                Some(i) => {
+                    blocked.pending_wakeups.swap_remove(i);
+                    t.run_state = thread::RunState::Ready;
+                }
+                None => {
+                    match t.run_state {
+                        thread::RunState::WakeOn(deadline) if deadline > self.now() => {
+                            self.sleepers.lock().insert(deadline, t);
+                            return;
+                        }
+                        // it's already time
+                        thread::RunState::WakeOn(_) => t.run_state = thread::RunState::Ready,
+                        _ => {
+                            blocked.threads.push(t);
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+        self.make_ready(t);
+    }
+
 // TODO move start to thread object.
     pub fn thread_start(old_thread: Option<Box<thread::Thread>>, new_thread: Box<thread::Thread>) {
 
@@ -40,100 +239,130 @@ impl Sched {
         ::platform::get_platform_services().get_current_cpu().set_running_thread(new_thread);
 
         if let Some(old) = old_thread {
-            let mut threads = platform::get_platform_services().get_scheduler().threads.lock();
-            threads.push(old);
+            platform::get_platform_services().get_scheduler().enqueue(old);
         }
 
+        platform::get_platform_services().get_scheduler().reap_dying_threads();
+
         // all plumbing set! we can enable interrupts
         ::platform::intr::enable_interrupts();
 
         (newthreadfun)();
         
-        platform::get_platform_services().get_scheduler().exit_thread();
+        platform::get_platform_services().get_scheduler().exit_thread_with(thread::ExitStatus::Returned);
         
     }
 
-    fn new_thread_obj<F>(tid: ThreadId, f: F) -> Box<thread::Thread>
-        where F: FnOnce(),
-              F: Send + 'static {
+    pub fn new_thread_id(&self) -> ThreadId {
+        ThreadId(self.thread_id_counter.fetch_add(1, atomic::Ordering::SeqCst))
+    }
 
-        Box::new(thread::Thread::new(tid,  Box::new(f)))
+    // a new thread, made by a thread::ThreadBuilder, goes to a run queue.
+    pub fn start(&self, t: Box<thread::Thread>) {
+        let ig = platform::intr::no_interrupts();
+        self.make_ready(t);
     }
 
-    pub fn spawn<F>(&self, f: F)
-        where F: FnOnce(),
-              F: Send + 'static {
-        // TODO thread safety and SMP Support
-        let tid = self.thread_id_counter.fetch_add(1, atomic::Ordering::SeqCst);
+    // a thread with the default settings; see thread::ThreadBuilder for the others.
+    pub fn spawn<F, T>(&self, f: F) -> thread::JoinHandle<T>
+        where F: FnOnce() -> T,
+              F: Send + 'static,
+              T: Send + 'static {
+        thread::ThreadBuilder::new().spawn(f)
+    }
 
-        let t = Self::new_thread_obj(ThreadId(tid), f);
+    // spawn a thread that sees address_space in the user half of memory
+    pub fn spawn_in<F, T>(&self, address_space: Arc<::mem::AddressSpace>, f: F) -> thread::JoinHandle<T>
+        where F: FnOnce() -> T,
+              F: Send + 'static,
+              T: Send + 'static {
+        thread::ThreadBuilder::new().address_space(address_space).spawn(f)
+    }
 
-        let ig = platform::intr::no_interrupts();
-        self.threads.lock().push(t);
+    // switch the user half of memory if the new thread lives in a different address space.
+    fn switch_address_space(old_thread: Option<&thread::Thread>, new_thread: &thread::Thread) {
+        let new_id = new_thread.address_space.as_ref().map(|a| a.id());
+        if let Some(old) = old_thread {
+            if old.address_space.as_ref().map(|a| a.id()) == new_id {
+                return;
+            }
+        }
+        ::mem::AddressSpace::activate(new_thread.address_space.as_ref().map(|a| &**a));
     }
 
 
+    // the next thread to run on this cpu, taken out of its run queue. None means run_thread
+    // should keep running: it is ready, and everyone that waits is less important. between
+    // threads of the same priority it's round robin.
     fn schedule_new(&self, run_thread : Option<&Box<thread::Thread>>) -> Option<Box<thread::Thread>> {
-        // find an eligble thread
-        // threads.map()
-        let mut threads = self.threads.lock();
-
-        let time_since_boot_millies = self.time_since_boot_millies.load(atomic::Ordering::Acquire);
-
         let curcpuid = platform::get_current_cpu_id();
 
-        let mut chosen : Option<usize> = None;
-
-        for i in 0.. threads.len() {
-            {
-                let mut cur_thread = &mut threads[i];
-                    if let thread::RunState::WakeOn(wake_on) = cur_thread.run_state {
-                        if wake_on >= time_since_boot_millies {
-                            cur_thread.run_state = ::thread::RunState::Ready;
-                        }
+        let popped = {
+            let mut queue = self.run_queues[curcpuid].lock();
+            match run_thread {
+                Some(run_thread) if run_thread.is_ready() && !run_thread.is_idle => {
+                    match queue.highest_priority() {
+                        Some(p) if p >= RunQueue::level(run_thread.priority) => queue.pop(),
+                        _ => return None,
                     }
-            }
-            let  cur_thread = &threads[i];
-
-            if let Some(affinity) = cur_thread.cpu_affinity {
-                if affinity != curcpuid {
-                    continue
                 }
+                // anything is better than idling
+                _ => queue.pop(),
             }
-            if ! cur_thread.is_ready()  {
-                    continue
-            }
-
-            if let Some(index) = chosen {
-
-                if cur_thread.priority > threads[index].priority {
-                    chosen = Some(i);
-                }
-            } else if let Some(run_thread) = run_thread {
-                if (run_thread.is_ready()) && (cur_thread.priority > run_thread.priority)  {
-                 chosen = Some(i);
+        };
+
+        let next = match popped {
+            Some(t) => t,
+            // nothing here; take work from another cpu rather than idle. our queue is unlocked by
+            // now, so we never hold two.
+            None => {
+                match self.steal_work(curcpuid, 1) {
+                    Some(t) => t,
+                    None => {
+                        match run_thread {
+                            // the idle thread runs already
+                            Some(run_thread) if run_thread.is_ready() => return None,
+                            // nothing else can run; idle until something can.
+                            _ => self.idle_threads[curcpuid].lock().take().expect("idle thread is already running!"),
+                        }
+                    }
                 }
-            } 
-
-            if chosen.is_none() {
-                chosen = Some(i);
             }
+        };
 
+        if next.is_idle {
+            self.idle_cpus.fetch_or(1 << curcpuid, atomic::Ordering::Relaxed);
+        } else {
+            self.idle_cpus.fetch_and(!(1 << curcpuid), atomic::Ordering::Relaxed);
         }
-            
-        if let Some(index) = chosen {
-            return Some(threads.swap_remove(index));
-        }
-        if let Some(run_thread) = run_thread {
-            if run_thread.is_ready() {
-                return None
-            }
-        }
-        // no thread is ready.. panic
-        panic!("No thread to run!")
+
+        let mut next = next;
+        next.ticks_left = self.quantum_ticks;
+        next.last_cpu = Some(curcpuid);
+        Some(next)
     }
 
     pub fn exit_thread(&self) {
+        self.exit_thread_with(thread::ExitStatus::Exited)
+    }
+
+    pub fn exit_thread_with(&self, status: thread::ExitStatus) {
+        // tell whoever waits for us first; that may wake threads, so do it while we are still
+        // the running thread.
+        let on_exit = {
+            let ig = platform::intr::no_interrupts();
+            let curthread = ::platform::get_platform_services().get_current_cpu().get_running_thread();
+            // a thread killed by a fault may have had itself borrowed
+            let on_exit = match curthread.try_borrow_mut() {
+                Ok(mut t) => t.as_mut().and_then(|t| t.on_exit.take()),
+                Err(_) => None,
+            };
+            on_exit
+        };
+        if let Some(on_exit) = on_exit {
+            on_exit(status);
+        }
+
         // disable interrupts
         let ig = platform::intr::no_interrupts();
 
@@ -142,16 +371,37 @@ impl Sched {
         // so instead of delete the thread here, place it in a list.
         {
             let mut threads = self.dying_threads.lock();
-            curr_thread.cpu_affinity = Some(platform::get_current_cpu_id());
+            curr_thread.last_cpu = Some(platform::get_current_cpu_id());
             threads.push(curr_thread);
         }
-        let new_thread = self.schedule_new(None);
+        let new_thread = self.schedule_new(None).expect("No thread to run");
 
-        platform::switch_context(None, new_thread.expect("No thread to run"));
+        Self::switch_address_space(None, &new_thread);
+        platform::switch_context(None, new_thread);
         // never gonna get here..
 
     }
 
+    // free the threads that exited on this cpu. we are no longer running on their stacks, as we
+    // are only called after a context switch.
+    fn reap_dying_threads(&self) {
+        let curcpuid = platform::get_current_cpu_id();
+        let mut reaped = vec![];
+        {
+            let mut threads = self.dying_threads.lock();
+            let mut i = 0;
+            while i < threads.len() {
+                if threads[i].last_cpu == Some(curcpuid) {
+                    reaped.push(threads.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        // dropping the threads frees their stacks; do it outside the lock, as it unmaps memory.
+        drop(reaped);
+    }
+
     pub fn yield_thread(&self) {
         // disable interrupts
         let ig = platform::intr::no_interrupts();
@@ -161,13 +411,14 @@ impl Sched {
 
     pub fn yeild_thread_no_intr(&self) {
 
-        let curr_thread = ::platform::get_platform_services().get_current_cpu().take_running_thread();
+        let mut curr_thread = ::platform::get_platform_services().get_current_cpu().take_running_thread();
 
         // get new thread to run
         let new_thread = self.schedule_new(Some(&curr_thread));
 
         if new_thread.is_none() {
             // short path - thread has not changed..
+            curr_thread.ticks_left = self.quantum_ticks;
             ::platform::get_platform_services().get_current_cpu().set_running_thread(curr_thread);
             return
         }
@@ -186,19 +437,23 @@ impl Sched {
 
         /* MemBar incase thread goes to other cpu */
 
+        let new_thread = new_thread.unwrap();
+        Self::switch_address_space(Some(&curr_thread), &new_thread);
+
         platform::memory_write_barrier();
 
-        let (old, current) = platform::switch_context(Some(curr_thread), new_thread.unwrap());
+        let (old, current) = platform::switch_context(Some(curr_thread), new_thread);
 
         platform::memory_read_barrier();
 
         ::platform::get_platform_services().get_current_cpu().set_running_thread(current);
 
         if let Some(old) = old {
-            let mut threads = self.threads.lock();
-            threads.push(old);
+            self.enqueue(old);
         }
 
+        self.reap_dying_threads();
+
         // cur_thread thread is now running!
 
         // we get here when context is switch back to us
@@ -216,7 +471,18 @@ impl Sched {
         self.block_no_intr()
     }
 
-    pub fn sleep(&self, millis: u32) {
+    // the time since boot, in the resolution of the clock tick
+    pub fn now(&self) -> Instant {
+        Instant::from_millis_since_boot(self.time_since_boot_millies.load(atomic::Ordering::Acquire))
+    }
+
+    // sleep for at least d. the thread wakes on the first tick after that, so it may be up to a
+    // tick more.
+    pub fn sleep(&self, d: Duration) {
+        self.sleep_until(self.now() + d)
+    }
+
+    pub fn sleep_until(&self, deadline: Instant) {
         // disable interrupts
         //TODO how to release cpu guard after the context was saved?!
         let ig = platform::intr::no_interrupts();
@@ -225,10 +491,8 @@ impl Sched {
             let mut curthread_cell = platform::get_platform_services().get_current_cpu().get_running_thread().borrow_mut();
             let mut cur_thread = curthread_cell.as_mut().unwrap();
 
-            cur_thread.run_state = thread::RunState::WakeOn(
-                self.time_since_boot_millies.load(atomic::Ordering::Acquire) + (millis as usize)
-            );
-
+            cur_thread.run_state = thread::RunState::WakeOn(deadline);
+            self.blocked.lock().blocking.push(cur_thread.id);
         }
 
         self.yeild_thread_no_intr()
@@ -240,6 +504,7 @@ impl Sched {
         let mut t = curthread_cell.as_mut().unwrap();
         if t.is_ready() {
             t.run_state = thread::RunState::Never;
+            self.blocked.lock().blocking.push(t.id);
         }
         
     }
@@ -258,13 +523,48 @@ impl Sched {
 
     // assume interrupts are blocked
     pub fn wakeup_no_intr(&self, tid: ThreadId) {
-        let mut threads = self.threads.lock();
-        for t in threads.iter_mut().filter(|x| x.id == tid) {
-            // there can only be one..
+        let woken = {
+            let mut blocked = self.blocked.lock();
+            match blocked.threads.iter().position(|x| x.id == tid) {
+                Some(i) => Some(blocked.threads.swap_remove(i)),
+                None => {
+                    // a sleeper is woken before its time
+                    let sleeper = self.sleepers.lock().remove(tid);
+                    // it is still on its way to block. a thread that runs (or is ready) and
+                    // doesn't block is not woken, and its next block isn't cut short.
+                    if sleeper.is_none() && blocked.blocking.contains(&tid) &&
+                       !blocked.pending_wakeups.contains(&tid) {
+                        blocked.pending_wakeups.push(tid);
+                    }
+                    sleeper
+                }
+            }
+        };
+        if let Some(mut t) = woken {
             t.run_state = thread::RunState::Ready;
-            break;
+            self.make_ready(t);
+        }
+    }
+
+    // the stack usage of the threads that wait, and of the one that runs on this cpu. threads
+    // that run on other cpus are not included.
+    pub fn stack_usage(&self) -> Vec<thread::StackUsage> {
+        let mut usage: Vec<thread::StackUsage> = vec![];
+        for queue in self.run_queues.iter() {
+            queue.lock().for_each(|t| usage.extend(t.stack_usage()));
+        }
+        usage.extend(self.blocked.lock().threads.iter().filter_map(|t| t.stack_usage()));
+        for idle in self.idle_threads.iter() {
+            usage.extend(idle.lock().as_ref().and_then(|t| t.stack_usage()));
+        }
+        self.sleepers.lock().for_each(|t| usage.extend(t.stack_usage()));
+        let curthread_cell = platform::get_platform_services().get_current_cpu().get_running_thread();
+        if let Ok(curthread) = curthread_cell.try_borrow() {
+            if let Some(u) = curthread.as_ref().and_then(|t| t.stack_usage()) {
+                usage.push(u);
+            }
         }
-        // TODO: if we have other CPUs sleeping wake them up with an IPI...
+        usage
     }
 
     pub fn get_current_thread(&self) -> ThreadId {
@@ -279,10 +579,93 @@ impl Sched {
 
     pub fn unlock(&mut self) {}
 
-    // this method is called about platform::ticks_in_second times a second
+    // this method is called about platform::ticks_in_second() times a second
     pub fn clock(&self) {
-        const DELTA_MILLIS: usize = (1000 / platform::ticks_in_second) as usize;
+        let delta_millis = 1000 / platform::ticks_in_second();
         // TODO fix time_since_boot_millies to be in cell?!
-        self.time_since_boot_millies.fetch_add(DELTA_MILLIS, atomic::Ordering::Release); 
+        let time_since_boot_millies =
+            self.time_since_boot_millies.fetch_add(delta_millis, atomic::Ordering::Release) + delta_millis;
+        self.wake_sleepers(Instant::from_millis_since_boot(time_since_boot_millies));
+    }
+
+    // wake the sleepers whose deadline passed; the others are not looked at.
+    fn wake_sleepers(&self, now: Instant) {
+        let woken = self.sleepers.lock().expired(now);
+        for mut t in woken {
+            t.run_state = thread::RunState::Ready;
+            self.make_ready(t);
+        }
+    }
+
+    // called on every cpu, every tick. true when the running thread used up its time slice.
+    pub fn tick(&self) -> bool {
+        let cpu = platform::get_platform_services().get_current_cpu();
+        cpu.ticks.set(cpu.ticks.get() + 1);
+        if (cpu.ticks.get() % self.balance_ticks) == 0 {
+            self.balance();
+        }
+
+        let mut curthread = match cpu.get_running_thread().try_borrow_mut() {
+            Ok(t) => t,
+            Err(_) => return false,
+        };
+        match *curthread {
+            Some(ref t) if t.is_idle => {
+                cpu.idle_ticks.set(cpu.idle_ticks.get() + 1);
+                false
+            }
+            Some(ref mut t) => {
+                if t.ticks_left > 0 {
+                    t.ticks_left -= 1;
+                }
+                t.ticks_left == 0
+            }
+            None => false,
+        }
+    }
+
+    pub fn cpu_usage(&self) -> Vec<CpuUsage> {
+        platform::get_platform_services().cpus.iter().map(|cpu| {
+            CpuUsage {
+                cpu: cpu.id(),
+                ticks: cpu.ticks.get(),
+                idle_ticks: cpu.idle_ticks.get(),
+            }
+        }).collect()
+    }
+
+    // pull a thread from the busiest cpu, if it has at least two more waiting than we do, or any
+    // waiting when we have none. only threads that wait are moved; running ones stay where they
+    // are.
+    fn balance(&self) {
+        let curcpuid = platform::get_current_cpu_id();
+        let mine = self.run_queues[curcpuid].lock().len();
+        let min_len = if mine == 0 { 1 } else { mine + 2 };
+        if let Some(t) = self.steal_work(curcpuid, min_len) {
+            self.make_ready(t);
+        }
+    }
+
+    // take a thread that may run on cpu from the busiest other queue, if that has at least
+    // min_len threads waiting.
+    fn steal_work(&self, cpu: usize, min_len: usize) -> Option<Box<thread::Thread>> {
+        let mut busiest: Option<(usize, usize)> = None;
+        for other in (0..self.run_queues.len()).filter(|other| *other != cpu) {
+            let len = self.run_queues[other].lock().len();
+            if busiest.map_or(true, |(_, busiest_len)| len > busiest_len) {
+                busiest = Some((other, len));
+            }
+        }
+
+        let from = match busiest {
+            Some((other, len)) if len >= min_len => other,
+            _ => return None,
+        };
+        // never hold two queues at once, so two cpus stealing from each other can't deadlock.
+        let stolen = self.run_queues[from].lock().steal(cpu);
+        stolen.map(|mut t| {
+            t.last_cpu = Some(cpu);
+            t
+        })
     }
 }
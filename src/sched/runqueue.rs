@@ -0,0 +1,100 @@
+use collections::Vec;
+use collections::VecDeque;
+use collections::boxed::Box;
+use core::cmp;
+
+use thread;
+
+// priorities above the last level share it.
+pub const NUM_PRIORITIES: usize = 32;
+
+// the threads that are ready to run on one cpu. a fifo per priority level, and a bitmap of the
+// levels that are not empty, so finding the next thread doesn't depend on how many there are.
+pub struct RunQueue {
+    // bit n is set when levels[n] is not empty
+    bitmap: u32,
+    levels: Vec<VecDeque<Box<thread::Thread>>>,
+    len: usize,
+}
+
+impl RunQueue {
+    pub fn new() -> RunQueue {
+        RunQueue {
+            bitmap: 0,
+            levels: (0..NUM_PRIORITIES).map(|_| VecDeque::new()).collect(),
+            len: 0,
+        }
+    }
+
+    pub fn level(priority: usize) -> usize {
+        cmp::min(priority, NUM_PRIORITIES - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // goes to the back of its level, after the threads of the same priority.
+    pub fn push_back(&mut self, t: Box<thread::Thread>) {
+        let level = Self::level(t.priority);
+        self.levels[level].push_back(t);
+        self.bitmap |= 1 << level;
+        self.len += 1;
+    }
+
+    // the priority of the thread pop would return
+    pub fn highest_priority(&self) -> Option<usize> {
+        if self.bitmap == 0 {
+            None
+        } else {
+            Some(31 - self.bitmap.leading_zeros() as usize)
+        }
+    }
+
+    // the first thread of the highest priority level
+    pub fn pop(&mut self) -> Option<Box<thread::Thread>> {
+        let level = match self.highest_priority() {
+            Some(level) => level,
+            None => return None,
+        };
+        let t = self.levels[level].pop_front();
+        if self.levels[level].is_empty() {
+            self.bitmap &= !(1 << level);
+        }
+        self.len -= 1;
+        t
+    }
+
+    // take a thread that may run on cpu, to move it there. the most important one that can go,
+    // and of those the one that was queued last.
+    pub fn steal(&mut self, cpu: usize) -> Option<Box<thread::Thread>> {
+        for level in (0..NUM_PRIORITIES).rev() {
+            if (self.bitmap & (1 << level)) == 0 {
+                continue;
+            }
+            let index = match self.levels[level].iter().rposition(|t| t.cpu_affinity.contains(cpu)) {
+                Some(index) => index,
+                None => continue,
+            };
+            let t = self.levels[level].remove(index);
+            if self.levels[level].is_empty() {
+                self.bitmap &= !(1 << level);
+            }
+            self.len -= 1;
+            return t;
+        }
+        None
+    }
+
+    pub fn for_each<F: FnMut(&thread::Thread)>(&self, mut f: F) {
+        for level in self.levels.iter() {
+            for t in level.iter() {
+                f(t);
+            }
+        }
+    }
+}
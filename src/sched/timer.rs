@@ -0,0 +1,61 @@
+use collections::Vec;
+use collections::boxed::Box;
+use core::cmp::Ordering;
+
+use platform::ThreadId;
+use thread;
+use time::Instant;
+
+// sleeping threads, sorted by the time they wake: latest first, so the ones that are due are
+// popped off the end. threads with the same deadline wake in the order they went to sleep.
+pub struct TimerQueue {
+    sleepers: Vec<(Instant, Box<thread::Thread>)>,
+}
+
+impl TimerQueue {
+    pub fn new() -> TimerQueue {
+        TimerQueue { sleepers: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sleepers.len()
+    }
+
+    // before the ones with the same deadline, so they are popped after them.
+    pub fn insert(&mut self, deadline: Instant, t: Box<thread::Thread>) {
+        let index = match self.sleepers.binary_search_by(|&(d, _)| {
+            if d > deadline { Ordering::Less } else { Ordering::Greater }
+        }) {
+            Ok(index) | Err(index) => index,
+        };
+        self.sleepers.insert(index, (deadline, t));
+    }
+
+    // the deadline of the next thread to wake
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.sleepers.last().map(|&(d, _)| d)
+    }
+
+    // the threads whose deadline is at or before now, in the order they are due.
+    pub fn expired(&mut self, now: Instant) -> Vec<Box<thread::Thread>> {
+        let mut expired = vec![];
+        while self.next_deadline().map_or(false, |d| d <= now) {
+            expired.push(self.sleepers.pop().unwrap().1);
+        }
+        expired
+    }
+
+    // take a thread out before its time, e.g. to wake it early.
+    pub fn remove(&mut self, tid: ThreadId) -> Option<Box<thread::Thread>> {
+        match self.sleepers.iter().position(|&(_, ref t)| t.id == tid) {
+            Some(index) => Some(self.sleepers.remove(index).1),
+            None => None,
+        }
+    }
+
+    pub fn for_each<F: FnMut(&thread::Thread)>(&self, mut f: F) {
+        for &(_, ref t) in self.sleepers.iter() {
+            f(t);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use alloc::arc::Arc;
+use collections::String;
+use collections::boxed::Box;
+
+use cpu::CpuMask;
+use mem;
+use platform;
+use platform::ThreadId;
+use sync;
+
+use super::{ExitStatus, Thread, DEFAULT_STACK_PAGES};
+
+// where a thread leaves its result for the one that joins it
+struct Packet<T> {
+    // what it returned, or how it ended if it didn't return
+    result: sync::CpuMutex<Option<Result<T, ExitStatus>>>,
+    // released once, when the thread ends
+    done: sync::Semaphore,
+}
+
+// owns the right to wait for a thread and take its result. dropping it detaches the thread.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    // block until the thread ends, and take what it returned. a thread that ends without
+    // returning (it faulted, or called exit_thread) gives how it ended instead.
+    pub fn join(self) -> Result<T, ExitStatus> {
+        self.packet.done.acquire();
+        self.packet.result.lock().take().expect("thread ended without a result!")
+    }
+}
+
+// the settings of a new thread, before it is spawned:
+//     thread::ThreadBuilder::new().name("net").priority(3).spawn(|| ...)
+pub struct ThreadBuilder {
+    name: Option<String>,
+    priority: usize,
+    affinity: CpuMask,
+    stack_size: usize,
+    address_space: Option<Arc<mem::AddressSpace>>,
+}
+
+impl ThreadBuilder {
+    pub fn new() -> ThreadBuilder {
+        ThreadBuilder {
+            name: None,
+            priority: 1,
+            affinity: CpuMask::all(),
+            stack_size: DEFAULT_STACK_PAGES << platform::PAGE_SHIFT,
+            address_space: None,
+        }
+    }
+
+    pub fn name(mut self, name: &str) -> ThreadBuilder {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    pub fn priority(mut self, priority: usize) -> ThreadBuilder {
+        self.priority = priority;
+        self
+    }
+
+    // the cpus it may run on
+    pub fn affinity(mut self, affinity: CpuMask) -> ThreadBuilder {
+        self.affinity = affinity;
+        self
+    }
+
+    // in bytes; rounded up to whole pages
+    pub fn stack_size(mut self, size: usize) -> ThreadBuilder {
+        self.stack_size = size;
+        self
+    }
+
+    // the user half of memory it sees
+    pub fn address_space(mut self, address_space: Arc<mem::AddressSpace>) -> ThreadBuilder {
+        self.address_space = Some(address_space);
+        self
+    }
+
+    pub fn spawn<F, T>(self, f: F) -> JoinHandle<T>
+        where F: FnOnce() -> T,
+              F: Send + 'static,
+              T: Send + 'static
+    {
+        let packet = Arc::new(Packet {
+            result: sync::CpuMutex::new(None),
+            done: sync::Semaphore::new(0),
+        });
+
+        let their_packet = packet.clone();
+        let main = move || {
+            let result = f();
+            *their_packet.result.lock() = Some(Ok(result));
+        };
+        // runs on every way out of the thread, including the ones that never get back to main.
+        let exit_packet = packet.clone();
+        let on_exit = move |status: ExitStatus| {
+            {
+                let mut result = exit_packet.result.lock();
+                if result.is_none() {
+                    *result = Some(Err(status));
+                }
+            }
+            exit_packet.done.release();
+        };
+
+        let scheduler = platform::get_platform_services().get_scheduler();
+        let id = scheduler.new_thread_id();
+        let stack_pages = (self.stack_size + platform::PAGE_SIZE - 1) >> platform::PAGE_SHIFT;
+        let mut t = Box::new(Thread::new(id, Box::new(main), if stack_pages == 0 { 1 } else { stack_pages }));
+        t.name = self.name;
+        t.priority = self.priority;
+        t.cpu_affinity = self.affinity;
+        t.address_space = self.address_space;
+        t.on_exit = Some(Box::new(on_exit));
+        scheduler.start(t);
+
+        JoinHandle {
+            id: id,
+            packet: packet,
+        }
+    }
+}
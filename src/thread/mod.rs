@@ -1,15 +1,33 @@
 use super::mem;
-use core::sync::atomic;
+use core::mem::forget;
 use core::ops::Drop;
 use platform;
 use platform::ThreadId;
 use collections::boxed::Box;
+use collections::String;
 use alloc::boxed::FnBox;
+use alloc::arc::Arc;
 use core::cell::RefCell;
+use core::slice;
+
+mod builder;
+
+pub use self::builder::{JoinHandle, ThreadBuilder};
+
+// how a thread ended
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    // its function returned
+    Returned,
+    // it called exit_thread before its function returned
+    Exited,
+    // it was killed after a fault it couldn't recover from
+    Faulted,
+}
 
 pub enum RunState {
     Ready,
-    WakeOn(usize),
+    WakeOn(::time::Instant),
     Never,
 }
 
@@ -17,47 +35,139 @@ pub struct Thread {
     pub ctx: super::platform::ThreadContext,
     pub run_state: RunState,
     pub id: ThreadId,
+    pub name: Option<String>,
     pub func : RefCell<Option<Box<FnBox()>>>,
-    pub cpu_affinity: Option<usize>,
+    // the cpus it may run on
+    pub cpu_affinity: ::cpu::CpuMask,
+    // where it ran last; None if it never ran
+    pub last_cpu: Option<usize>,
     pub priority: usize,
+    // what is left of the time slice, in ticks
+    pub ticks_left: usize,
+    // None for the boot thread, that runs on the boot stack
+    pub stack: Option<Stack>,
+    // None for threads that only run in the kernel
+    pub address_space: Option<Arc<mem::AddressSpace>>,
+    // a cpu's idle thread; it is never in a run queue
+    pub is_idle: bool,
+    // called once, however the thread ends, while it still runs as itself
+    pub on_exit: Option<Box<FnBox(ExitStatus)>>,
 }
 
-static STACK_BASE_COUNTER: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
-const STACK_PAGES: usize = 4;
-const STACK_SIZE: usize = STACK_PAGES << platform::PAGE_SHIFT;
-const STACK_BASE: ::mem::VirtualAddress = ::mem::VirtualAddress(0x100_0000);
+// unless the builder says otherwise
+pub const DEFAULT_STACK_PAGES: usize = 4;
+// new stacks are filled with this, so we can tell how deep they were used.
+const STACK_PAINT: u32 = 0xdead_57ac;
 
-impl Thread {
+#[derive(Copy, Clone)]
+pub struct StackUsage {
+    pub thread: ThreadId,
+    // the high water mark, in bytes
+    pub used: usize,
+    pub size: usize,
+}
 
+// a kernel stack in a region of its own. the stack is unmapped and its frames are freed on drop.
+// every stack region has an unmapped guard page below the stack, so an overflow faults instead of
+// silently running into the stack below it.
+pub struct Stack {
+    guard: ::mem::VirtualAddress,
+    frames: ::mem::PhysicalAddress,
+    pages: usize,
+}
+
+impl Stack {
+    pub fn allocate(pages: usize) -> Stack {
+        let guard = platform::get_memory_services().mem_manager.alloc_region(
+            mem::MemorySize::PageSizes(pages + 1),
+            platform::PAGE_SIZE).expect("out of kernel address space for stacks!");
+        let stack = Stack {
+            guard: guard,
+            frames: platform::get_memory_services().frame_alloc.allocate(pages).expect("Can't allocate stack"),
+            pages: pages,
+        };
+        platform::get_memory_services().mem_manager.map(
+           stack.frames,
+           stack.bottom(),
+           mem::MemorySize::PageSizes(pages),
+           mem::KERNEL_DATA).expect("Can't map stack");
+        let words = unsafe { slice::from_raw_parts_mut(stack.bottom().0 as *mut u32, stack.size() / 4) };
+        for w in words.iter_mut() {
+            *w = STACK_PAINT;
+        }
 
-    fn free_stack(_ : ::mem::VirtualAddress) {
-        // TODO: we leak the stack :-(
+        stack
     }
 
-    pub fn allocate_stack() -> ::mem::VirtualAddress {
-        let oldcounter = STACK_BASE_COUNTER.fetch_add(STACK_SIZE, atomic::Ordering::SeqCst);
-        let stack_start = STACK_BASE.uoffset(oldcounter);
-        let stack_end   = stack_start.uoffset(STACK_SIZE);
-        // allocate to pages
-        let pv = platform::get_memory_services().frame_alloc.allocate(STACK_PAGES).unwrap();
-        platform::get_memory_services().mem_manager.map(
-           pv,
-           stack_start,
-           mem::MemorySize::PageSizes(STACK_PAGES)).expect("Can't map stack");
-        
-        stack_end 
+    fn words(&self) -> &[u32] {
+        unsafe { slice::from_raw_parts(self.bottom().0 as *const u32, self.size() / 4) }
+    }
+
+    pub fn size(&self) -> usize {
+        self.pages << platform::PAGE_SHIFT
+    }
+
+    // the most the stack was ever used: from the top down to the deepest word that lost its
+    // paint.
+    pub fn high_water_mark(&self) -> usize {
+        let words = self.words();
+        let untouched = words.iter().position(|w| *w != STACK_PAINT).unwrap_or(words.len());
+        self.size() - untouched * 4
+    }
 
+    pub fn bottom(&self) -> ::mem::VirtualAddress {
+        self.guard.uoffset(platform::PAGE_SIZE)
+    }
+
+    // stacks grow down, so this is the initial stack pointer
+    pub fn top(&self) -> ::mem::VirtualAddress {
+        self.bottom().uoffset(self.size())
+    }
+
+    pub fn is_guard_page(&self, v: ::mem::VirtualAddress) -> bool {
+        (self.guard <= v) && (v < self.bottom())
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        platform::get_memory_services().mem_manager.unmap(
+            self.bottom(),
+            mem::MemorySize::PageSizes(self.pages)).expect("Can't unmap stack");
+        platform::get_memory_services().frame_alloc.deallocate(self.frames, self.pages);
+        platform::get_memory_services().mem_manager.free_region(
+            self.guard,
+            mem::MemorySize::PageSizes(self.pages + 1));
+    }
+}
+
+impl Thread {
+
+    // allocate a stack that is never freed. used for the mode stacks and the other cpus' boot stacks.
+    pub fn allocate_stack() -> ::mem::VirtualAddress {
+        let stack = Stack::allocate(DEFAULT_STACK_PAGES);
+        let top = stack.top();
+        forget(stack);
+        top
     }
 
 // TODO: remove the start address
-    pub fn new(id : ThreadId, f: Box<FnBox()>) -> Self {
+    pub fn new(id : ThreadId, f: Box<FnBox()>, stack_pages: usize) -> Self {
+        let stack = Stack::allocate(stack_pages);
         Thread {
-            ctx: platform::new_thread(Thread::allocate_stack()),
+            ctx: platform::new_thread(stack.top()),
             run_state: RunState::Ready,
             id: id,
+            name: None,
             func : RefCell::new(Some(f)),
-            cpu_affinity: None,
+            cpu_affinity: ::cpu::CpuMask::all(),
+            last_cpu: None,
             priority: 1,
+            ticks_left: 0,
+            stack: Some(stack),
+            address_space: None,
+            is_idle: false,
+            on_exit: None,
         }
     }
 
@@ -68,21 +178,68 @@ impl Thread {
         false
     }
 
+    // None for the boot thread, as we don't own its stack.
+    pub fn stack_usage(&self) -> Option<StackUsage> {
+        self.stack.as_ref().map(|s| {
+            StackUsage {
+                thread: self.id,
+                used: s.high_water_mark(),
+                size: s.size(),
+            }
+        })
+    }
+
+    // true if v is in the guard page of this thread's stack, i.e. the thread overflowed its stack
+    pub fn is_stack_overflow(&self, v: ::mem::VirtualAddress) -> bool {
+        match self.stack {
+            Some(ref s) => s.is_guard_page(v),
+            None => false,
+        }
+    }
+
     pub fn new_cur_thread(id : ThreadId) -> Self {
         Thread{
                     ctx : platform::new_thread(::mem::VirtualAddress(0)),
                     run_state: RunState::Ready,
                     id : id,
+                    name: None,
                     func : RefCell::new(None),
-                    cpu_affinity: None,
+                    cpu_affinity: ::cpu::CpuMask::all(),
+                    last_cpu: Some(platform::get_current_cpu_id()),
                     priority: 1,
+                    ticks_left: 0,
+                    // the boot stack is not ours to free
+                    stack: None,
+                    address_space: None,
+                    is_idle: false,
+                    on_exit: None,
         }
     }
 }
 
+// the identity of a running thread
+#[derive(Clone)]
+pub struct Handle {
+    id: ThreadId,
+    name: Option<String>,
+}
 
-impl Drop for Thread {
-    fn drop(&mut self) {
-        // TODO: free the stacks.
+impl Handle {
+    pub fn id(&self) -> ThreadId {
+        self.id
     }
-}
\ No newline at end of file
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|n| n.as_str())
+    }
+}
+
+// the thread that calls it
+pub fn current() -> Handle {
+    let curthread_cell = platform::get_platform_services().get_current_cpu().get_running_thread().borrow();
+    let cur_thread = curthread_cell.as_ref().expect("no thread is running!");
+    Handle {
+        id: cur_thread.id,
+        name: cur_thread.name.clone(),
+    }
+}
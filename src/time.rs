@@ -0,0 +1,63 @@
+// kernel time, in the resolution of the scheduler clock (milliseconds).
+
+use core::ops::{Add, Sub};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration(usize);
+
+impl Duration {
+    pub fn from_millis(millis: usize) -> Duration {
+        Duration(millis)
+    }
+
+    pub fn from_secs(secs: usize) -> Duration {
+        Duration(secs * 1000)
+    }
+
+    pub fn as_millis(&self) -> usize {
+        self.0
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, other: Duration) -> Duration {
+        Duration(self.0 + other.0)
+    }
+}
+
+// a point in time, as milliseconds since boot
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(usize);
+
+impl Instant {
+    pub fn from_millis_since_boot(millis: usize) -> Instant {
+        Instant(millis)
+    }
+
+    pub fn millis_since_boot(&self) -> usize {
+        self.0
+    }
+
+    // zero when earlier is not earlier
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, d: Duration) -> Instant {
+        Instant(self.0.saturating_add(d.0))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}